@@ -0,0 +1,127 @@
+use std::path::PathBuf;
+
+use url::Url;
+
+/// A group of downloads (videos, archives, ...) picked by its rules, downloads
+/// in it go to its directory and start with its options
+#[derive(Debug, Clone, PartialEq)]
+pub struct Category {
+    pub name: String,
+    /// a download is in the category if any of the rules match
+    pub rules: Vec<CategoryRule>,
+    /// destination directory, relative ones are inside the default download directory
+    pub directory: Option<PathBuf>,
+    /// overrides `DownloadConfig::connections_per_server`
+    pub connections: Option<usize>,
+    /// speed limit of each download in bytes per second
+    pub speed_limit: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CategoryRule {
+    /// file extension without the dot, case insensitive
+    Extension(String),
+    /// Content-Type of the response, `*` matches anything, e.g. `video/*`
+    Mime(String),
+    /// the host or any of its subdomains
+    Host(String),
+    /// the whole url, `*` matches anything, e.g. `*/releases/*`
+    Url(String),
+}
+
+impl Category {
+    pub fn matches(&self, url: &str, file_name: &str, content_type: Option<&str>) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| rule.matches(url, file_name, content_type))
+    }
+}
+
+impl CategoryRule {
+    pub fn matches(&self, url: &str, file_name: &str, content_type: Option<&str>) -> bool {
+        match self {
+            CategoryRule::Extension(extension) => file_name
+                .rsplit_once('.')
+                .is_some_and(|(_, ext)| ext.eq_ignore_ascii_case(extension)),
+            CategoryRule::Mime(pattern) => content_type.is_some_and(|content_type| {
+                // parameters like charset don't matter
+                let mime = content_type.split(';').next().unwrap_or_default().trim();
+                wildcard_match(&pattern.to_ascii_lowercase(), &mime.to_ascii_lowercase())
+            }),
+            CategoryRule::Host(host) => Url::parse(url)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_ascii_lowercase))
+                .is_some_and(|url_host| {
+                    let host = host.to_ascii_lowercase();
+                    url_host == host || url_host.ends_with(&format!(".{}", host))
+                }),
+            CategoryRule::Url(pattern) => wildcard_match(pattern, url),
+        }
+    }
+}
+
+/// First category whose rules match, categories earlier in the list win
+pub fn find_category<'a>(
+    categories: &'a [Category],
+    url: &str,
+    file_name: &str,
+    content_type: Option<&str>,
+) -> Option<&'a Category> {
+    categories
+        .iter()
+        .find(|category| category.matches(url, file_name, content_type))
+}
+
+/// Whether `text` matches `pattern`, where `*` stands for any number of characters
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let mut pieces = pattern.split('*');
+    // without a `*` the pattern is the only piece and has to match exactly
+    let first = pieces.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+
+    let pieces: Vec<&str> = pieces.collect();
+    let Some((last, middle)) = pieces.split_last() else {
+        return rest.is_empty();
+    };
+    for piece in middle {
+        match rest.find(piece) {
+            Some(index) => rest = &rest[index + piece.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules_match() {
+        let url = "https://dl.example.com/releases/app-1.0.tar.GZ";
+        assert!(CategoryRule::Extension("gz".into()).matches(url, "app-1.0.tar.GZ", None));
+        assert!(CategoryRule::Host("example.com".into()).matches(url, "", None));
+        assert!(!CategoryRule::Host("ample.com".into()).matches(url, "", None));
+        assert!(CategoryRule::Url("*/releases/*".into()).matches(url, "", None));
+        assert!(!CategoryRule::Url("*/videos/*".into()).matches(url, "", None));
+        assert!(CategoryRule::Mime("video/*".into()).matches(
+            url,
+            "",
+            Some("Video/MP4; codecs=avc1")
+        ));
+        assert!(!CategoryRule::Mime("video/*".into()).matches(url, "", None));
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("abc", "abc"));
+        assert!(!wildcard_match("abc", "abcd"));
+        assert!(wildcard_match("a*c", "abbbc"));
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("a*b*c", "a-b-b-c"));
+        assert!(!wildcard_match("a*bc", "abc-bd"));
+        assert!(!wildcard_match("ab*ba", "aba"));
+    }
+}
@@ -0,0 +1,397 @@
+// Content-Disposition parsing as per RFC 6266 with RFC 8187 extended parameters
+//
+// the parser is lenient on purpose, real world servers send all kinds of
+// broken headers (unquoted spaces, raw UTF-8, percent-encoded filename=)
+// and we'd rather pick a sensible name than fall back to a generated one
+use crate::utils::percent_decode;
+
+/// A parsed Content-Disposition header
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContentDisposition {
+    /// disposition type in lowercase (e.g. "attachment", "inline"), empty if missing
+    pub disposition: String,
+    /// parameters in the order they appear in the header
+    pub parameters: Vec<DispositionParameter>,
+}
+
+/// A single `name=value` parameter of the Content-Disposition header
+#[derive(Clone, Debug, PartialEq)]
+pub struct DispositionParameter {
+    /// parameter name in lowercase, extended parameters keep their trailing `*`
+    pub name: String,
+    /// decoded value (quoted-string unescaped, ext-value charset decoded)
+    pub value: String,
+    /// language tag of an extended parameter, if any
+    pub language: Option<String>,
+}
+
+impl ContentDisposition {
+    /// Parse the raw header bytes, non UTF-8 bytes are read as ISO-8859-1
+    pub fn parse_bytes(header: &[u8]) -> Option<Self> {
+        match std::str::from_utf8(header) {
+            Ok(header) => Self::parse(header),
+            Err(_) => Self::parse(&header.iter().map(|b| *b as char).collect::<String>()),
+        }
+    }
+
+    /// Parse a Content-Disposition header value
+    pub fn parse(header: &str) -> Option<Self> {
+        let header = header.trim();
+        if header.is_empty() {
+            return None;
+        }
+
+        // the disposition type is a token before the first ';', if it contains
+        // a '=' the type was left out and the header starts with a parameter
+        let (disposition, rest) = match header.split_once(';') {
+            Some((disposition, rest)) => (disposition.trim(), rest),
+            None => (header, ""),
+        };
+        let (disposition, rest) = if disposition.contains('=') {
+            ("", header)
+        } else {
+            (disposition, rest)
+        };
+
+        let mut parameters: Vec<DispositionParameter> = Vec::new();
+        for (name, value) in split_parameters(rest) {
+            // first occurrence wins, repeated parameters are invalid anyway
+            if parameters.iter().any(|p| p.name == name) {
+                continue;
+            }
+
+            let parameter = if name.ends_with('*') {
+                match decode_ext_value(&value) {
+                    Some((value, language)) => DispositionParameter {
+                        name,
+                        value,
+                        language,
+                    },
+                    // undecodable ext-values are dropped so the plain parameter is used
+                    None => continue,
+                }
+            } else {
+                DispositionParameter {
+                    name,
+                    value,
+                    language: None,
+                }
+            };
+            parameters.push(parameter);
+        }
+
+        Some(Self {
+            disposition: disposition.to_ascii_lowercase(),
+            parameters,
+        })
+    }
+
+    /// Get a parameter value by (case-insensitive) name
+    pub fn parameter(&self, name: &str) -> Option<&str> {
+        self.parameters
+            .iter()
+            .find(|p| p.name.eq_ignore_ascii_case(name))
+            .map(|p| p.value.as_str())
+    }
+
+    /// The filename, `filename*` takes precedence over `filename` (RFC 6266 4.3)
+    pub fn filename(&self) -> Option<String> {
+        if let Some(filename) = self.parameter("filename*")
+            && !filename.is_empty()
+        {
+            return Some(filename.to_string());
+        }
+
+        let filename = self.parameter("filename")?;
+        if filename.is_empty() {
+            return None;
+        }
+
+        // plenty of servers percent-encode non-ASCII names in the plain filename,
+        // decode those but leave ASCII-only escapes like "foo-%41.html" alone
+        if filename.contains('%')
+            && let Ok(decoded) = String::from_utf8(percent_decode(filename))
+            && !decoded.is_ascii()
+        {
+            return Some(decoded);
+        }
+
+        Some(filename.to_string())
+    }
+}
+
+/// Split `; name=value; name="quoted value"` into lowercase names and unescaped values
+fn split_parameters(input: &str) -> Vec<(String, String)> {
+    let mut parameters = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    loop {
+        // skip separators and whitespace before the name
+        while matches!(chars.peek(), Some(c) if *c == ';' || c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            break;
+        }
+
+        let mut name = String::new();
+        while let Some(c) = chars.peek() {
+            if *c == '=' || *c == ';' {
+                break;
+            }
+            name.push(*c);
+            chars.next();
+        }
+
+        // a name without a value is ignored
+        if chars.next() != Some('=') {
+            continue;
+        }
+
+        while matches!(chars.peek(), Some(c) if c.is_whitespace()) {
+            chars.next();
+        }
+
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => {
+                        if let Some(escaped) = chars.next() {
+                            value.push(escaped);
+                        }
+                    }
+                    c => value.push(c),
+                }
+            }
+            // anything between the closing quote and the next ';' is garbage
+            while matches!(chars.peek(), Some(c) if *c != ';') {
+                chars.next();
+            }
+        } else {
+            while let Some(c) = chars.peek() {
+                if *c == ';' {
+                    break;
+                }
+                value.push(*c);
+                chars.next();
+            }
+            value = value.trim_end().to_string();
+        }
+
+        let name = name.trim().to_ascii_lowercase();
+        if !name.is_empty() {
+            parameters.push((name, value));
+        }
+    }
+
+    parameters
+}
+
+/// Decode an RFC 8187 ext-value `charset'[language]'value-chars`
+/// returning the decoded value and the language tag
+fn decode_ext_value(value: &str) -> Option<(String, Option<String>)> {
+    let mut split = value.splitn(3, '\'');
+    let charset = split.next()?.trim();
+    let language = split.next()?;
+    let encoded = split.next()?;
+
+    let bytes = percent_decode(encoded);
+    let decoded = if charset.eq_ignore_ascii_case("utf-8") {
+        String::from_utf8(bytes).ok()?
+    } else if charset.eq_ignore_ascii_case("iso-8859-1") {
+        bytes.iter().map(|b| *b as char).collect()
+    } else {
+        return None;
+    };
+
+    let language = (!language.is_empty()).then(|| language.to_string());
+    Some((decoded, language))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filename(header: &str) -> Option<String> {
+        ContentDisposition::parse(header).and_then(|cd| cd.filename())
+    }
+
+    #[test]
+    fn plain_filenames() {
+        let cases = [
+            ("attachment; filename=foo.html", "foo.html"),
+            ("attachment; filename=\"foo.html\"", "foo.html"),
+            ("inline; filename=\"foo.html\"", "foo.html"),
+            ("ATTACHMENT; FILENAME=\"foo.html\"", "foo.html"),
+            ("attachment; filename = \"foo.html\"", "foo.html"),
+            ("attachment;filename=foo.html", "foo.html"),
+            ("attachment; filename=\"foo bar.html\"", "foo bar.html"),
+            ("attachment; filename=foo bar.html", "foo bar.html"),
+            ("attachment; filename=\"foo;bar.html\"", "foo;bar.html"),
+            ("attachment; filename=\"foo.html\"; size=1024", "foo.html"),
+            ("attachment; size=1024; filename=\"foo.html\"", "foo.html"),
+            ("attachment; filename=\"foo.html\";", "foo.html"),
+            ("filename=\"foo.html\"", "foo.html"),
+        ];
+        for (header, expected) in cases {
+            assert_eq!(filename(header).as_deref(), Some(expected), "{header}");
+        }
+    }
+
+    #[test]
+    fn quoted_string_escapes() {
+        let cases = [
+            ("attachment; filename=\"f\\oo.html\"", "foo.html"),
+            (
+                "attachment; filename=\"\\\"quoting\\\" tested.html\"",
+                "\"quoting\" tested.html",
+            ),
+            (
+                "attachment; filename=\"Here's a semicolon;.html\"",
+                "Here's a semicolon;.html",
+            ),
+            ("attachment; filename=\"foo-%41.html\"", "foo-%41.html"),
+            (
+                "attachment; filename=\"%E6%97%A5%E6%9C%AC.txt\"",
+                "日本.txt",
+            ),
+            ("attachment; filename=\"50%.html\"", "50%.html"),
+        ];
+        for (header, expected) in cases {
+            assert_eq!(filename(header).as_deref(), Some(expected), "{header}");
+        }
+    }
+
+    #[test]
+    fn extended_filenames() {
+        let cases = [
+            (
+                "attachment; filename*=UTF-8''foo-%c3%a4-%e2%82%ac.html",
+                "foo-ä-€.html",
+            ),
+            ("attachment; filename*=utf-8''foo-%C3%A4.html", "foo-ä.html"),
+            (
+                "attachment; filename*=iso-8859-1''foo-%E4.html",
+                "foo-ä.html",
+            ),
+            (
+                "attachment; filename*=UTF-8'en'%E2%82%AC%20rates.pdf",
+                "€ rates.pdf",
+            ),
+            (
+                "attachment; filename*=UTF-8''%E6%97%A5%E6%9C%AC%E8%AA%9E.txt",
+                "日本語.txt",
+            ),
+            (
+                "attachment; filename*=UTF-8''%D0%BE%D1%82%D1%87%D0%B5%D1%82.docx",
+                "отчет.docx",
+            ),
+            (
+                "attachment; filename*=\"UTF-8''foo-%c3%a4.html\"",
+                "foo-ä.html",
+            ),
+            ("attachment; filename*=UTF-8''a+b.txt", "a+b.txt"),
+        ];
+        for (header, expected) in cases {
+            assert_eq!(filename(header).as_deref(), Some(expected), "{header}");
+        }
+    }
+
+    #[test]
+    fn extended_filename_takes_precedence() {
+        let cases = [
+            (
+                "attachment; filename=\"EURO rates\"; filename*=utf-8''%e2%82%ac%20rates",
+                "€ rates",
+            ),
+            (
+                "attachment; filename*=utf-8''%e2%82%ac%20rates; filename=\"EURO rates\"",
+                "€ rates",
+            ),
+            (
+                "attachment; filename=\"foo-ae.html\"; filename*=UTF-8''foo-%c3%a4.html",
+                "foo-ä.html",
+            ),
+        ];
+        for (header, expected) in cases {
+            assert_eq!(filename(header).as_deref(), Some(expected), "{header}");
+        }
+    }
+
+    #[test]
+    fn broken_extended_filename_falls_back() {
+        let cases = [
+            // unknown charset
+            (
+                "attachment; filename*=foo''bar.html; filename=\"fallback.html\"",
+                "fallback.html",
+            ),
+            // invalid UTF-8 after decoding
+            (
+                "attachment; filename*=UTF-8''foo-%E4.html; filename=\"fallback.html\"",
+                "fallback.html",
+            ),
+            // missing language separator
+            (
+                "attachment; filename*=UTF-8'foo.html; filename=\"fallback.html\"",
+                "fallback.html",
+            ),
+        ];
+        for (header, expected) in cases {
+            assert_eq!(filename(header).as_deref(), Some(expected), "{header}");
+        }
+    }
+
+    #[test]
+    fn language_and_parameters() {
+        let cd = ContentDisposition::parse(
+            "Attachment; filename*=UTF-8'de-DE'Gr%C3%BC%C3%9Fe.txt; creation-date=\"Wed, 12 Feb 1997 16:29:51 -0500\"",
+        )
+        .unwrap();
+        assert_eq!(cd.disposition, "attachment");
+        assert_eq!(cd.parameters[0].language.as_deref(), Some("de-DE"));
+        assert_eq!(cd.filename().as_deref(), Some("Grüße.txt"));
+        assert_eq!(
+            cd.parameter("Creation-Date"),
+            Some("Wed, 12 Feb 1997 16:29:51 -0500")
+        );
+    }
+
+    #[test]
+    fn raw_non_ascii_bytes() {
+        let utf8 = "attachment; filename=\"日本語.txt\"".as_bytes();
+        let cd = ContentDisposition::parse_bytes(utf8).unwrap();
+        assert_eq!(cd.filename().as_deref(), Some("日本語.txt"));
+
+        let latin1 = b"attachment; filename=\"foo-\xe4.html\"";
+        let cd = ContentDisposition::parse_bytes(latin1).unwrap();
+        assert_eq!(cd.filename().as_deref(), Some("foo-ä.html"));
+    }
+
+    #[test]
+    fn no_filename() {
+        let cases = [
+            "",
+            "inline",
+            "attachment",
+            "attachment; filename=",
+            "attachment; filename=\"\"",
+            "attachment; name=\"foo.html\"",
+            "attachment; filename",
+        ];
+        for header in cases {
+            assert_eq!(filename(header), None, "{header}");
+        }
+    }
+
+    #[test]
+    fn duplicate_parameters_use_the_first() {
+        assert_eq!(
+            filename("attachment; filename=\"foo.html\"; filename=\"bar.html\"").as_deref(),
+            Some("foo.html")
+        );
+    }
+}
@@ -1,6 +1,10 @@
+use crate::category::{Category, find_category};
 use crate::download_config::DownloadConfig;
 use crate::errors::DownloadError;
-use crate::types::DownloadRequest;
+use crate::open_file_writer::claim_file;
+use crate::speed_limiter::SpeedLimiter;
+use crate::speed_tracker::SpeedTracker;
+use crate::types::{DownloadPriority, DownloadRequest};
 use crate::utils::{calculate_chunks, extract_filename};
 use crate::{NonResumableDownloadPart, ResumableDownloadPart};
 use crate::{
@@ -9,15 +13,32 @@ use crate::{
     utils::format_bytes,
 };
 use chrono::{DateTime, Duration, Utc};
-use reqwest::{Client, header};
+use reqwest::{
+    Client, StatusCode,
+    header::{self, HeaderMap, HeaderName},
+};
 use std::{
+    collections::HashMap,
+    future::Future,
     path::PathBuf,
-    sync::{Arc, atomic::AtomicBool},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
 };
-use tokio::sync::Mutex;
-use tracing::info;
+use tokio::{sync::Mutex, task::JoinHandle};
+use tracing::{info, warn};
+use url::Url;
 use uuid::Uuid;
 
+/// extension added to the file while it is being downloaded
+pub(crate) const TEMP_EXTENSION: &str = "nm";
+
+/// how long connecting for `fetch_info` and `probe` may take
+const INFO_CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+/// how long `fetch_info` and `probe` wait for the server to send anything
+const INFO_READ_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
 #[derive(Clone, Debug)]
 pub struct Download {
     /// Unique identifier for the download.
@@ -48,6 +69,63 @@ pub struct Download {
     pub parts: DownloadParts,
     /// Progress of each part, shared using Arc Mutex
     pub progress: DownloadPartsProgress,
+    /// Smoothed speed and recent speed history, sampled in update_progress
+    pub speed: SpeedTracker,
+    /// Handles of the running part tasks by part id, used to abort them
+    pub tasks: Arc<Mutex<HashMap<Uuid, JoinHandle<()>>>>,
+    /// Maximum number of parts downloading at the same time, shared with the
+    /// part tasks so they pick up changes when they hand over their connection
+    pub connection_limit: Arc<AtomicUsize>,
+    /// Priority in the queue, higher priority downloads start first and keep
+    /// their connections longest
+    pub priority: DownloadPriority,
+    /// Expected checksum given with the request (e.g. `sha-256=<hex>`), the same
+    /// checksum means the same content even behind a different url
+    pub checksum: Option<String>,
+    /// Category the download is in, its options are applied on top of `config`
+    pub category: Option<String>,
+    /// When the download completed, failed or was cancelled, None while it isn't finished
+    pub date_finished: Option<DateTime<Utc>>,
+    /// Caps the speed of every part, share one limiter between downloads for a global limit
+    pub speed_limiter: Arc<SpeedLimiter>,
+    /// This download's own cap on top of `speed_limiter`, unlimited unless changed
+    pub own_speed_limiter: Arc<SpeedLimiter>,
+    /// Connections set for this download alone with `set_connections`, they win
+    /// over the config and the category
+    pub connections: Option<usize>,
+    /// ETag of the file when the download info was loaded, used to tell whether
+    /// it changed on the server before resuming
+    pub etag: Option<String>,
+    /// Last-Modified of the file, used like `etag` if the server sends no ETag
+    pub last_modified: Option<String>,
+    /// The parts come from an earlier run and the server hasn't confirmed yet
+    /// that they still fit the file, see `probe`
+    pub restored: bool,
+}
+
+/// What the server said about the file of a new download, see `Download::fetch_info`
+#[derive(Debug, Clone)]
+pub struct FileInfo {
+    /// 0 if the server didn't say
+    pub total_size: u64,
+    pub resumable: bool,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// name from the request, Content-Disposition or the url
+    pub suggested_name: Option<String>,
+    pub content_type: Option<String>,
+}
+
+/// What the server said about the file of a restored download, see `Download::probe`
+#[derive(Debug, Clone)]
+pub struct RemoteFile {
+    /// None if the server didn't say
+    pub total_size: Option<u64>,
+    pub resumable: bool,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// whether the saved progress still fits the file
+    pub unchanged: bool,
 }
 
 impl Download {
@@ -71,54 +149,211 @@ impl Download {
             config: config.clone(),
             parts: DownloadParts::None,
             progress: DownloadPartsProgress::None,
+            speed: SpeedTracker::default(),
+            tasks: Arc::new(Mutex::new(HashMap::new())),
+            connection_limit: Arc::new(AtomicUsize::new(config.connections_per_server)),
+            priority: request.priority,
+            checksum: request.checksum,
+            category: request.category,
+            date_finished: None,
+            speed_limiter: Arc::new(SpeedLimiter::default()),
+            own_speed_limiter: Arc::new(SpeedLimiter::default()),
+            connections: None,
+            etag: None,
+            last_modified: None,
+            restored: false,
         }
     }
 
     pub async fn load_download_info(&mut self) -> Result<(), DownloadError> {
         info!("Loading download_info for {:?}", self.id);
         self.status = DownloadStatus::Connecting;
-        let client = Client::new();
-        let response = match client.get(&self.url).send().await {
-            Ok(response) => response,
+        let file = match self.fetch_info().await {
+            Ok(file) => file,
             Err(err) => {
                 self.status = DownloadStatus::Failed;
-                return Err(DownloadError::HttpRequestError(err));
+                return Err(err);
             }
         };
+        self.apply_info(file).await
+    }
 
-        let total_size = response
-            .headers()
-            .get(header::CONTENT_LENGTH)
-            .and_then(|val| val.to_str().ok())
-            .and_then(|val| val.parse::<u64>().ok())
-            .unwrap_or(0);
-
-        let resume = response.headers().get(header::ACCEPT_RANGES).is_some();
-
-        // if the request doesn't provide a filename, we try to get it from
-        // the response headers
-        // or from the URL
-        // or a fallback name using the download ID
-        let mut file = match &self.file_name {
-            Some(name) => name.into(),
-            None => match extract_filename(response.headers(), &self.url) {
-                Some(name) => name,
-                None => format!("net-manthan-download-{}", self.id).into(),
-            },
-        };
+    /// Ask the server about the file of a new download, see `apply_info`
+    ///
+    /// the future doesn't borrow the download, so it can run outside of whoever
+    /// owns it
+    pub fn fetch_info(
+        &self,
+    ) -> impl Future<Output = Result<FileInfo, DownloadError>> + Send + 'static {
+        let request = info_client().get(&self.url);
+        let (url, file_name) = (self.url.clone(), self.file_name.clone());
+
+        async move {
+            let response = request
+                .send()
+                .await
+                .map_err(DownloadError::HttpRequestError)?;
+            let headers = response.headers();
+
+            // if the request doesn't provide a filename, we try to get it from
+            // the response headers or from the URL
+            let suggested_name = match file_name {
+                Some(name) => Some(name.to_string_lossy().into_owned()),
+                None => extract_filename(headers, &url),
+            };
+            Ok(FileInfo {
+                total_size: header_value(headers, header::CONTENT_LENGTH)
+                    .and_then(|val| val.parse::<u64>().ok())
+                    .unwrap_or(0),
+                resumable: headers.contains_key(header::ACCEPT_RANGES),
+                etag: header_value(headers, header::ETAG),
+                last_modified: header_value(headers, header::LAST_MODIFIED),
+                suggested_name,
+                content_type: header_value(headers, header::CONTENT_TYPE),
+            })
+        }
+    }
 
-        let new_extension = match file.extension() {
-            Some(ext) => {
-                let ext_str = ext.to_str().expect("Non-UTF8 extension");
-                format!("{}.nm", ext_str) // Append ".nm"
+    /// Name the file, pick the category and split the download into parts with
+    /// what `fetch_info` found
+    pub async fn apply_info(&mut self, file: FileInfo) -> Result<(), DownloadError> {
+        let total_size = file.total_size;
+        let resume = file.resumable;
+        self.etag = file.etag;
+        self.last_modified = file.last_modified;
+
+        // without a suggested name a fallback name using the download ID is used,
+        // a missing extension is guessed from the Content-Type
+        let content_type = file.content_type.as_deref();
+        let file_name = self.config.filename_policy.build_filename(
+            file.suggested_name.as_deref(),
+            content_type,
+            &format!("net-manthan-download-{}", self.id),
+            TEMP_EXTENSION.len() + 1,
+        );
+
+        let category = match &self.category {
+            Some(name) => self.config.categories.iter().find(|c| &c.name == name),
+            None => find_category(&self.config.categories, &self.url, &file_name, content_type),
+        }
+        .cloned();
+        if let Some(category) = &category {
+            info!("Download {:?} is in category {}", self.id, category.name);
+            self.apply_category(category);
+        }
+        // an empty directory means the request left it to the config
+        if self.file.as_os_str().is_empty() {
+            self.file = self.config.directory_for(category.as_ref());
+        }
+
+        if let Err(err) = tokio::fs::create_dir_all(&self.file).await {
+            self.status = DownloadStatus::Failed;
+            return Err(DownloadError::FileSystemError(err));
+        }
+        self.file = match claim_file(&self.file, &file_name, TEMP_EXTENSION).await {
+            Ok(file) => file,
+            Err(err) => {
+                self.status = DownloadStatus::Failed;
+                return Err(DownloadError::FileSystemError(err));
             }
-            None => String::from("nm"), // No existing extension; use "nm"
         };
-        file.set_extension(new_extension);
+        self.file_name = self.file.file_name().map(PathBuf::from);
+
+        self.create_parts(total_size, resume);
+        // the parts are new, there is nothing to check with the server
+        self.restored = false;
 
-        self.file_name = Some(file.clone());
-        self.file.push(file);
+        self.status = DownloadStatus::Queued;
 
+        if total_size != self.get_total_size() {
+            self.status = DownloadStatus::Failed;
+            self.parts = DownloadParts::None;
+            self.progress = DownloadPartsProgress::None;
+            return Err(DownloadError::GeneralError("Mismatch in total size".into()));
+        }
+
+        Ok(())
+    }
+
+    /// Ask the server whether the file of a restored download is still the one
+    /// its parts were saved for, without downloading it again
+    ///
+    /// only the first byte is requested, with `If-Range` set to the saved ETag or
+    /// Last-Modified so a changed file answers with a full response. The future
+    /// doesn't borrow the download, so it can run outside of whoever owns it
+    pub fn probe(
+        &self,
+    ) -> impl Future<Output = Result<RemoteFile, DownloadError>> + Send + 'static {
+        let mut request = info_client()
+            .get(&self.url)
+            .header(header::RANGE, "bytes=0-0");
+        if let Some(validator) = self.etag.as_ref().or(self.last_modified.as_ref()) {
+            request = request.header(header::IF_RANGE, validator);
+        }
+        let saved_size = self.get_total_size();
+
+        async move {
+            let response = request
+                .send()
+                .await
+                .map_err(DownloadError::HttpRequestError)?;
+            let headers = response.headers();
+            let etag = header_value(headers, header::ETAG);
+            let last_modified = header_value(headers, header::LAST_MODIFIED);
+            match response.status() {
+                StatusCode::PARTIAL_CONTENT => {
+                    // Content-Range: bytes 0-0/<size>, the size is * if unknown
+                    let total_size = header_value(headers, header::CONTENT_RANGE)
+                        .and_then(|range| range.rsplit_once('/')?.1.parse::<u64>().ok());
+                    Ok(RemoteFile {
+                        total_size,
+                        resumable: true,
+                        etag,
+                        last_modified,
+                        unchanged: total_size.is_none_or(|size| size == saved_size),
+                    })
+                }
+                // a full response means If-Range didn't match or ranges aren't
+                // supported anymore, either way the saved parts are useless
+                status if status.is_success() => Ok(RemoteFile {
+                    total_size: header_value(headers, header::CONTENT_LENGTH)
+                        .and_then(|length| length.parse().ok()),
+                    resumable: headers.contains_key(header::ACCEPT_RANGES),
+                    etag,
+                    last_modified,
+                    unchanged: false,
+                }),
+                status => Err(DownloadError::GeneralError(format!(
+                    "failed to revalidate download, HTTP status code: {}",
+                    status
+                ))),
+            }
+        }
+    }
+
+    /// Apply what `probe` found, a changed file starts over from the first byte
+    pub fn apply_probe(&mut self, file: RemoteFile) {
+        self.restored = false;
+        if file.unchanged {
+            self.etag = file.etag.or(self.etag.take());
+            self.last_modified = file.last_modified.or(self.last_modified.take());
+            return;
+        }
+
+        let total_size = file.total_size.unwrap_or(0);
+        warn!(
+            "Download {} changed on the server (size {} -> {}), starting over",
+            self.id,
+            self.get_total_size(),
+            total_size
+        );
+        self.etag = file.etag;
+        self.last_modified = file.last_modified;
+        self.create_parts(total_size, file.resumable);
+    }
+
+    /// Split a download of `total_size` bytes into parts with no progress yet
+    pub fn create_parts(&mut self, total_size: u64, resume: bool) {
         self.parts = if resume {
             DownloadParts::Resumable(
                 calculate_chunks(total_size, self.config.connections_per_server as u64)
@@ -143,29 +378,7 @@ impl Download {
             })
         };
 
-        self.progress = match &self.parts {
-            DownloadParts::NonResumable(part) => {
-                DownloadPartsProgress::NonResumable(Arc::new(Mutex::new(part.clone())))
-            }
-            DownloadParts::Resumable(parts) => DownloadPartsProgress::Resumable(
-                parts
-                    .iter()
-                    .map(|part| Arc::new(Mutex::new(part.clone())))
-                    .collect(),
-            ),
-            DownloadParts::None => DownloadPartsProgress::None,
-        };
-
-        self.status = DownloadStatus::Queued;
-
-        if total_size != self.get_total_size() {
-            self.status = DownloadStatus::Failed;
-            self.parts = DownloadParts::None;
-            self.progress = DownloadPartsProgress::None;
-            return Err(DownloadError::GeneralError("Mismatch in total size".into()));
-        }
-
-        Ok(())
+        self.progress = DownloadPartsProgress::from(&self.parts);
     }
 
     pub fn set_status(&mut self, status: DownloadStatus) {
@@ -180,6 +393,48 @@ impl Download {
         }
     }
 
+    /// Put the download in `category` and use its options, connections set with
+    /// `set_connections` stay. Call it again after replacing `speed_limiter`
+    /// since the category limit is chained to it
+    pub fn apply_category(&mut self, category: &Category) {
+        self.category = Some(category.name.clone());
+        if let Some(connections) = category.connections
+            && self.connections.is_none()
+        {
+            self.config.connections_per_server = connections.max(1);
+            self.connection_limit
+                .fetch_min(connections.max(1), Ordering::Relaxed);
+        }
+        if let Some(limit) = category.speed_limit {
+            self.speed_limiter =
+                Arc::new(SpeedLimiter::with_parent(limit, self.speed_limiter.clone()));
+        }
+    }
+
+    /// Final path of the file (without the temporary extension), None while the
+    /// name is not known yet
+    pub fn target_file(&self) -> Option<PathBuf> {
+        if self
+            .file
+            .extension()
+            .is_some_and(|ext| ext == TEMP_EXTENSION)
+        {
+            return Some(self.file.with_extension(""));
+        }
+        // a finished download was renamed to its final name
+        if !matches!(self.parts, DownloadParts::None) {
+            return Some(self.file.clone());
+        }
+        self.file_name.as_ref().map(|name| self.file.join(name))
+    }
+
+    /// Host the download connects to, connection limits per server are keyed by it
+    pub fn host(&self) -> Option<String> {
+        Url::parse(&self.url)
+            .ok()
+            .and_then(|url| url.host_str().map(String::from))
+    }
+
     pub fn get_total_size(&self) -> u64 {
         match &self.parts {
             DownloadParts::Resumable(parts) => parts.iter().map(|part| part.get_total_size()).sum(),
@@ -215,15 +470,10 @@ impl Download {
     }
 
     pub fn get_average_speed(&self) -> usize {
-        let milli_seconds = self.active_time.num_milliseconds();
-        let milli_seconds = if milli_seconds < 0 {
-            0 as u64
-        } else {
-            milli_seconds as u64
-        };
+        let milli_seconds = self.active_time.num_milliseconds().max(0) as u64;
         // If active_time is zero, return current speed instead
         // also a safeguard against division by zero
-        if milli_seconds <= 0 {
+        if milli_seconds == 0 {
             return self.get_current_speed();
         }
 
@@ -231,14 +481,42 @@ impl Download {
         ((bytes_downloaded * 1000) / milli_seconds) as usize
     }
 
+    /// Speed averaged over the last few seconds, steadier than the current speed
+    pub fn get_smoothed_speed(&self) -> usize {
+        self.speed.smoothed_speed
+    }
+
+    /// Estimated time remaining based on the smoothed speed,
+    /// None if the download is not progressing or the size is unknown
+    pub fn get_eta(&self) -> Option<Duration> {
+        let total_size = self.get_total_size();
+        let speed = self.get_smoothed_speed() as u64;
+        if total_size == 0 || speed == 0 {
+            return None;
+        }
+
+        let remaining = total_size.saturating_sub(self.get_bytes_downloaded());
+        Some(Duration::seconds((remaining / speed) as i64))
+    }
+
+    /// Recent smoothed speed samples (oldest first) for drawing speed graphs
+    pub fn get_speed_history(&self) -> Vec<usize> {
+        self.speed.history.iter().copied().collect()
+    }
+
     /// Get a formatted string representation of the average speed
     pub fn get_formatted_average_speed(&self) -> String {
-        format!("{}/s", format_bytes(self.get_average_speed() as u64)).into()
+        format!("{}/s", format_bytes(self.get_average_speed() as u64))
     }
 
     /// Get a formatted string representation of the current speed
     pub fn get_formatted_current_speed(&self) -> String {
-        format!("{}/s", format_bytes(self.get_current_speed() as u64)).into()
+        format!("{}/s", format_bytes(self.get_current_speed() as u64))
+    }
+
+    /// Get a formatted string representation of the smoothed speed
+    pub fn get_formatted_smoothed_speed(&self) -> String {
+        format!("{}/s", format_bytes(self.get_smoothed_speed() as u64))
     }
 
     pub async fn update_progress(&mut self) {
@@ -262,24 +540,32 @@ impl Download {
             }
         }
 
+        let finished = matches!(
+            self.get_status(),
+            DownloadStatus::Complete | DownloadStatus::Failed | DownloadStatus::Cancelled
+        );
+        match (finished, self.date_finished) {
+            (true, None) => self.date_finished = Some(Utc::now()),
+            // restarted or retried
+            (false, Some(_)) => self.date_finished = None,
+            _ => {}
+        }
+
         // if we are  not actively downloading, selt last_update_time to none
-        if match self.get_status() {
-            DownloadStatus::Connecting => false,
-            DownloadStatus::Retrying => false,
-            DownloadStatus::Downloading => false,
-            _ => true,
-        } {
+        if !matches!(
+            self.get_status(),
+            DownloadStatus::Connecting | DownloadStatus::Retrying | DownloadStatus::Downloading
+        ) {
             self.last_update_time = None;
+            self.speed.reset();
         };
 
-        match self.last_update_time {
-            Some(last_update_time) => {
-                let now = Utc::now();
-                let diff = now - last_update_time;
-                self.last_update_time = Some(now);
-                self.active_time += diff;
-            }
-            None => {}
+        if let Some(last_update_time) = self.last_update_time {
+            let now = Utc::now();
+            let diff = now - last_update_time;
+            self.last_update_time = Some(now);
+            self.active_time += diff;
+            self.speed.sample(self.get_bytes_downloaded(), now);
         }
     }
 
@@ -289,7 +575,8 @@ impl Download {
                 Download::calculate_status(parts.iter().map(|p| p.status.clone()).collect())
             }
             DownloadParts::NonResumable(part) => part.status.clone(),
-            DownloadParts::None => DownloadStatus::Created,
+            // before the info is loaded only the download itself knows (e.g. Queued)
+            DownloadParts::None => self.status.clone(),
         }
     }
 
@@ -307,17 +594,20 @@ impl Download {
             return DownloadStatus::Complete;
         }
 
-        // Check if all parts are Queued
+        // Check if all non-complete parts are Queued
         let all_queued = status_vec
             .iter()
+            .filter(|p| !matches!(p, DownloadStatus::Complete))
             .all(|p| matches!(p, DownloadStatus::Queued));
         if all_queued {
             return DownloadStatus::Queued;
         }
 
-        // Check if all parts are Cancelled
+        // Check if all non-complete parts are Cancelled, cancelling leaves the
+        // finished parts of a resumable download alone
         let all_cancelled = status_vec
             .iter()
+            .filter(|p| !matches!(p, DownloadStatus::Complete))
             .all(|p| matches!(p, DownloadStatus::Cancelled));
         if all_cancelled {
             return DownloadStatus::Cancelled;
@@ -358,11 +648,13 @@ impl Download {
             return DownloadStatus::Failed;
         }
 
-        // Check if all parts are paused
-        let all_paused = status_vec
+        // Check if all non-complete parts are paused, a download paused halfway
+        // has some complete parts
+        let all_remaining_paused = status_vec
             .iter()
+            .filter(|p| !matches!(p, DownloadStatus::Complete))
             .all(|p| matches!(p, DownloadStatus::Paused));
-        if all_paused {
+        if all_remaining_paused {
             return DownloadStatus::Paused;
         }
 
@@ -370,3 +662,39 @@ impl Download {
         DownloadStatus::Created
     }
 }
+
+/// Client for the requests about the file, a server that stops answering fails
+/// them instead of holding up whoever waits for them
+fn info_client() -> Client {
+    Client::builder()
+        .connect_timeout(INFO_CONNECT_TIMEOUT)
+        .read_timeout(INFO_READ_TIMEOUT)
+        .build()
+        .unwrap_or_default()
+}
+
+fn header_value(headers: &HeaderMap, name: HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancelled_with_complete_parts_is_cancelled() {
+        let status = Download::calculate_status(vec![
+            DownloadStatus::Complete,
+            DownloadStatus::Cancelled,
+            DownloadStatus::Cancelled,
+        ]);
+        assert_eq!(status, DownloadStatus::Cancelled);
+
+        let status =
+            Download::calculate_status(vec![DownloadStatus::Complete, DownloadStatus::Paused]);
+        assert_eq!(status, DownloadStatus::Paused);
+    }
+}
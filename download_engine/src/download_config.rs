@@ -1,9 +1,29 @@
+use std::path::PathBuf;
+
+use crate::{category::Category, filename_policy::FilenamePolicy};
+
 #[derive(Debug, Clone)]
 pub struct DownloadConfig {
     pub buffer_size: usize,
     pub update_interval: usize,
     pub retry_count: usize,
     pub connections_per_server: usize,
+    pub filename_policy: FilenamePolicy,
+    /// directory of downloads whose request leaves it empty, a matching
+    /// category's directory goes inside it unless it is absolute
+    pub download_dir: PathBuf,
+    /// picked when the server answers, the first matching one wins
+    pub categories: Vec<Category>,
+}
+
+impl DownloadConfig {
+    /// Directory of a download whose request leaves it empty
+    pub fn directory_for(&self, category: Option<&Category>) -> PathBuf {
+        match category.and_then(|category| category.directory.as_ref()) {
+            Some(directory) => self.download_dir.join(directory),
+            None => self.download_dir.clone(),
+        }
+    }
 }
 
 impl Default for DownloadConfig {
@@ -15,6 +35,9 @@ impl Default for DownloadConfig {
             update_interval: 500,
             retry_count: 3,
             connections_per_server: 10,
+            filename_policy: FilenamePolicy::default(),
+            download_dir: PathBuf::from("/tmp/"),
+            categories: Vec::new(),
         }
     }
 }
@@ -18,6 +18,23 @@ pub enum DownloadPartsProgress {
     None,
 }
 
+impl From<&DownloadParts> for DownloadPartsProgress {
+    fn from(parts: &DownloadParts) -> Self {
+        match parts {
+            DownloadParts::NonResumable(part) => {
+                DownloadPartsProgress::NonResumable(Arc::new(Mutex::new(part.clone())))
+            }
+            DownloadParts::Resumable(parts) => DownloadPartsProgress::Resumable(
+                parts
+                    .iter()
+                    .map(|part| Arc::new(Mutex::new(part.clone())))
+                    .collect(),
+            ),
+            DownloadParts::None => DownloadPartsProgress::None,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum DownloadPart {
     Resumable(ResumableDownloadPart),
@@ -31,6 +48,13 @@ pub enum DownloadProgressPart {
 }
 
 impl DownloadProgressPart {
+    pub async fn id(&self) -> Uuid {
+        match self {
+            DownloadProgressPart::Resumable(part) => part.lock().await.id,
+            DownloadProgressPart::NonResumable(part) => part.lock().await.id,
+        }
+    }
+
     pub async fn update_status(&self, status: DownloadStatus) {
         match self {
             DownloadProgressPart::Resumable(part) => part.lock().await.status = status,
@@ -53,6 +77,66 @@ impl ResumableDownloadPart {
     pub fn get_total_size(&self) -> u64 {
         self.end_byte - self.start_byte + 1
     }
+
+    /// Bytes of the range not downloaded yet
+    pub fn remaining(&self) -> u64 {
+        self.get_total_size().saturating_sub(self.bytes_downloaded)
+    }
+}
+
+/// Ranges smaller than this aren't split any further, another connection
+/// wouldn't be worth it
+pub const MIN_SPLIT_SIZE: u64 = 1024 * 1024;
+
+/// Change the parts so `count` of them are unfinished, the bytes already
+/// downloaded stay with the part that has them
+///
+/// more parts come from halving the unfinished part with the most bytes left,
+/// fewer from merging a part that hasn't started into the one right before it,
+/// so there may be fewer or more than `count` left. None of the parts may be
+/// downloading while their ranges change
+pub fn resegment(parts: &mut Vec<ResumableDownloadPart>, count: usize) {
+    let count = count.max(1);
+    let unfinished =
+        |parts: &[ResumableDownloadPart]| parts.iter().filter(|part| part.remaining() > 0).count();
+
+    while unfinished(parts) < count {
+        let Some(index) = (0..parts.len()).max_by_key(|index| parts[*index].remaining()) else {
+            break;
+        };
+        let part = &mut parts[index];
+        let remaining = part.remaining();
+        if remaining < 2 * MIN_SPLIT_SIZE {
+            break;
+        }
+        let split_at = part.end_byte + 1 - remaining / 2;
+        let second_half = ResumableDownloadPart {
+            id: Uuid::new_v4(),
+            status: part.status.clone(),
+            start_byte: split_at,
+            end_byte: part.end_byte,
+            bytes_downloaded: 0,
+            current_speed: 0,
+        };
+        part.end_byte = split_at - 1;
+        parts.insert(index + 1, second_half);
+    }
+
+    while unfinished(parts) > count {
+        let Some(index) = (1..parts.len())
+            .filter(|index| {
+                let (before, after) = (&parts[index - 1], &parts[*index]);
+                before.remaining() > 0
+                    && after.bytes_downloaded == 0
+                    && before.end_byte + 1 == after.start_byte
+            })
+            .min_by_key(|index| parts[index - 1].remaining() + parts[*index].remaining())
+        else {
+            break;
+        };
+        let merged = parts.remove(index);
+        parts[index - 1].end_byte = merged.end_byte;
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -63,3 +147,60 @@ pub struct NonResumableDownloadPart {
     pub bytes_downloaded: u64,
     pub current_speed: usize,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: u64 = 1024 * 1024;
+
+    fn part(start_byte: u64, end_byte: u64, bytes_downloaded: u64) -> ResumableDownloadPart {
+        ResumableDownloadPart {
+            id: Uuid::new_v4(),
+            status: DownloadStatus::Queued,
+            start_byte,
+            end_byte,
+            bytes_downloaded,
+            current_speed: 0,
+        }
+    }
+
+    fn ranges(parts: &[ResumableDownloadPart]) -> Vec<(u64, u64, u64)> {
+        parts
+            .iter()
+            .map(|part| (part.start_byte, part.end_byte, part.bytes_downloaded))
+            .collect()
+    }
+
+    #[test]
+    fn resegments_the_remaining_bytes() {
+        // the first part is half done, the second one complete
+        let mut parts = vec![
+            part(0, 8 * MB - 1, 4 * MB),
+            part(8 * MB, 10 * MB - 1, 2 * MB),
+        ];
+        resegment(&mut parts, 2);
+        assert_eq!(
+            ranges(&parts),
+            [
+                (0, 6 * MB - 1, 4 * MB),
+                (6 * MB, 8 * MB - 1, 0),
+                (8 * MB, 10 * MB - 1, 2 * MB),
+            ]
+        );
+        let total: u64 = parts.iter().map(|part| part.get_total_size()).sum();
+        assert_eq!(total, 10 * MB);
+
+        // going back merges the part that hasn't started
+        resegment(&mut parts, 1);
+        assert_eq!(
+            ranges(&parts),
+            [(0, 8 * MB - 1, 4 * MB), (8 * MB, 10 * MB - 1, 2 * MB)]
+        );
+
+        // small ranges stay whole
+        let mut parts = vec![part(0, MB, 0)];
+        resegment(&mut parts, 4);
+        assert_eq!(parts.len(), 1);
+    }
+}
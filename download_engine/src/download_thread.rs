@@ -1,8 +1,18 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, atomic::Ordering},
+};
 
 use crate::{
-    Download, DownloadPartsProgress, DownloadProgressPart, errors::DownloadError,
-    open_file_writer::open_file_writer, types::DownloadStatus,
+    Download, DownloadParts, DownloadPartsProgress, DownloadProgressPart,
+    download::TEMP_EXTENSION,
+    download_part::resegment,
+    errors::DownloadError,
+    filename_policy::numbered_filename,
+    open_file_writer::{claim_file, open_file_writer},
+    types::DownloadStatus,
 };
 use chrono::Utc;
 use futures_util::StreamExt;
@@ -10,8 +20,9 @@ use reqwest::{
     Client,
     header::{self, HeaderMap, HeaderName, HeaderValue},
 };
-use tokio::{io::AsyncWriteExt, sync::Mutex, time::sleep};
+use tokio::{io::AsyncWriteExt, sync::Mutex, task::JoinHandle};
 use tracing::{error, info};
+use uuid::Uuid;
 
 impl Download {
     pub async fn start(&mut self) -> Result<(), DownloadError> {
@@ -27,6 +38,10 @@ impl Download {
                 }
                 Err(err) => return Err(err),
             }
+        } else if self.restored {
+            // parts from an earlier run, make sure they still fit the file
+            let file = self.probe().await?;
+            self.apply_probe(file);
         }
 
         info!("Starting download, id {:?} {:?}", self.id, self.file_name);
@@ -35,34 +50,13 @@ impl Download {
         self.set_status(DownloadStatus::Connecting);
         match &self.progress {
             DownloadPartsProgress::NonResumable(part) => {
-                let me = self.clone();
                 let part = DownloadProgressPart::NonResumable(part.clone());
-                part.update_status(DownloadStatus::Downloading).await;
-                tokio::spawn(async move {
-                    match me.download(&part).await {
-                        Ok(_) => {}
-                        Err(e) => {
-                            part.update_status(DownloadStatus::Failed).await;
-                            error!("Download failed: {}", e)
-                        }
-                    }
-                });
+                self.spawn_part(part).await;
             }
-            DownloadPartsProgress::Resumable(parts) => {
-                for part in parts.iter() {
-                    let me = self.clone();
-                    part.lock().await.status = DownloadStatus::Downloading;
-                    let part = DownloadProgressPart::Resumable(part.clone());
-                    tokio::spawn(async move {
-                        match me.download(&part).await {
-                            Ok(_) => {}
-                            Err(e) => {
-                                part.update_status(DownloadStatus::Failed).await;
-                                error!("Download failed: {}", e)
-                            }
-                        }
-                    });
-                }
+            DownloadPartsProgress::Resumable(_) => {
+                // parts over the connection limit stay Queued until a connection frees up
+                self.set_progress_status(DownloadStatus::Queued).await;
+                self.fill_connections().await;
             }
             DownloadPartsProgress::None => {
                 unreachable!("Download Information should already be loaded.");
@@ -71,6 +65,417 @@ impl Download {
         Ok(())
     }
 
+    /// Change how many parts may download at the same time
+    ///
+    /// extra running parts are stopped and go back to Queued (their flushed bytes
+    /// are kept), free connections pick up Queued parts if the download is running
+    pub async fn limit_connections(&mut self, limit: usize) {
+        let limit = limit.max(1);
+        self.connection_limit.store(limit, Ordering::Relaxed);
+
+        let progress_parts = match &self.progress {
+            DownloadPartsProgress::Resumable(parts) => parts.clone(),
+            // a non resumable download only ever has one connection
+            _ => return,
+        };
+
+        let mut part_ids = Vec::with_capacity(progress_parts.len());
+        for part in progress_parts.iter() {
+            part_ids.push(part.lock().await.id);
+        }
+
+        let mut stopped = Vec::new();
+        {
+            let mut tasks = self.tasks.lock().await;
+            tasks.retain(|_, task| !task.is_finished());
+            // stop the parts further in the file first, the earlier ones are
+            // more useful for anyone previewing the partial file
+            let mut running: Vec<_> = progress_parts
+                .iter()
+                .zip(part_ids.iter())
+                .filter(|(_, id)| tasks.contains_key(id))
+                .collect();
+            while running.len() > limit {
+                let Some((part, id)) = running.pop() else {
+                    break;
+                };
+                if let Some(task) = tasks.remove(id) {
+                    task.abort();
+                    stopped.push((part.clone(), task));
+                }
+            }
+        }
+
+        for (part, task) in stopped {
+            let _ = task.await;
+            let mut part = part.lock().await;
+            if !matches!(part.status, DownloadStatus::Complete) {
+                part.status = DownloadStatus::Queued;
+            }
+        }
+
+        self.fill_connections().await;
+        self.update_progress().await;
+    }
+
+    /// Change how many connections the download may use, this wins over the
+    /// config and the category
+    ///
+    /// the bytes a resumable download still misses are split again into that
+    /// many parts, running parts are stopped for it and continue on their new
+    /// ranges right away. Bytes already flushed to the file are kept
+    pub async fn set_connections(&mut self, connections: usize) {
+        let connections = connections.max(1);
+        info!(
+            "Download {:?} now uses up to {} connections",
+            self.id, connections
+        );
+        self.connections = Some(connections);
+        self.config.connections_per_server = connections;
+        if !matches!(self.progress, DownloadPartsProgress::Resumable(_)) {
+            return;
+        }
+
+        let running = self.stop_connections().await;
+        if let DownloadParts::Resumable(parts) = &mut self.parts {
+            resegment(parts, connections);
+        }
+        self.progress = DownloadPartsProgress::from(&self.parts);
+        if running {
+            self.fill_connections().await;
+        }
+        self.update_progress().await;
+    }
+
+    /// Move the file to `dir` and/or rename it to `file_name` before the download
+    /// completes, a name already taken there gets a number like a new download
+    ///
+    /// running resumable parts are stopped while the file moves and continue
+    /// where they were, a download that can't resume has to be stopped first
+    pub async fn relocate(
+        &mut self,
+        dir: Option<PathBuf>,
+        file_name: Option<String>,
+    ) -> Result<(), DownloadError> {
+        // nothing is on disk before the info is loaded, the new place is used then
+        if matches!(self.parts, DownloadParts::None) {
+            if let Some(dir) = dir {
+                self.file = dir;
+            }
+            if let Some(file_name) = file_name {
+                self.file_name = Some(PathBuf::from(file_name));
+            }
+            return Ok(());
+        }
+
+        let target = self.target_file().unwrap_or_else(|| self.file.clone());
+        let dir = dir.unwrap_or_else(|| target.parent().map(Path::to_path_buf).unwrap_or_default());
+        let file_name = match file_name {
+            Some(file_name) => file_name,
+            None => target
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        };
+        if dir.join(&file_name) == target {
+            return Ok(());
+        }
+        if matches!(self.parts, DownloadParts::NonResumable(_))
+            && self.active_connections().await > 0
+        {
+            return Err(DownloadError::general(
+                "a download that can't resume has to be paused before it moves",
+            ));
+        }
+
+        tokio::fs::create_dir_all(&dir).await?;
+        let file = claim_file(&dir, &file_name, TEMP_EXTENSION).await?;
+        let running = self.stop_connections().await;
+        let moved = move_file(&self.file, &file).await;
+        match moved {
+            Ok(()) => {
+                info!("Moved download {:?} to {:?}", self.id, file);
+                self.file = file;
+                self.file_name = self.file.file_name().map(PathBuf::from);
+            }
+            Err(_) => {
+                let _ = tokio::fs::remove_file(&file).await;
+            }
+        }
+        if running {
+            self.fill_connections().await;
+        }
+        self.update_progress().await;
+        moved.map_err(DownloadError::FileSystemError)
+    }
+
+    /// Rename the temporary file of a complete download to its final name, a
+    /// file that took that name in the meantime gets the next numbered one
+    pub async fn finish_file(&mut self) -> Result<(), DownloadError> {
+        if self.get_status() != DownloadStatus::Complete {
+            return Ok(());
+        }
+        let Some(target) = self.target_file().filter(|target| *target != self.file) else {
+            return Ok(());
+        };
+        let dir = target.parent().map(Path::to_path_buf).unwrap_or_default();
+        let name = target
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut file = target;
+        for n in 1.. {
+            if !tokio::fs::try_exists(&file).await? {
+                break;
+            }
+            file = dir.join(numbered_filename(&name, n));
+        }
+
+        move_file(&self.file, &file).await?;
+        info!("Finished download {:?} as {:?}", self.id, file);
+        self.file_name = file.file_name().map(PathBuf::from);
+        self.file = file;
+        Ok(())
+    }
+
+    /// Stop the running parts of a resumable download and queue them again, their
+    /// flushed bytes are kept. Returns whether any part was running
+    async fn stop_connections(&mut self) -> bool {
+        if !matches!(self.progress, DownloadPartsProgress::Resumable(_)) {
+            return false;
+        }
+        let running = self.active_connections().await > 0;
+        self.abort_tasks().await;
+        if running {
+            self.set_progress_status(DownloadStatus::Queued).await;
+        }
+        self.update_progress().await;
+        running
+    }
+
+    /// Number of parts with a running task
+    pub async fn active_connections(&self) -> usize {
+        let mut tasks = self.tasks.lock().await;
+        tasks.retain(|_, task| !task.is_finished());
+        tasks.len()
+    }
+
+    /// Spawn tasks for Queued parts until `connection_limit` parts are running
+    async fn fill_connections(&self) {
+        self.hand_over_connection(None).await
+    }
+
+    /// `fill_connections` without counting the task of part `finished`, a part
+    /// task calls it with its own part once it is done with its connection
+    ///
+    /// boxed since the part tasks call it, which makes the future recursive
+    fn hand_over_connection(
+        &self,
+        finished: Option<Uuid>,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(async move {
+            let progress_parts = match &self.progress {
+                DownloadPartsProgress::Resumable(parts) => parts.clone(),
+                _ => return,
+            };
+
+            let limit = self.connection_limit.load(Ordering::Relaxed);
+            let mut running = {
+                let mut tasks = self.tasks.lock().await;
+                tasks.retain(|_, task| !task.is_finished());
+                tasks.keys().filter(|id| Some(**id) != finished).count()
+            };
+            for part in progress_parts {
+                if running >= limit {
+                    break;
+                }
+                {
+                    let mut part = part.lock().await;
+                    if !matches!(part.status, DownloadStatus::Queued) {
+                        continue;
+                    }
+                    // its task was stopped after the last byte, nothing to ask for
+                    if part.remaining() == 0 {
+                        part.status = DownloadStatus::Complete;
+                        continue;
+                    }
+                }
+                self.spawn_part(DownloadProgressPart::Resumable(part)).await;
+                running += 1;
+            }
+        })
+    }
+
+    /// Mark the part Downloading and spawn the task downloading it
+    async fn spawn_part(&self, part: DownloadProgressPart) {
+        let me = self.clone();
+        let id = part.id().await;
+        part.update_status(DownloadStatus::Downloading).await;
+        // no await between spawning and keeping the handle, an aborted caller
+        // would leave a task nobody can stop
+        let mut tasks = self.tasks.lock().await;
+        let task = tokio::spawn(async move {
+            let result = me.download(&part).await;
+            // hand the connection to a Queued part while this one still counts
+            // as running, so the download never looks Queued in between
+            me.hand_over_connection(Some(id)).await;
+            match result {
+                Ok(()) => match &part {
+                    DownloadProgressPart::Resumable(part) => {
+                        let mut part = part.lock().await;
+                        // a server closing the connection early leaves bytes missing
+                        part.status = match part.remaining() {
+                            0 => DownloadStatus::Complete,
+                            _ => DownloadStatus::Failed,
+                        };
+                    }
+                    DownloadProgressPart::NonResumable(part) => {
+                        let mut part = part.lock().await;
+                        // without a known size the end of the stream is the end of the file
+                        part.status =
+                            if part.total_size == 0 || part.bytes_downloaded >= part.total_size {
+                                DownloadStatus::Complete
+                            } else {
+                                DownloadStatus::Failed
+                            };
+                    }
+                },
+                Err(e) => {
+                    part.update_status(DownloadStatus::Failed).await;
+                    error!("Download failed: {}", e)
+                }
+            }
+        });
+        tasks.insert(id, task);
+    }
+
+    /// Abort all running part tasks and mark the unfinished parts Cancelled,
+    /// returns once every task has actually stopped
+    pub async fn cancel(&mut self) {
+        info!("Cancelling download, id {:?}", self.id);
+        self.abort_tasks().await;
+        self.set_progress_status(DownloadStatus::Cancelled).await;
+        self.status = DownloadStatus::Cancelled;
+        self.update_progress().await;
+    }
+
+    /// Stop all running part tasks and mark the unfinished parts Paused,
+    /// resumable parts keep the bytes they already flushed
+    pub async fn pause(&mut self) {
+        info!("Pausing download, id {:?}", self.id);
+        self.abort_tasks().await;
+        self.set_progress_status(DownloadStatus::Paused).await;
+        self.status = DownloadStatus::Paused;
+        self.update_progress().await;
+    }
+
+    /// Set every part that isn't complete yet to `status`, e.g. Failed when
+    /// the download can't start
+    pub async fn mark_unfinished(&mut self, status: DownloadStatus) {
+        self.set_progress_status(status.clone()).await;
+        self.status = status;
+        self.update_progress().await;
+    }
+
+    /// Put a paused (or failed) download back in the queue, it continues from
+    /// its saved progress once started again
+    pub async fn requeue(&mut self) {
+        // a non resumable download can only start over from the first byte
+        if let DownloadPartsProgress::NonResumable(part) = &self.progress {
+            part.lock().await.bytes_downloaded = 0;
+        }
+        self.set_progress_status(DownloadStatus::Queued).await;
+        self.status = DownloadStatus::Queued;
+        self.update_progress().await;
+    }
+
+    /// Throw away the progress and queue the download to start over from the
+    /// first byte, the file keeps its name and is overwritten
+    pub async fn restart(&mut self) {
+        info!("Restarting download, id {:?}", self.id);
+        self.abort_tasks().await;
+        match &self.parts {
+            DownloadParts::Resumable(_) => self.create_parts(self.get_total_size(), true),
+            DownloadParts::NonResumable(_) => self.create_parts(self.get_total_size(), false),
+            DownloadParts::None => {}
+        }
+        self.active_time = chrono::Duration::zero();
+        self.status = DownloadStatus::Queued;
+        self.update_progress().await;
+    }
+
+    /// Cancel the download and optionally delete its file from disk
+    ///
+    /// for an unfinished download this is the partial `.nm` file, for a
+    /// completed one it is the finished file, so callers decide whether to keep it
+    pub async fn remove(&mut self, delete_file: bool) -> Result<(), DownloadError> {
+        self.cancel().await;
+
+        // the file name is only known once the download info is loaded
+        if !delete_file || self.file_name.is_none() {
+            return Ok(());
+        }
+
+        match tokio::fs::remove_file(&self.file).await {
+            Ok(_) => {
+                info!("Deleted file {:?} of download {:?}", self.file, self.id);
+                Ok(())
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(DownloadError::FileSystemError(err)),
+        }
+    }
+
+    /// Abort the part tasks and wait for them to finish so nothing
+    /// writes to the file after this returns
+    async fn abort_tasks(&self) {
+        // a part finishing right now may hand its connection to a new task
+        loop {
+            let tasks: Vec<JoinHandle<()>> = self
+                .tasks
+                .lock()
+                .await
+                .drain()
+                .map(|(_, task)| task)
+                .collect();
+            if tasks.is_empty() {
+                break;
+            }
+            for task in tasks.iter() {
+                task.abort();
+            }
+            for task in tasks {
+                let _ = task.await;
+            }
+        }
+    }
+
+    /// Set the status of every part that isn't complete yet in the shared progress
+    ///
+    /// a resumable part without remaining bytes is Complete, its task may have
+    /// been stopped after the last byte but before it marked the part
+    async fn set_progress_status(&self, status: DownloadStatus) {
+        match &self.progress {
+            DownloadPartsProgress::NonResumable(part) => {
+                let mut part = part.lock().await;
+                if !matches!(part.status, DownloadStatus::Complete) {
+                    part.status = status;
+                }
+            }
+            DownloadPartsProgress::Resumable(parts) => {
+                for part in parts {
+                    let mut part = part.lock().await;
+                    if part.remaining() == 0 {
+                        part.status = DownloadStatus::Complete;
+                    } else if !matches!(part.status, DownloadStatus::Complete) {
+                        part.status = status.clone();
+                    }
+                }
+            }
+            DownloadPartsProgress::None => {}
+        }
+    }
+
     async fn download(&self, part: &DownloadProgressPart) -> Result<(), DownloadError> {
         let last_flush_time = Arc::new(Mutex::new(Utc::now()));
 
@@ -80,13 +485,13 @@ impl Download {
         if let Some(headers) = &self.headers {
             let mut header_map = HeaderMap::new();
             for header in headers {
-                if let Some((name, value)) = header.split_once(": ") {
-                    if let (Ok(name), Ok(value)) = (
+                if let Some((name, value)) = header.split_once(": ")
+                    && let (Ok(name), Ok(value)) = (
                         HeaderName::from_bytes(name.as_bytes()),
                         HeaderValue::from_str(value),
-                    ) {
-                        header_map.insert(name, value);
-                    }
+                    )
+                {
+                    header_map.insert(name, value);
                 }
             }
             req = req.headers(header_map);
@@ -124,7 +529,6 @@ impl Download {
             // there is no way we can insert a on_flush on an existing bufwriter easily
             // the only way is to write my own, very own simple async bufwriter
             Box::new(move |bytes_flushed| {
-                let bytes_flushed = bytes_flushed;
                 let last_flush_time = last_flush_time.clone();
                 let part = part_clone.clone();
                 futures::executor::block_on(async move {
@@ -139,15 +543,12 @@ impl Download {
                             let mut part = part.lock().await;
                             part.bytes_downloaded += bytes_flushed as u64;
                             part.current_speed = current_speed;
-                            if part.bytes_downloaded == (*part).get_total_size() {
-                                part.status = DownloadStatus::Complete;
-                            }
                         }
                         DownloadProgressPart::NonResumable(part) => {
                             let mut part = part.lock().await;
                             part.bytes_downloaded += bytes_flushed as u64;
                             part.current_speed = current_speed;
-                            if part.bytes_downloaded == (*part).total_size {
+                            if part.bytes_downloaded == part.total_size {
                                 part.status = DownloadStatus::Complete;
                             }
                         }
@@ -191,6 +592,8 @@ impl Download {
         while let Some(chunk) = stream.next().await {
             match chunk {
                 Ok(chunk) => {
+                    self.own_speed_limiter.consume(chunk.len()).await;
+                    self.speed_limiter.consume(chunk.len()).await;
                     writer.write_all(&chunk).await?;
                 }
                 Err(err) => {
@@ -201,8 +604,127 @@ impl Download {
 
         writer.flush().await?;
 
-        sleep(Duration::from_secs(1)).await;
-
         Ok(())
     }
 }
+
+/// Rename `from` to `to`, copying it over if they are on different file systems
+async fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if tokio::fs::rename(from, to).await.is_ok() {
+        return Ok(());
+    }
+    tokio::fs::copy(from, to).await?;
+    tokio::fs::remove_file(from).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        NonResumableDownloadPart, ResumableDownloadPart, download_config::DownloadConfig,
+        types::DownloadRequest,
+    };
+
+    fn part(start_byte: u64, end_byte: u64, status: DownloadStatus) -> ResumableDownloadPart {
+        ResumableDownloadPart {
+            id: Uuid::new_v4(),
+            status,
+            start_byte,
+            end_byte,
+            bytes_downloaded: end_byte + 1 - start_byte,
+            current_speed: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn parts_stopped_after_their_last_byte_stay_complete() {
+        let request = DownloadRequest {
+            // nothing may be requested, every byte is there
+            url: "http://127.0.0.1:9/file".into(),
+            file_dir: PathBuf::from("/tmp/"),
+            file_name: None,
+            referrer: None,
+            headers: None,
+            priority: Default::default(),
+            checksum: None,
+            category: None,
+        };
+        let mut download = Download::new(request, &DownloadConfig::default());
+        // the first part's stream ended but its task was stopped before it
+        // marked the part Complete
+        download.parts = DownloadParts::Resumable(vec![
+            part(0, 99, DownloadStatus::Downloading),
+            part(100, 199, DownloadStatus::Complete),
+        ]);
+        download.progress = DownloadPartsProgress::from(&download.parts);
+
+        download.pause().await;
+        assert_eq!(download.get_status(), DownloadStatus::Complete);
+
+        download.requeue().await;
+        download.start().await.unwrap();
+        assert_eq!(download.active_connections().await, 0);
+        download.update_progress().await;
+        assert_eq!(download.get_status(), DownloadStatus::Complete);
+    }
+
+    #[tokio::test]
+    async fn chunked_downloads_without_a_length_complete() {
+        use tokio::io::AsyncReadExt;
+
+        let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/file", server.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut socket, _) = server.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = socket.read(&mut request).await.unwrap();
+            socket
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+                      5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n",
+                )
+                .await
+                .unwrap();
+        });
+
+        let request = DownloadRequest {
+            url,
+            file_dir: std::env::temp_dir(),
+            file_name: None,
+            referrer: None,
+            headers: None,
+            priority: Default::default(),
+            checksum: None,
+            category: None,
+        };
+        let mut download = Download::new(request, &DownloadConfig::default());
+        download.file = std::env::temp_dir().join(format!("{}.{}", Uuid::new_v4(), TEMP_EXTENSION));
+        download.file_name = download.file.file_name().map(PathBuf::from);
+        // the server sent no Content-Length, the size is unknown
+        download.parts = DownloadParts::NonResumable(NonResumableDownloadPart {
+            id: Uuid::new_v4(),
+            status: DownloadStatus::Queued,
+            total_size: 0,
+            bytes_downloaded: 0,
+            current_speed: 0,
+        });
+        download.progress = DownloadPartsProgress::from(&download.parts);
+
+        download.start().await.unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while download.active_connections().await > 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the download never finished");
+        download.update_progress().await;
+
+        assert_eq!(download.get_status(), DownloadStatus::Complete);
+        assert_eq!(
+            tokio::fs::read_to_string(&download.file).await.unwrap(),
+            "hello world"
+        );
+        download.remove(true).await.unwrap();
+    }
+}
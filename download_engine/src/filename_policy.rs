@@ -0,0 +1,478 @@
+// Filenames that are valid on every platform we support
+//
+// the rules are the union of what Linux (ext4), macOS (APFS) and Windows (NTFS)
+// refuse, so a download started on one machine can be moved to any other
+
+/// Characters not allowed in filenames on at least one platform
+const INVALID_CHARS: &[char] = &['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
+
+/// Names Windows reserves for devices, with or without an extension
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Extensions longer than this are treated as part of the name when truncating
+const MAX_EXTENSION_BYTES: usize = 16;
+
+/// Rules for turning a suggested name into a filename safe on every platform
+#[derive(Debug, Clone)]
+pub struct FilenamePolicy {
+    /// maximum length of the filename in bytes (255 on ext4 and most other filesystems)
+    pub max_bytes: usize,
+    /// replace non-ASCII characters with ASCII look-alikes (e.g. "Grüße" -> "Gruesse")
+    pub transliterate: bool,
+}
+
+impl Default for FilenamePolicy {
+    fn default() -> Self {
+        Self {
+            max_bytes: 255,
+            transliterate: false,
+        }
+    }
+}
+
+impl FilenamePolicy {
+    /// Build the filename for a download
+    ///
+    /// `name` is the suggested name (request, Content-Disposition or URL), `fallback` is
+    /// used when there is none, a missing extension is inferred from `content_type` and
+    /// `reserved_bytes` are kept free for a suffix added later (e.g. the ".nm" extension)
+    pub fn build_filename(
+        &self,
+        name: Option<&str>,
+        content_type: Option<&str>,
+        fallback: &str,
+        reserved_bytes: usize,
+    ) -> String {
+        let mut filename = name
+            .map(|name| self.sanitize(name))
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| self.sanitize(fallback));
+
+        if split_extension(&filename).1.is_none()
+            && let Some(extension) = content_type.and_then(extension_from_mime)
+        {
+            filename = format!("{}.{}", filename, extension);
+        }
+
+        truncate_filename(
+            &filename,
+            self.max_bytes.saturating_sub(reserved_bytes).max(1),
+        )
+    }
+
+    /// Make `name` a valid filename, returns an empty string if nothing usable is left
+    pub fn sanitize(&self, name: &str) -> String {
+        let mut sanitized = String::with_capacity(name.len());
+        for c in name.chars() {
+            if c.is_control() || INVALID_CHARS.contains(&c) {
+                sanitized.push('_');
+            } else if self.transliterate && !c.is_ascii() {
+                match transliterate(c) {
+                    Some(replacement) => sanitized.push_str(replacement),
+                    None => sanitized.push('_'),
+                }
+            } else {
+                sanitized.push(c);
+            }
+        }
+
+        // leading dots make hidden files, Windows drops trailing dots and spaces
+        let sanitized = sanitized
+            .trim()
+            .trim_start_matches('.')
+            .trim_end_matches(['.', ' ']);
+
+        let stem = sanitized.split('.').next().unwrap_or_default().trim_end();
+        let sanitized = if WINDOWS_RESERVED_NAMES
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+        {
+            format!("_{}", sanitized)
+        } else {
+            sanitized.to_string()
+        };
+
+        truncate_filename(&sanitized, self.max_bytes)
+    }
+}
+
+/// `name` with a counter before the extension, e.g. `file (2).zip`, used when a
+/// name is already taken
+pub fn numbered_filename(name: &str, n: usize) -> String {
+    match split_extension(name) {
+        (stem, Some(extension)) => format!("{} ({}).{}", stem, n, extension),
+        (stem, None) => format!("{} ({})", stem, n),
+    }
+}
+
+/// Split a filename into its stem and extension (without the dot)
+fn split_extension(filename: &str) -> (&str, Option<&str>) {
+    match filename.rsplit_once('.') {
+        Some((stem, extension))
+            if !stem.is_empty() && !extension.is_empty() && !extension.contains(' ') =>
+        {
+            (stem, Some(extension))
+        }
+        _ => (filename, None),
+    }
+}
+
+/// Cut `filename` down to `max_bytes` on a char boundary, keeping the extension
+fn truncate_filename(filename: &str, max_bytes: usize) -> String {
+    if filename.len() <= max_bytes {
+        return filename.to_string();
+    }
+
+    let (stem, extension) = match split_extension(filename) {
+        (stem, Some(extension)) if extension.len() < MAX_EXTENSION_BYTES => (stem, extension),
+        _ => (filename, ""),
+    };
+    let suffix_len = if extension.is_empty() {
+        0
+    } else {
+        extension.len() + 1
+    };
+
+    let mut end = max_bytes.saturating_sub(suffix_len).min(stem.len());
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    let stem = stem[..end].trim_end_matches(['.', ' ']);
+
+    if extension.is_empty() {
+        stem.to_string()
+    } else {
+        format!("{}.{}", stem, extension)
+    }
+}
+
+/// Guess a file extension from a Content-Type header value
+pub fn extension_from_mime(content_type: &str) -> Option<&'static str> {
+    let mime = content_type.split(';').next()?.trim().to_ascii_lowercase();
+
+    let extension = match mime.as_str() {
+        // archives and packages
+        "application/zip" | "application/x-zip-compressed" => "zip",
+        "application/x-7z-compressed" => "7z",
+        "application/vnd.rar" | "application/x-rar-compressed" => "rar",
+        "application/gzip" | "application/x-gzip" => "gz",
+        "application/x-tar" => "tar",
+        "application/x-bzip2" => "bz2",
+        "application/x-xz" => "xz",
+        "application/zstd" => "zst",
+        "application/x-iso9660-image" => "iso",
+        "application/x-apple-diskimage" => "dmg",
+        "application/vnd.debian.binary-package" => "deb",
+        "application/x-rpm" => "rpm",
+        "application/vnd.android.package-archive" => "apk",
+        "application/x-msdownload" | "application/vnd.microsoft.portable-executable" => "exe",
+        "application/x-msi" | "application/x-ms-installer" => "msi",
+        "application/x-bittorrent" => "torrent",
+        "application/wasm" => "wasm",
+        // documents
+        "application/pdf" => "pdf",
+        "application/epub+zip" => "epub",
+        "application/rtf" => "rtf",
+        "application/msword" => "doc",
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document" => "docx",
+        "application/vnd.ms-excel" => "xls",
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet" => "xlsx",
+        "application/vnd.ms-powerpoint" => "ppt",
+        "application/vnd.openxmlformats-officedocument.presentationml.presentation" => "pptx",
+        "application/vnd.oasis.opendocument.text" => "odt",
+        "application/vnd.oasis.opendocument.spreadsheet" => "ods",
+        "application/json" => "json",
+        "application/xml" | "text/xml" => "xml",
+        "application/javascript" | "text/javascript" => "js",
+        "text/plain" => "txt",
+        "text/html" => "html",
+        "text/css" => "css",
+        "text/csv" => "csv",
+        "text/markdown" => "md",
+        // images
+        "image/jpeg" => "jpg",
+        "image/png" => "png",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/avif" => "avif",
+        "image/svg+xml" => "svg",
+        "image/bmp" => "bmp",
+        "image/tiff" => "tiff",
+        "image/x-icon" | "image/vnd.microsoft.icon" => "ico",
+        // audio
+        "audio/mpeg" => "mp3",
+        "audio/ogg" => "ogg",
+        "audio/opus" => "opus",
+        "audio/wav" | "audio/x-wav" => "wav",
+        "audio/flac" => "flac",
+        "audio/aac" => "aac",
+        "audio/mp4" => "m4a",
+        // video
+        "video/mp4" => "mp4",
+        "video/webm" => "webm",
+        "video/x-matroska" => "mkv",
+        "video/quicktime" => "mov",
+        "video/x-msvideo" => "avi",
+        "video/mpeg" => "mpeg",
+        // fonts
+        "font/ttf" => "ttf",
+        "font/otf" => "otf",
+        "font/woff" => "woff",
+        "font/woff2" => "woff2",
+        _ => return None,
+    };
+
+    Some(extension)
+}
+
+/// ASCII replacement for common Latin, Greek and Cyrillic letters
+fn transliterate(c: char) -> Option<&'static str> {
+    let replacement = match c {
+        'À' | 'Á' | 'Â' | 'Ã' | 'Å' | 'Ā' | 'Ă' | 'Ą' => "A",
+        'à' | 'á' | 'â' | 'ã' | 'å' | 'ā' | 'ă' | 'ą' => "a",
+        'Ä' | 'Æ' => "Ae",
+        'ä' | 'æ' => "ae",
+        'Ç' | 'Ć' | 'Č' => "C",
+        'ç' | 'ć' | 'č' => "c",
+        'Ď' | 'Đ' | 'Ð' => "D",
+        'ď' | 'đ' | 'ð' => "d",
+        'È' | 'É' | 'Ê' | 'Ë' | 'Ē' | 'Ė' | 'Ę' | 'Ě' => "E",
+        'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ė' | 'ę' | 'ě' => "e",
+        'Ğ' => "G",
+        'ğ' => "g",
+        'Ì' | 'Í' | 'Î' | 'Ï' | 'Ī' | 'İ' => "I",
+        'ì' | 'í' | 'î' | 'ï' | 'ī' | 'ı' => "i",
+        'Ł' => "L",
+        'ł' => "l",
+        'Ñ' | 'Ń' | 'Ň' => "N",
+        'ñ' | 'ń' | 'ň' => "n",
+        'Ò' | 'Ó' | 'Ô' | 'Õ' | 'Ø' | 'Ō' | 'Ő' => "O",
+        'ò' | 'ó' | 'ô' | 'õ' | 'ø' | 'ō' | 'ő' => "o",
+        'Ö' | 'Œ' => "Oe",
+        'ö' | 'œ' => "oe",
+        'Ř' => "R",
+        'ř' => "r",
+        'Ś' | 'Š' | 'Ş' => "S",
+        'ś' | 'š' | 'ş' => "s",
+        'ß' => "ss",
+        'Ť' | 'Ţ' => "T",
+        'ť' | 'ţ' => "t",
+        'Þ' => "Th",
+        'þ' => "th",
+        'Ù' | 'Ú' | 'Û' | 'Ů' | 'Ū' | 'Ű' => "U",
+        'ù' | 'ú' | 'û' | 'ů' | 'ū' | 'ű' => "u",
+        'Ü' => "Ue",
+        'ü' => "ue",
+        'Ý' | 'Ÿ' => "Y",
+        'ý' | 'ÿ' => "y",
+        'Ź' | 'Ż' | 'Ž' => "Z",
+        'ź' | 'ż' | 'ž' => "z",
+        // Greek
+        'Α' => "A",
+        'α' => "a",
+        'Β' => "B",
+        'β' => "b",
+        'Γ' => "G",
+        'γ' => "g",
+        'Δ' => "D",
+        'δ' => "d",
+        'Ε' => "E",
+        'ε' => "e",
+        'Ζ' => "Z",
+        'ζ' => "z",
+        'Η' => "I",
+        'η' => "i",
+        'Θ' => "Th",
+        'θ' => "th",
+        'Ι' => "I",
+        'ι' => "i",
+        'Κ' => "K",
+        'κ' => "k",
+        'Λ' => "L",
+        'λ' => "l",
+        'Μ' => "M",
+        'μ' => "m",
+        'Ν' => "N",
+        'ν' => "n",
+        'Ξ' => "X",
+        'ξ' => "x",
+        'Ο' => "O",
+        'ο' => "o",
+        'Π' => "P",
+        'π' => "p",
+        'Ρ' => "R",
+        'ρ' => "r",
+        'Σ' => "S",
+        'σ' | 'ς' => "s",
+        'Τ' => "T",
+        'τ' => "t",
+        'Υ' => "Y",
+        'υ' => "y",
+        'Φ' => "F",
+        'φ' => "f",
+        'Χ' => "Ch",
+        'χ' => "ch",
+        'Ψ' => "Ps",
+        'ψ' => "ps",
+        'Ω' => "O",
+        'ω' => "o",
+        // Cyrillic
+        'А' => "A",
+        'а' => "a",
+        'Б' => "B",
+        'б' => "b",
+        'В' => "V",
+        'в' => "v",
+        'Г' => "G",
+        'г' => "g",
+        'Д' => "D",
+        'д' => "d",
+        'Е' | 'Ё' | 'Э' => "E",
+        'е' | 'ё' | 'э' => "e",
+        'Ж' => "Zh",
+        'ж' => "zh",
+        'З' => "Z",
+        'з' => "z",
+        'И' => "I",
+        'и' => "i",
+        'Й' | 'Ы' => "Y",
+        'й' | 'ы' => "y",
+        'К' => "K",
+        'к' => "k",
+        'Л' => "L",
+        'л' => "l",
+        'М' => "M",
+        'м' => "m",
+        'Н' => "N",
+        'н' => "n",
+        'О' => "O",
+        'о' => "o",
+        'П' => "P",
+        'п' => "p",
+        'Р' => "R",
+        'р' => "r",
+        'С' => "S",
+        'с' => "s",
+        'Т' => "T",
+        'т' => "t",
+        'У' => "U",
+        'у' => "u",
+        'Ф' => "F",
+        'ф' => "f",
+        'Х' => "Kh",
+        'х' => "kh",
+        'Ц' => "Ts",
+        'ц' => "ts",
+        'Ч' => "Ch",
+        'ч' => "ch",
+        'Ш' => "Sh",
+        'ш' => "sh",
+        'Щ' => "Shch",
+        'щ' => "shch",
+        'Ю' => "Yu",
+        'ю' => "yu",
+        'Я' => "Ya",
+        'я' => "ya",
+        'Ъ' | 'ъ' | 'Ь' | 'ь' => "",
+        // punctuation that often shows up in titles
+        '‘' | '’' | '‚' | '′' => "'",
+        '“' | '”' | '„' => "'",
+        '–' | '—' | '‐' => "-",
+        '…' => "...",
+        '\u{a0}' => " ",
+        _ => return None,
+    };
+
+    Some(replacement)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_invalid_and_control_characters() {
+        let policy = FilenamePolicy::default();
+        assert_eq!(
+            policy.sanitize("a/b\\c:d*e?f\"g<h>i|j"),
+            "a_b_c_d_e_f_g_h_i_j"
+        );
+        assert_eq!(policy.sanitize("tab\there\u{0}.txt"), "tab_here_.txt");
+    }
+
+    #[test]
+    fn trims_dots_and_spaces() {
+        let policy = FilenamePolicy::default();
+        assert_eq!(policy.sanitize("  .hidden.txt  "), "hidden.txt");
+        assert_eq!(policy.sanitize("report. . ."), "report");
+        assert_eq!(policy.sanitize("..."), "");
+    }
+
+    #[test]
+    fn escapes_windows_reserved_names() {
+        let policy = FilenamePolicy::default();
+        assert_eq!(policy.sanitize("CON"), "_CON");
+        assert_eq!(policy.sanitize("nul.txt"), "_nul.txt");
+        assert_eq!(policy.sanitize("com1.tar.gz"), "_com1.tar.gz");
+        assert_eq!(policy.sanitize("CONSOLE.txt"), "CONSOLE.txt");
+    }
+
+    #[test]
+    fn truncates_to_byte_limit_keeping_extension() {
+        let policy = FilenamePolicy::default();
+        let long = format!("{}.tar.gz", "日".repeat(100));
+        let sanitized = policy.sanitize(&long);
+        assert!(sanitized.len() <= 255);
+        assert!(sanitized.ends_with(".gz"));
+        assert!(sanitized.starts_with('日'));
+
+        let filename = policy.build_filename(Some(&"a".repeat(300)), None, "fallback", 3);
+        assert_eq!(filename.len(), 252);
+    }
+
+    #[test]
+    fn infers_extension_from_content_type() {
+        let policy = FilenamePolicy::default();
+        assert_eq!(
+            policy.build_filename(Some("download"), Some("application/pdf"), "fallback", 0),
+            "download.pdf"
+        );
+        assert_eq!(
+            policy.build_filename(None, Some("video/mp4; codecs=avc1"), "nm-download-1", 0),
+            "nm-download-1.mp4"
+        );
+        assert_eq!(
+            policy.build_filename(
+                Some("archive.zip"),
+                Some("application/octet-stream"),
+                "f",
+                0
+            ),
+            "archive.zip"
+        );
+        assert_eq!(
+            policy.build_filename(Some("..."), Some("application/octet-stream"), "f", 0),
+            "f"
+        );
+    }
+
+    #[test]
+    fn transliterates_when_enabled() {
+        let policy = FilenamePolicy {
+            transliterate: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.sanitize("Grüße aus Köln.txt"),
+            "Gruesse aus Koeln.txt"
+        );
+        assert_eq!(policy.sanitize("отчет.docx"), "otchet.docx");
+        assert_eq!(policy.sanitize("日本.txt"), "__.txt");
+
+        let policy = FilenamePolicy::default();
+        assert_eq!(policy.sanitize("Grüße.txt"), "Grüße.txt");
+    }
+}
@@ -1,10 +1,15 @@
 pub mod buf_writer_on_flush;
+pub mod category;
+pub mod content_disposition;
 pub mod download;
 pub mod download_config;
 pub mod download_part;
 pub mod download_thread;
 pub mod errors;
+pub mod filename_policy;
 pub mod open_file_writer;
+pub mod speed_limiter;
+pub mod speed_tracker;
 pub mod types;
 pub mod utils;
 
@@ -1,10 +1,42 @@
-use std::{io::SeekFrom, path::PathBuf};
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+};
 use tokio::{
     fs::{File, OpenOptions},
     io::{self, AsyncSeekExt},
 };
 
-use crate::buf_writer_on_flush::BufWriterWithOnFlush;
+use crate::{buf_writer_on_flush::BufWriterWithOnFlush, filename_policy::numbered_filename};
+
+/// Create `dir/name.extension` for a new download, numbering the name if that
+/// file (or the finished `dir/name`) already exists, so two downloads never
+/// write to the same file. Returns the path that was created
+pub async fn claim_file(dir: &Path, name: &str, extension: &str) -> Result<PathBuf, io::Error> {
+    for n in 0.. {
+        let name = match n {
+            0 => name.to_string(),
+            n => numbered_filename(name, n),
+        };
+        if tokio::fs::try_exists(dir.join(&name)).await? {
+            continue;
+        }
+
+        let file = dir.join(format!("{}.{}", name, extension));
+        // create_new fails if the file exists, even if someone else creates it right now
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&file)
+            .await
+        {
+            Ok(_) => return Ok(file),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    unreachable!("ran out of numbered names")
+}
 
 pub async fn open_file_writer(
     file: PathBuf,
@@ -16,6 +48,8 @@ pub async fn open_file_writer(
     let file = OpenOptions::new()
         .write(true)
         .create(true)
+        // parts write into the same file at different offsets, never truncate
+        .truncate(false)
         .open(file)
         .await?;
 
@@ -0,0 +1,95 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicU64, Ordering},
+};
+use tokio::{
+    sync::Mutex,
+    time::{Duration, Instant, sleep},
+};
+
+/// Token bucket shared by every connection it is handed to, so one limiter
+/// can cap a single download or all of them together
+///
+/// bytes are taken before they are written, going below zero makes the caller
+/// sleep off the debt, the bucket holds at most one second worth of bytes.
+/// A limiter with a parent takes the bytes from the parent too, so a single
+/// download can have its own limit on top of the global one
+#[derive(Debug)]
+pub struct SpeedLimiter {
+    /// bytes per second, 0 means unlimited
+    bytes_per_second: AtomicU64,
+    /// available bytes and when they were last refilled
+    bucket: Mutex<(f64, Instant)>,
+    parent: Option<Arc<SpeedLimiter>>,
+}
+
+impl Default for SpeedLimiter {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl SpeedLimiter {
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second: AtomicU64::new(bytes_per_second),
+            bucket: Mutex::new((0.0, Instant::now())),
+            parent: None,
+        }
+    }
+
+    /// A limiter that also takes every byte from `parent`
+    pub fn with_parent(bytes_per_second: u64, parent: Arc<SpeedLimiter>) -> Self {
+        Self {
+            parent: Some(parent),
+            ..Self::new(bytes_per_second)
+        }
+    }
+
+    /// Current limit in bytes per second, 0 means unlimited
+    pub fn limit(&self) -> u64 {
+        self.bytes_per_second.load(Ordering::Relaxed)
+    }
+
+    /// Change the limit, connections already waiting pick it up on their next chunk
+    pub fn set_limit(&self, bytes_per_second: u64) {
+        self.bytes_per_second
+            .store(bytes_per_second, Ordering::Relaxed);
+    }
+
+    /// Take `bytes` from the bucket and the ones of its parents, waiting if a
+    /// limit is exceeded
+    pub async fn consume(&self, bytes: usize) {
+        let mut limiter = Some(self);
+        while let Some(current) = limiter {
+            current.consume_own(bytes).await;
+            limiter = current.parent.as_deref();
+        }
+    }
+
+    async fn consume_own(&self, bytes: usize) {
+        let rate = self.limit();
+        if rate == 0 {
+            return;
+        }
+        let rate = rate as f64;
+
+        let wait = {
+            let mut bucket = self.bucket.lock().await;
+            let (available, last_refill) = &mut *bucket;
+            let now = Instant::now();
+            *available =
+                (*available + now.duration_since(*last_refill).as_secs_f64() * rate).min(rate);
+            *last_refill = now;
+            *available -= bytes as f64;
+            match *available < 0.0 {
+                true => Duration::from_secs_f64(-*available / rate),
+                false => Duration::ZERO,
+            }
+        };
+
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+    }
+}
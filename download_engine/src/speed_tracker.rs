@@ -0,0 +1,122 @@
+use chrono::{DateTime, Utc};
+use std::collections::VecDeque;
+
+/// minimum time between two samples, shorter gaps are too noisy to be useful
+const SAMPLE_INTERVAL_MS: i64 = 1000;
+/// weight of the newest sample in the moving average
+const SMOOTHING_FACTOR: f64 = 0.3;
+/// number of samples kept for drawing speed graphs (one per SAMPLE_INTERVAL)
+pub const SPEED_HISTORY_LEN: usize = 60;
+
+/// Exponentially weighted moving average of the download speed along with
+/// a ring buffer of the recent speed samples
+#[derive(Clone, Debug, Default)]
+pub struct SpeedTracker {
+    /// smoothed speed in bytes per second
+    pub smoothed_speed: usize,
+    /// recent smoothed speed samples in bytes per second, oldest first
+    pub history: VecDeque<usize>,
+    /// time and downloaded bytes at the last sample, None means not sampling
+    last_sample: Option<(DateTime<Utc>, u64)>,
+    /// whether smoothed_speed holds a sample since sampling (re)started, the
+    /// first one after a reset starts the average over
+    seeded: bool,
+}
+
+impl SpeedTracker {
+    /// Rebuild a tracker from already computed values (e.g. received over RPC)
+    pub fn new(smoothed_speed: usize, history: VecDeque<usize>) -> Self {
+        Self {
+            smoothed_speed,
+            history,
+            last_sample: None,
+            seeded: false,
+        }
+    }
+
+    /// Feed the total downloaded bytes at `now`, a new sample is only taken
+    /// once SAMPLE_INTERVAL_MS has passed since the previous one
+    pub fn sample(&mut self, bytes_downloaded: u64, now: DateTime<Utc>) {
+        let (last_time, last_bytes) = match self.last_sample {
+            Some(last_sample) => last_sample,
+            None => {
+                self.last_sample = Some((now, bytes_downloaded));
+                return;
+            }
+        };
+
+        let elapsed = (now - last_time).num_milliseconds();
+        if elapsed < SAMPLE_INTERVAL_MS {
+            return;
+        }
+
+        let speed = (bytes_downloaded.saturating_sub(last_bytes) * 1000 / elapsed as u64) as usize;
+        self.smoothed_speed = if !self.seeded {
+            self.seeded = true;
+            speed
+        } else {
+            (SMOOTHING_FACTOR * speed as f64
+                + (1.0 - SMOOTHING_FACTOR) * self.smoothed_speed as f64) as usize
+        };
+
+        if self.history.len() == SPEED_HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(self.smoothed_speed);
+        self.last_sample = Some((now, bytes_downloaded));
+    }
+
+    /// Stop sampling (download is not active anymore), the history is kept
+    pub fn reset(&mut self) {
+        self.smoothed_speed = 0;
+        self.last_sample = None;
+        self.seeded = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+
+    #[test]
+    fn smooths_samples_taken_a_second_apart() {
+        let start = Utc::now();
+        let mut tracker = SpeedTracker::default();
+        tracker.sample(0, start);
+        assert!(tracker.history.is_empty());
+
+        // too soon after the first sample
+        tracker.sample(500, start + TimeDelta::milliseconds(400));
+        assert!(tracker.history.is_empty());
+
+        tracker.sample(1000, start + TimeDelta::seconds(1));
+        assert_eq!(tracker.smoothed_speed, 1000);
+
+        tracker.sample(3000, start + TimeDelta::seconds(2));
+        // 0.3 * 2000 + 0.7 * 1000
+        assert_eq!(tracker.smoothed_speed, 1300);
+        assert_eq!(tracker.history, [1000, 1300]);
+
+        tracker.reset();
+        assert_eq!(tracker.smoothed_speed, 0);
+        assert_eq!(tracker.history.len(), 2);
+
+        // resuming starts the average over instead of blending with 0
+        tracker.sample(3000, start + TimeDelta::seconds(10));
+        tracker.sample(5000, start + TimeDelta::seconds(11));
+        assert_eq!(tracker.smoothed_speed, 2000);
+        assert_eq!(tracker.history, [1000, 1300, 2000]);
+    }
+
+    #[test]
+    fn keeps_the_newest_samples() {
+        let start = Utc::now();
+        let mut tracker = SpeedTracker::default();
+        for second in 0..=SPEED_HISTORY_LEN as i64 + 5 {
+            tracker.sample(second as u64 * 100, start + TimeDelta::seconds(second));
+        }
+        assert_eq!(tracker.history.len(), SPEED_HISTORY_LEN);
+        assert!(tracker.history.iter().all(|speed| *speed == 100));
+    }
+}
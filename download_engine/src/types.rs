@@ -1,6 +1,6 @@
 use std::path::PathBuf;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum DownloadStatus {
     Created,
     Queued,
@@ -13,6 +13,15 @@ pub enum DownloadStatus {
     Cancelled,
 }
 
+/// Order in which queued downloads start and keep their connections
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DownloadPriority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
 #[derive(Debug, Clone)]
 pub struct DownloadRequest {
     pub url: String,
@@ -20,4 +29,9 @@ pub struct DownloadRequest {
     pub file_name: Option<PathBuf>,
     pub referrer: Option<String>,
     pub headers: Option<Vec<String>>,
+    pub priority: DownloadPriority,
+    /// expected checksum of the content, e.g. `sha-256=<hex>`
+    pub checksum: Option<String>,
+    /// name of the category to put the download in, picked by its rules if None
+    pub category: Option<String>,
 }
@@ -1,5 +1,5 @@
+use crate::content_disposition::ContentDisposition;
 use reqwest::header::{CONTENT_DISPOSITION, HeaderMap};
-use std::path::PathBuf;
 use url::Url;
 
 /// format bytes from bytes
@@ -74,126 +74,73 @@ pub fn calculate_chunks(total_size: u64, num_chunks: u64) -> Vec<(u64, u64)> {
 /// This function tries multiple approaches to get the filename:
 /// 1. From Content-Disposition header
 /// 2. From the URL path
-pub fn extract_filename(headers: &HeaderMap, url: &str) -> Option<PathBuf> {
+///
+/// The name is returned as sent by the server, it has to go through
+/// a `FilenamePolicy` before being used on disk
+pub fn extract_filename(headers: &HeaderMap, url: &str) -> Option<String> {
     // Try to get filename from Content-Disposition header
     if let Some(filename) = extract_filename_from_content_disposition(headers) {
-        return Some(PathBuf::from(filename));
+        return Some(filename);
     }
 
     // Try to get filename from URL
-    if let Some(filename) = extract_filename_from_url(url) {
-        return Some(PathBuf::from(filename));
-    }
+    extract_filename_from_url(url)
+}
 
-    None
+/// Url in a canonical form for comparing downloads: scheme and host lowercased,
+/// default port and fragment dropped. Unparsable urls are only trimmed
+pub fn normalize_url(url: &str) -> String {
+    match Url::parse(url.trim()) {
+        Ok(mut url) => {
+            url.set_fragment(None);
+            url.to_string()
+        }
+        Err(_) => url.trim().to_string(),
+    }
 }
 
 /// Extract filename from Content-Disposition header
 fn extract_filename_from_content_disposition(headers: &HeaderMap) -> Option<String> {
     let content_disposition = headers.get(CONTENT_DISPOSITION)?;
-    let content_disposition = content_disposition.to_str().ok()?;
-
-    // Handle various Content-Disposition formats
-
-    // 1. Try filename=
-    if let Some(pos) = content_disposition.find("filename=") {
-        let start = pos + "filename=".len();
-        let mut filename = content_disposition[start..].trim_start();
-
-        // Handle quoted filenames
-        if filename.starts_with('"') && filename.len() > 1 {
-            filename = &filename[1..];
-            if let Some(end_quote) = filename.find('"') {
-                filename = &filename[..end_quote];
-            }
-        } else {
-            // Non-quoted filename ends at first semicolon or whitespace
-            if let Some(end_pos) = filename.find(|c| c == ';' || c == ' ') {
-                filename = &filename[..end_pos];
-            }
-        }
-
-        if !filename.is_empty() {
-            return Some(sanitize_filename(filename));
-        }
-    }
-
-    // 2. Try filename*=
-    if let Some(pos) = content_disposition.find("filename*=") {
-        let start = pos + "filename*=".len();
-        let value = content_disposition[start..].trim_start();
-
-        // Handle UTF-8 encoding format: UTF-8''filename
-        if value.starts_with("UTF-8''") || value.starts_with("utf-8''") {
-            let encoded_filename = value.split('\'').nth(2)?;
-            let decoded = percent_decode(encoded_filename);
-            if !decoded.is_empty() {
-                return Some(sanitize_filename(&decoded));
-            }
-        } else {
-            // Simple case without encoding specification
-            let end_pos = value.find(|c| c == ';' || c == ' ').unwrap_or(value.len());
-            let filename = &value[..end_pos];
-            if !filename.is_empty() {
-                return Some(sanitize_filename(filename));
-            }
-        }
-    }
-
-    None
+    ContentDisposition::parse_bytes(content_disposition.as_bytes())?.filename()
 }
 
 /// Extract filename from URL path
 fn extract_filename_from_url(url_str: &str) -> Option<String> {
     let url = Url::parse(url_str).ok()?;
-    let path = url.path();
-
-    // Get the last segment of the path
-    let segments: Vec<&str> = path.split('/').collect();
-    let last_segment = segments.last()?;
 
+    // Get the last segment of the path, the query is not part of the path
+    let last_segment = url.path_segments()?.next_back()?;
     if last_segment.is_empty() {
-        None
-    } else {
-        // Remove query parameters if present
-        let clean_segment = last_segment.split('?').next().unwrap_or(last_segment);
-        Some(percent_decode(&sanitize_filename(clean_segment)))
+        return None;
     }
+
+    Some(String::from_utf8_lossy(&percent_decode(last_segment)).into_owned())
 }
 
-/// URL-decode a percent-encoded string
-fn percent_decode(input: &str) -> String {
-    let mut output = String::with_capacity(input.len());
-    let mut bytes = input.bytes().peekable();
-
-    while let Some(byte) = bytes.next() {
-        if byte == b'%' {
-            let hex1 = bytes.next();
-            let hex2 = bytes.next();
-
-            if let (Some(h1), Some(h2)) = (hex1, hex2) {
-                if let (Some(d1), Some(d2)) = (decode_hex_digit(h1), decode_hex_digit(h2)) {
-                    let decoded_byte = (d1 << 4) | d2;
-                    // Add as UTF-8 character
-                    output.push(decoded_byte as char);
-                    continue;
-                }
-            }
-
-            // If we can't decode, just add the percent sign and continue
-            output.push('%');
-            if let Some(h1) = hex1 {
-                output.push(h1 as char);
-            }
-            if let Some(h2) = hex2 {
-                output.push(h2 as char);
-            }
-        } else if byte == b'+' {
-            // In some encodings, + represents space
-            output.push(' ');
-        } else {
-            output.push(byte as char);
+/// Decode a percent-encoded string into raw bytes
+///
+/// `+` is left as is, it only means space in form encoding, not in URL paths
+/// or RFC 8187 ext-values. Invalid escapes are kept verbatim.
+pub fn percent_decode(input: &str) -> Vec<u8> {
+    let bytes = input.as_bytes();
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && i + 2 < bytes.len()
+            && let (Some(d1), Some(d2)) = (
+                decode_hex_digit(bytes[i + 1]),
+                decode_hex_digit(bytes[i + 2]),
+            )
+        {
+            output.push((d1 << 4) | d2);
+            i += 3;
+            continue;
         }
+        output.push(bytes[i]);
+        i += 1;
     }
 
     output
@@ -208,23 +155,3 @@ fn decode_hex_digit(digit: u8) -> Option<u8> {
         _ => None,
     }
 }
-
-/// Sanitize filename by removing invalid characters
-fn sanitize_filename(filename: &str) -> String {
-    // List of characters not allowed in filenames on most platforms
-    const INVALID_CHARS: &[char] = &['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
-
-    let sanitized: String = filename
-        .chars()
-        .map(|c| if INVALID_CHARS.contains(&c) { '_' } else { c })
-        .collect();
-
-    // Trim leading/trailing whitespace and dots
-    let sanitized = sanitized.trim().trim_start_matches('.');
-
-    if sanitized.is_empty() {
-        String::from("unnamed_file")
-    } else {
-        sanitized.to_string()
-    }
-}
@@ -0,0 +1,138 @@
+use crate::scheduler::parse_rate;
+use download_engine::category::{Category, CategoryRule};
+use std::path::PathBuf;
+
+/// Parse a category given on the command line:
+///
+/// `NAME [dir:PATH] [ext:EXT,..] [mime:TYPE,..] [host:HOST,..] [url:PATTERN]
+/// [connections:N] [limit:RATE]`
+///
+/// e.g. `video dir:Videos ext:mp4,mkv mime:video/* limit:2M`. Rules and options
+/// can be repeated, `*` in mime types and url patterns matches anything
+pub fn parse_category(category: &str) -> Result<Category, String> {
+    let mut tokens = category.split_whitespace();
+    let name = tokens
+        .next()
+        .ok_or(format!("invalid category \"{}\"", category))?;
+    let mut parsed = Category {
+        name: name.to_string(),
+        rules: Vec::new(),
+        directory: None,
+        connections: None,
+        speed_limit: None,
+    };
+
+    for token in tokens {
+        let (key, value) = token
+            .split_once(':')
+            .ok_or(format!("expected KEY:VALUE, got \"{}\"", token))?;
+        let values = value.split(',').filter(|value| !value.is_empty());
+        match key {
+            "dir" => parsed.directory = Some(PathBuf::from(value)),
+            "ext" => parsed.rules.extend(
+                values.map(|ext| CategoryRule::Extension(ext.trim_start_matches('.').into())),
+            ),
+            "mime" => parsed
+                .rules
+                .extend(values.map(|mime| CategoryRule::Mime(mime.into()))),
+            "host" => parsed
+                .rules
+                .extend(values.map(|host| CategoryRule::Host(host.into()))),
+            "url" => parsed.rules.push(CategoryRule::Url(value.into())),
+            "connections" => {
+                parsed.connections = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("invalid connections \"{}\"", value))?,
+                )
+            }
+            "limit" => parsed.speed_limit = Some(parse_rate(value)?),
+            _ => return Err(format!("unknown category key \"{}\"", key)),
+        }
+    }
+
+    Ok(parsed)
+}
+
+/// Categories used when none are configured, each goes to a directory named
+/// after it inside the download directory
+pub fn default_categories() -> Vec<Category> {
+    [
+        (
+            "Video",
+            &["mp4", "mkv", "webm", "avi", "mov", "m4v", "flv", "wmv"][..],
+            "video/*",
+        ),
+        (
+            "Audio",
+            &["mp3", "flac", "ogg", "opus", "m4a", "wav", "aac"][..],
+            "audio/*",
+        ),
+        (
+            "Images",
+            &["jpg", "jpeg", "png", "gif", "webp", "svg", "bmp"][..],
+            "image/*",
+        ),
+        (
+            "Archives",
+            &["zip", "rar", "7z", "tar", "gz", "xz", "bz2", "zst", "iso"][..],
+            "application/zip",
+        ),
+        (
+            "Documents",
+            &[
+                "pdf", "doc", "docx", "odt", "xls", "xlsx", "ppt", "pptx", "epub", "txt",
+            ][..],
+            "application/pdf",
+        ),
+        (
+            "Software",
+            &[
+                "exe", "msi", "dmg", "pkg", "deb", "rpm", "appimage", "apk", "flatpak",
+            ][..],
+            "application/vnd.microsoft.portable-executable",
+        ),
+    ]
+    .into_iter()
+    .map(|(name, extensions, mime)| Category {
+        name: name.to_string(),
+        rules: extensions
+            .iter()
+            .map(|ext| CategoryRule::Extension(ext.to_string()))
+            .chain([CategoryRule::Mime(mime.to_string())])
+            .collect(),
+        directory: Some(PathBuf::from(name)),
+        connections: None,
+        speed_limit: None,
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_categories() {
+        let category = parse_category(
+            "video dir:/media/videos ext:.mp4,mkv mime:video/* connections:4 limit:1M",
+        )
+        .unwrap();
+        assert_eq!(category.name, "video");
+        assert_eq!(category.directory, Some(PathBuf::from("/media/videos")));
+        assert_eq!(
+            category.rules,
+            [
+                CategoryRule::Extension("mp4".into()),
+                CategoryRule::Extension("mkv".into()),
+                CategoryRule::Mime("video/*".into()),
+            ]
+        );
+        assert_eq!(category.connections, Some(4));
+        assert_eq!(category.speed_limit, Some(1024 * 1024));
+
+        assert!(parse_category("").is_err());
+        assert!(parse_category("video ext").is_err());
+        assert!(parse_category("video size:10").is_err());
+    }
+}
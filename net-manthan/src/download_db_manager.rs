@@ -0,0 +1,843 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use download_engine::{
+    Download, DownloadParts, DownloadPartsProgress, NonResumableDownloadPart,
+    ResumableDownloadPart,
+    download_config::DownloadConfig,
+    types::{DownloadPriority, DownloadRequest, DownloadStatus},
+};
+use rusqlite::{Connection, Row, params};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Schema migrations, applied in order. The number of applied migrations is
+/// kept in `PRAGMA user_version`, so only ever append to this list
+const MIGRATIONS: &[&str] = &[
+    // 1: downloads and their parts
+    "CREATE TABLE downloads (
+        id TEXT PRIMARY KEY,
+        position INTEGER NOT NULL,
+        url TEXT NOT NULL,
+        file TEXT NOT NULL,
+        file_name TEXT,
+        headers TEXT,
+        referrer TEXT,
+        status TEXT NOT NULL,
+        priority TEXT NOT NULL,
+        resumable BOOLEAN,
+        total_size INTEGER NOT NULL,
+        bytes_downloaded INTEGER NOT NULL,
+        date_added TEXT NOT NULL,
+        date_finished TEXT,
+        active_time INTEGER NOT NULL
+    );
+    CREATE TABLE download_parts (
+        id TEXT PRIMARY KEY,
+        download_id TEXT NOT NULL,
+        part_index INTEGER NOT NULL,
+        status TEXT NOT NULL,
+        start_byte INTEGER NOT NULL,
+        end_byte INTEGER NOT NULL,
+        total_size INTEGER NOT NULL,
+        bytes_downloaded INTEGER NOT NULL,
+        FOREIGN KEY (download_id) REFERENCES downloads (id) ON DELETE CASCADE
+    );
+    CREATE INDEX idx_parts_download_id ON download_parts (download_id);",
+    // 2: scheduler rules changed over RPC
+    "CREATE TABLE schedules (
+        position INTEGER PRIMARY KEY,
+        rule TEXT NOT NULL
+    );",
+    // 3: checksum given with the request, used to find duplicates
+    "ALTER TABLE downloads ADD COLUMN checksum TEXT;",
+    // 4: category picked by the category rules
+    "ALTER TABLE downloads ADD COLUMN category TEXT;",
+    // 5: finished downloads, kept for the statistics after they are purged
+    "CREATE TABLE download_history (
+        id TEXT PRIMARY KEY,
+        host TEXT,
+        status TEXT NOT NULL,
+        bytes_downloaded INTEGER NOT NULL,
+        active_time INTEGER NOT NULL,
+        date_finished TEXT NOT NULL
+    );
+    INSERT INTO download_history (id, status, bytes_downloaded, active_time, date_finished)
+        SELECT id, status, bytes_downloaded, active_time, date_finished FROM downloads
+        WHERE date_finished IS NOT NULL;",
+    // 6: options changed over RPC for a single download
+    "ALTER TABLE downloads ADD COLUMN connections INTEGER;
+    ALTER TABLE downloads ADD COLUMN speed_limit INTEGER NOT NULL DEFAULT 0;",
+    // 7: validators telling whether the file changed on the server
+    "ALTER TABLE downloads ADD COLUMN etag TEXT;
+    ALTER TABLE downloads ADD COLUMN last_modified TEXT;",
+];
+
+const DOWNLOAD_COLUMNS: &str = "id, url, file, file_name, headers, referrer, status, priority,
+    resumable, date_added, active_time, checksum, category, date_finished, connections,
+    speed_limit, etag, last_modified";
+
+// connecting to the database
+/// Where downloads are saved unless the user picks a file, in the user's data
+/// directory so it doesn't depend on where the daemon is started from
+pub fn default_database_file() -> String {
+    let data_dir = match std::env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ if cfg!(windows) => std::env::var_os("APPDATA")
+            .map(PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir),
+        _ => std::env::var_os("HOME")
+            .map(|home| PathBuf::from(home).join(".local").join("share"))
+            .unwrap_or_else(std::env::temp_dir),
+    };
+    data_dir
+        .join("net-manthan")
+        .join("net-manthan.db")
+        .to_string_lossy()
+        .into_owned()
+}
+
+pub fn connect_to_database(db_path: &PathBuf) -> Result<DatabaseManager> {
+    if let Some(parent) = db_path.parent() {
+        std::fs::create_dir_all(parent)
+            .context("database directory doesn't exist and cannot be created")?;
+    }
+
+    DatabaseManager::new(db_path)
+}
+
+/// Keeps downloads and the progress of their parts on disk
+pub struct DatabaseManager {
+    conn: Connection,
+}
+
+impl DatabaseManager {
+    /// Opens the database and brings the schema up to date
+    pub fn new<P: AsRef<Path>>(db_path: P) -> Result<Self> {
+        let conn = Connection::open(db_path).context("Failed to open database connection")?;
+        conn.pragma_update(None, "foreign_keys", true)
+            .context("Failed to enable foreign keys")?;
+
+        let mut manager = DatabaseManager { conn };
+        manager.migrate()?;
+
+        Ok(manager)
+    }
+
+    /// Applies the migrations the database hasn't seen yet
+    fn migrate(&mut self) -> Result<()> {
+        let version: usize = self
+            .conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .context("Failed to read schema version")?;
+
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = self
+                .conn
+                .transaction()
+                .context("Failed to begin transaction")?;
+            tx.execute_batch(migration)
+                .with_context(|| format!("Failed to apply migration {}", index + 1))?;
+            tx.pragma_update(None, "user_version", index + 1)
+                .context("Failed to update schema version")?;
+            tx.commit().context("Failed to commit transaction")?;
+        }
+
+        Ok(())
+    }
+
+    /// Inserts or updates downloads together with their parts, `position` is
+    /// the place of the download in the queue
+    pub fn save_downloads<'a>(
+        &mut self,
+        downloads: impl IntoIterator<Item = (usize, &'a Download)>,
+    ) -> Result<()> {
+        let tx = self
+            .conn
+            .transaction()
+            .context("Failed to begin transaction")?;
+
+        for (position, download) in downloads {
+            let status = download.get_status();
+            let resumable = match &download.parts {
+                DownloadParts::Resumable(_) => Some(true),
+                DownloadParts::NonResumable(_) => Some(false),
+                DownloadParts::None => None,
+            };
+            let date_finished = download.date_finished.map(|date| date.to_rfc3339());
+
+            tx.execute(
+                "INSERT INTO downloads (
+                    id, position, url, file, file_name, headers, referrer, status, priority,
+                    resumable, total_size, bytes_downloaded, date_added, date_finished, active_time,
+                    checksum, category, connections, speed_limit, etag, last_modified
+                ) VALUES (
+                    ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17,
+                    ?18, ?19, ?20, ?21
+                )
+                ON CONFLICT (id) DO UPDATE SET
+                    position = excluded.position,
+                    file = excluded.file,
+                    file_name = excluded.file_name,
+                    headers = excluded.headers,
+                    connections = excluded.connections,
+                    speed_limit = excluded.speed_limit,
+                    etag = excluded.etag,
+                    last_modified = excluded.last_modified,
+                    status = excluded.status,
+                    priority = excluded.priority,
+                    resumable = excluded.resumable,
+                    total_size = excluded.total_size,
+                    bytes_downloaded = excluded.bytes_downloaded,
+                    date_finished = excluded.date_finished,
+                    active_time = excluded.active_time,
+                    category = excluded.category",
+                params![
+                    download.id.to_string(),
+                    position,
+                    download.url,
+                    download.file.to_string_lossy(),
+                    download
+                        .file_name
+                        .as_ref()
+                        .map(|name| name.to_string_lossy()),
+                    download.headers.as_ref().map(|headers| headers.join("\n")),
+                    download.referrer,
+                    status_to_str(&status),
+                    priority_to_str(&download.priority),
+                    resumable,
+                    download.get_total_size(),
+                    download.get_bytes_downloaded(),
+                    download.date_added.to_rfc3339(),
+                    date_finished,
+                    download.active_time.num_milliseconds(),
+                    download.checksum,
+                    download.category,
+                    download.connections,
+                    download.own_speed_limiter.limit(),
+                    download.etag,
+                    download.last_modified,
+                ],
+            )
+            .context("Failed to save download")?;
+
+            if let Some(date_finished) = &date_finished
+                && matches!(
+                    status,
+                    DownloadStatus::Complete | DownloadStatus::Failed | DownloadStatus::Cancelled
+                )
+            {
+                tx.execute(
+                    "INSERT INTO download_history (
+                        id, host, status, bytes_downloaded, active_time, date_finished
+                    ) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                    ON CONFLICT (id) DO UPDATE SET
+                        status = excluded.status,
+                        bytes_downloaded = excluded.bytes_downloaded,
+                        active_time = excluded.active_time,
+                        date_finished = excluded.date_finished",
+                    params![
+                        download.id.to_string(),
+                        download.host(),
+                        status_to_str(&status),
+                        download.get_bytes_downloaded(),
+                        download.active_time.num_milliseconds(),
+                        date_finished,
+                    ],
+                )
+                .context("Failed to save download history")?;
+            }
+
+            // parts are recreated when a download restarts, so replace them all
+            tx.execute(
+                "DELETE FROM download_parts WHERE download_id = ?1",
+                [download.id.to_string()],
+            )
+            .context("Failed to delete old download parts")?;
+
+            let parts: Vec<(Uuid, &DownloadStatus, u64, u64, u64, u64)> = match &download.parts {
+                DownloadParts::Resumable(parts) => parts
+                    .iter()
+                    .map(|part| {
+                        (
+                            part.id,
+                            &part.status,
+                            part.start_byte,
+                            part.end_byte,
+                            part.get_total_size(),
+                            part.bytes_downloaded,
+                        )
+                    })
+                    .collect(),
+                DownloadParts::NonResumable(part) => vec![(
+                    part.id,
+                    &part.status,
+                    0,
+                    part.total_size.saturating_sub(1),
+                    part.total_size,
+                    part.bytes_downloaded,
+                )],
+                DownloadParts::None => Vec::new(),
+            };
+
+            for (index, (id, status, start_byte, end_byte, total_size, bytes_downloaded)) in
+                parts.into_iter().enumerate()
+            {
+                tx.execute(
+                    "INSERT INTO download_parts (
+                        id, download_id, part_index, status, start_byte, end_byte, total_size,
+                        bytes_downloaded
+                    ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                    params![
+                        id.to_string(),
+                        download.id.to_string(),
+                        index,
+                        status_to_str(status),
+                        start_byte,
+                        end_byte,
+                        total_size,
+                        bytes_downloaded,
+                    ],
+                )
+                .context("Failed to save download part")?;
+            }
+        }
+
+        tx.commit().context("Failed to commit transaction")?;
+        Ok(())
+    }
+
+    /// Retrieves all downloads in queue order
+    pub fn get_all_downloads(&self, config: &DownloadConfig) -> Result<Vec<Download>> {
+        self.query_downloads("1", config)
+    }
+
+    /// Deletes a download and all its parts
+    pub fn delete_download(&self, id: &Uuid) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM downloads WHERE id = ?1", [id.to_string()])
+            .context("Failed to delete download")?;
+
+        Ok(())
+    }
+
+    /// Scheduler rules in the order they were set
+    pub fn get_schedules(&self) -> Result<Vec<String>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT rule FROM schedules ORDER BY position")
+            .context("Failed to prepare statement")?;
+        let rules = stmt
+            .query_map([], |row| row.get(0))
+            .context("Failed to query schedules")?
+            .collect::<rusqlite::Result<Vec<String>>>()
+            .context("Failed to read schedule")?;
+
+        Ok(rules)
+    }
+
+    /// Replaces all scheduler rules
+    pub fn save_schedules(&mut self, rules: &[String]) -> Result<()> {
+        let tx = self
+            .conn
+            .transaction()
+            .context("Failed to begin transaction")?;
+        tx.execute("DELETE FROM schedules", [])
+            .context("Failed to clear schedules")?;
+        for (position, rule) in rules.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO schedules (position, rule) VALUES (?1, ?2)",
+                params![position, rule],
+            )
+            .context("Failed to save schedule")?;
+        }
+        tx.commit().context("Failed to commit transaction")?;
+
+        Ok(())
+    }
+
+    /// Statistics of the downloads in the list and of every download that
+    /// finished, `days` is the number of days in `per_day`
+    pub fn get_download_stats(&self, days: u32) -> Result<DownloadStats> {
+        let mut stats = self
+            .conn
+            .query_row(
+                "SELECT
+                    COUNT(*),
+                    COUNT(*) FILTER (WHERE status IN ('connecting', 'retrying', 'downloading')),
+                    COUNT(*) FILTER (WHERE status = 'paused'),
+                    COALESCE(SUM(bytes_downloaded), 0)
+                FROM downloads",
+                [],
+                |row| {
+                    Ok(DownloadStats {
+                        total_downloads: row.get(0)?,
+                        active_downloads: row.get(1)?,
+                        paused_downloads: row.get(2)?,
+                        total_downloaded_bytes: row.get(3)?,
+                        ..Default::default()
+                    })
+                },
+            )
+            .context("Failed to query download stats")?;
+
+        let active_time: u64;
+        (
+            stats.succeeded,
+            stats.failed,
+            stats.cancelled,
+            stats.history_bytes,
+            active_time,
+        ) = self
+            .conn
+            .query_row(
+                "SELECT
+                    COUNT(*) FILTER (WHERE status = 'complete'),
+                    COUNT(*) FILTER (WHERE status = 'failed'),
+                    COUNT(*) FILTER (WHERE status = 'cancelled'),
+                    COALESCE(SUM(bytes_downloaded), 0),
+                    COALESCE(SUM(active_time), 0)
+                FROM download_history",
+                [],
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(4)?,
+                    ))
+                },
+            )
+            .context("Failed to query download history")?;
+        stats.average_speed = (stats.history_bytes * 1000)
+            .checked_div(active_time)
+            .unwrap_or(0);
+
+        stats.per_day = self.history_buckets(
+            "date(date_finished, 'localtime')",
+            "WHERE date(date_finished, 'localtime') > date('now', 'localtime', ?1)",
+            "key DESC",
+            [format!("-{} days", days)],
+        )?;
+        stats.per_host = self.history_buckets(
+            "COALESCE(host, 'unknown')",
+            "",
+            "SUM(bytes_downloaded) DESC",
+            [],
+        )?;
+
+        Ok(stats)
+    }
+
+    /// Finished downloads matching `condition`, grouped by `key`
+    ///
+    /// only the totals of a download are kept, so all its bytes count on the
+    /// day it finished even if it ran over several days
+    fn history_buckets(
+        &self,
+        key: &str,
+        condition: &str,
+        order: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<Vec<StatsBucket>> {
+        let mut stmt = self
+            .conn
+            .prepare(&format!(
+                "SELECT {} AS key,
+                    SUM(bytes_downloaded),
+                    COUNT(*) FILTER (WHERE status = 'complete'),
+                    COUNT(*) FILTER (WHERE status = 'failed'),
+                    COUNT(*) FILTER (WHERE status = 'cancelled')
+                FROM download_history {} GROUP BY key ORDER BY {}",
+                key, condition, order
+            ))
+            .context("Failed to prepare statement")?;
+
+        let buckets = stmt
+            .query_map(params, |row| {
+                Ok(StatsBucket {
+                    key: row.get(0)?,
+                    bytes: row.get(1)?,
+                    succeeded: row.get(2)?,
+                    failed: row.get(3)?,
+                    cancelled: row.get(4)?,
+                })
+            })
+            .context("Failed to query download history")?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to read download history")?;
+        Ok(buckets)
+    }
+
+    fn query_downloads(&self, condition: &str, config: &DownloadConfig) -> Result<Vec<Download>> {
+        let mut stmt = self
+            .conn
+            .prepare(&format!(
+                "SELECT {} FROM downloads WHERE {} ORDER BY position",
+                DOWNLOAD_COLUMNS, condition
+            ))
+            .context("Failed to prepare statement")?;
+
+        let download_iter = stmt
+            .query_map([], |row| row_to_download(row, config))
+            .context("Failed to query downloads")?;
+
+        let mut downloads = Vec::new();
+        for download_result in download_iter {
+            let mut download = download_result.context("Failed to read download")?;
+            self.load_parts(&mut download)?;
+            downloads.push(download);
+        }
+
+        Ok(downloads)
+    }
+
+    /// Loads the parts of a download and rebuilds its shared progress from them
+    fn load_parts(&self, download: &mut Download) -> Result<()> {
+        let resumable: Option<bool> = self
+            .conn
+            .query_row(
+                "SELECT resumable FROM downloads WHERE id = ?1",
+                [download.id.to_string()],
+                |row| row.get(0),
+            )
+            .context("Failed to query download")?;
+
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT id, status, start_byte, end_byte, total_size, bytes_downloaded
+                FROM download_parts WHERE download_id = ?1 ORDER BY part_index",
+            )
+            .context("Failed to prepare statement")?;
+
+        let part_iter = stmt
+            .query_map([download.id.to_string()], |row| {
+                Ok((
+                    parse_uuid(row, 0)?,
+                    status_from_str(&row.get::<_, String>(1)?),
+                    row.get::<_, u64>(2)?,
+                    row.get::<_, u64>(3)?,
+                    row.get::<_, u64>(4)?,
+                    row.get::<_, u64>(5)?,
+                ))
+            })
+            .context("Failed to query download parts")?;
+
+        let mut parts = Vec::new();
+        for part_result in part_iter {
+            parts.push(part_result.context("Failed to read download part")?);
+        }
+
+        download.parts = match resumable {
+            Some(true) => DownloadParts::Resumable(
+                parts
+                    .into_iter()
+                    .map(|(id, status, start_byte, end_byte, _, bytes_downloaded)| {
+                        ResumableDownloadPart {
+                            id,
+                            status,
+                            start_byte,
+                            end_byte,
+                            bytes_downloaded,
+                            current_speed: 0,
+                        }
+                    })
+                    .collect(),
+            ),
+            Some(false) => match parts.into_iter().next() {
+                Some((id, status, _, _, total_size, bytes_downloaded)) => {
+                    DownloadParts::NonResumable(NonResumableDownloadPart {
+                        id,
+                        status,
+                        total_size,
+                        bytes_downloaded,
+                        current_speed: 0,
+                    })
+                }
+                None => DownloadParts::None,
+            },
+            None => DownloadParts::None,
+        };
+        download.progress = DownloadPartsProgress::from(&download.parts);
+
+        Ok(())
+    }
+}
+
+/// Helper to convert a database row to a Download, parts are loaded separately
+fn row_to_download(row: &Row, config: &DownloadConfig) -> rusqlite::Result<Download> {
+    let headers: Option<String> = row.get(4)?;
+    let mut download = Download::new(
+        DownloadRequest {
+            url: row.get(1)?,
+            file_dir: PathBuf::from(row.get::<_, String>(2)?),
+            file_name: row.get::<_, Option<String>>(3)?.map(PathBuf::from),
+            referrer: row.get(5)?,
+            headers: headers.map(|headers| headers.lines().map(String::from).collect()),
+            priority: priority_from_str(&row.get::<_, String>(7)?),
+            checksum: row.get(11)?,
+            category: row.get(12)?,
+        },
+        config,
+    );
+
+    let date_added: String = row.get(9)?;
+    download.id = parse_uuid(row, 0)?;
+    download.status = status_from_str(&row.get::<_, String>(6)?);
+    download.date_added = DateTime::parse_from_rfc3339(&date_added)
+        .map(|dt| dt.with_timezone(&Utc))
+        .map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(9, rusqlite::types::Type::Text, Box::new(e))
+        })?;
+    download.active_time = Duration::milliseconds(row.get(10)?);
+    download.date_finished = row
+        .get::<_, Option<String>>(13)?
+        .map(|date| DateTime::parse_from_rfc3339(&date).map(|dt| dt.with_timezone(&Utc)))
+        .transpose()
+        .map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(13, rusqlite::types::Type::Text, Box::new(e))
+        })?;
+    if let Some(connections) = row.get::<_, Option<usize>>(14)? {
+        download.connections = Some(connections);
+        download.config.connections_per_server = connections;
+    }
+    download.own_speed_limiter.set_limit(row.get(15)?);
+    download.etag = row.get(16)?;
+    download.last_modified = row.get(17)?;
+
+    Ok(download)
+}
+
+fn parse_uuid(row: &Row, index: usize) -> rusqlite::Result<Uuid> {
+    let id: String = row.get(index)?;
+    Uuid::parse_str(&id).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, Box::new(e))
+    })
+}
+
+fn status_to_str(status: &DownloadStatus) -> &'static str {
+    match status {
+        DownloadStatus::Created => "created",
+        DownloadStatus::Queued => "queued",
+        DownloadStatus::Connecting => "connecting",
+        DownloadStatus::Retrying => "retrying",
+        DownloadStatus::Downloading => "downloading",
+        DownloadStatus::Paused => "paused",
+        DownloadStatus::Complete => "complete",
+        DownloadStatus::Failed => "failed",
+        DownloadStatus::Cancelled => "cancelled",
+    }
+}
+
+fn status_from_str(status: &str) -> DownloadStatus {
+    match status {
+        "queued" => DownloadStatus::Queued,
+        "connecting" => DownloadStatus::Connecting,
+        "retrying" => DownloadStatus::Retrying,
+        "downloading" => DownloadStatus::Downloading,
+        "paused" => DownloadStatus::Paused,
+        "complete" => DownloadStatus::Complete,
+        "failed" => DownloadStatus::Failed,
+        "cancelled" => DownloadStatus::Cancelled,
+        _ => DownloadStatus::Created,
+    }
+}
+
+fn priority_to_str(priority: &DownloadPriority) -> &'static str {
+    match priority {
+        DownloadPriority::Low => "low",
+        DownloadPriority::Normal => "normal",
+        DownloadPriority::High => "high",
+    }
+}
+
+fn priority_from_str(priority: &str) -> DownloadPriority {
+    match priority {
+        "low" => DownloadPriority::Low,
+        "high" => DownloadPriority::High,
+        _ => DownloadPriority::Normal,
+    }
+}
+
+/// Statistics about downloads in the database
+#[derive(Debug, Clone, Default)]
+pub struct DownloadStats {
+    pub total_downloads: u64,
+    pub active_downloads: u64,
+    pub total_downloaded_bytes: u64,
+    pub paused_downloads: u64,
+    /// finished downloads, including the ones purged from the list
+    pub succeeded: u64,
+    pub failed: u64,
+    pub cancelled: u64,
+    pub history_bytes: u64,
+    /// bytes per second of active time
+    pub average_speed: u64,
+    /// bytes of a download count on the day it finished
+    pub per_day: Vec<StatsBucket>,
+    pub per_host: Vec<StatsBucket>,
+}
+
+/// Finished downloads grouped by day or host
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatsBucket {
+    pub key: String,
+    pub bytes: u64,
+    pub succeeded: u64,
+    pub failed: u64,
+    pub cancelled: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn create_test_download() -> Download {
+        let mut download = Download::new(
+            DownloadRequest {
+                url: "https://example.com/files/test-file.zip".into(),
+                file_dir: "/downloads".into(),
+                file_name: None,
+                referrer: Some("https://example.com".into()),
+                headers: Some(vec!["Cookie: a=b".into(), "X-Test: 1".into()]),
+                priority: DownloadPriority::High,
+                checksum: Some("sha-256=abc".into()),
+                category: Some("Archives".into()),
+            },
+            &DownloadConfig::default(),
+        );
+        download.file = "/downloads/test-file.zip.nm".into();
+        download.parts = DownloadParts::Resumable(vec![
+            ResumableDownloadPart {
+                id: Uuid::new_v4(),
+                status: DownloadStatus::Complete,
+                start_byte: 0,
+                end_byte: 499999,
+                bytes_downloaded: 500000,
+                current_speed: 0,
+            },
+            ResumableDownloadPart {
+                id: Uuid::new_v4(),
+                status: DownloadStatus::Downloading,
+                start_byte: 500000,
+                end_byte: 999999,
+                bytes_downloaded: 1234,
+                current_speed: 0,
+            },
+        ]);
+        download
+    }
+
+    #[test]
+    fn test_database_operations() -> Result<()> {
+        let dir = tempdir()?;
+        let db_path = dir.path().join("test.db");
+        let config = DownloadConfig::default();
+
+        let mut db_manager = DatabaseManager::new(&db_path)?;
+        let mut download = create_test_download();
+        db_manager.save_downloads([(0, &download)])?;
+
+        // checkpointing again only moves the progress forward, options changed
+        // over RPC are saved with it
+        if let DownloadParts::Resumable(parts) = &mut download.parts {
+            parts[1].bytes_downloaded = 4321;
+        }
+        download.headers = Some(vec!["X-Test: 2".into()]);
+        download.connections = Some(4);
+        download.own_speed_limiter.set_limit(1024);
+        download.etag = Some("\"abc\"".into());
+        db_manager.save_downloads([(0, &download)])?;
+
+        // reopening runs the migrations again, which must be a no-op
+        drop(db_manager);
+        let db_manager = DatabaseManager::new(&db_path)?;
+
+        let mut all_downloads = db_manager.get_all_downloads(&config)?;
+        assert_eq!(all_downloads.len(), 1);
+        let retrieved = all_downloads.remove(0);
+        assert_eq!(retrieved.id, download.id);
+        assert_eq!(retrieved.url, download.url);
+        assert_eq!(retrieved.file, download.file);
+        assert_eq!(retrieved.headers, download.headers);
+        assert_eq!(retrieved.priority, DownloadPriority::High);
+        assert_eq!(retrieved.checksum, download.checksum);
+        assert_eq!(retrieved.category, download.category);
+        assert_eq!(retrieved.connections, Some(4));
+        assert_eq!(retrieved.config.connections_per_server, 4);
+        assert_eq!(retrieved.own_speed_limiter.limit(), 1024);
+        assert_eq!(retrieved.etag, download.etag);
+        assert_eq!(retrieved.last_modified, None);
+        assert_eq!(retrieved.get_status(), DownloadStatus::Downloading);
+        assert_eq!(retrieved.get_bytes_downloaded(), 504321);
+        assert!(matches!(
+            retrieved.progress,
+            DownloadPartsProgress::Resumable(ref parts) if parts.len() == 2
+        ));
+
+        let stats = db_manager.get_download_stats(30)?;
+        assert_eq!(stats.total_downloads, 1);
+        assert_eq!(stats.active_downloads, 1);
+        assert_eq!(stats.total_downloaded_bytes, 504321);
+
+        db_manager.delete_download(&download.id)?;
+        assert!(db_manager.get_all_downloads(&config)?.is_empty());
+        let parts: u64 =
+            db_manager
+                .conn
+                .query_row("SELECT COUNT(*) FROM download_parts", [], |row| row.get(0))?;
+        assert_eq!(parts, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn history_outlives_purged_downloads() -> Result<()> {
+        let dir = tempdir()?;
+        let mut db_manager = DatabaseManager::new(dir.path().join("test.db"))?;
+
+        let mut download = create_test_download();
+        if let DownloadParts::Resumable(parts) = &mut download.parts {
+            for part in parts.iter_mut() {
+                part.status = DownloadStatus::Complete;
+                part.bytes_downloaded = part.get_total_size();
+            }
+        }
+        download.active_time = Duration::seconds(10);
+        download.date_finished = Some(Utc::now());
+        db_manager.save_downloads([(0, &download)])?;
+        db_manager.delete_download(&download.id)?;
+
+        let mut cancelled = create_test_download();
+        if let DownloadParts::Resumable(parts) = &mut cancelled.parts {
+            // before anything was downloaded
+            for part in parts.iter_mut() {
+                part.status = DownloadStatus::Cancelled;
+                part.bytes_downloaded = 0;
+            }
+        }
+        cancelled.date_finished = Some(Utc::now());
+        db_manager.save_downloads([(1, &cancelled)])?;
+
+        let stats = db_manager.get_download_stats(30)?;
+        assert_eq!(stats.total_downloads, 1);
+        assert_eq!((stats.succeeded, stats.failed, stats.cancelled), (1, 0, 1));
+        assert_eq!(stats.history_bytes, 1_000_000);
+        assert_eq!(stats.average_speed, 100_000);
+        assert_eq!(stats.per_day.len(), 1);
+        assert_eq!(
+            stats.per_host,
+            [StatsBucket {
+                key: "example.com".into(),
+                bytes: 1_000_000,
+                succeeded: 1,
+                failed: 0,
+                cancelled: 1,
+            }]
+        );
+
+        Ok(())
+    }
+}
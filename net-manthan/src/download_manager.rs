@@ -1,39 +1,171 @@
-use download_engine::{Download, download_config::DownloadConfig};
+use crate::{
+    download_db_manager::{DatabaseManager, DownloadStats, StatsBucket, connect_to_database},
+    hooks::{HookEvent, Hooks},
+    listing::list_downloads,
+    net_manthan_config::{AutoResume, DuplicatePolicy, NetManthanConfig},
+    scheduler::{Schedule, ScheduleState, due_starts, schedule_state},
+};
+use chrono::{DateTime, Local, Utc};
+use download_engine::{
+    Download, DownloadParts, DownloadPartsProgress,
+    category::find_category,
+    download::{FileInfo, RemoteFile},
+    download_config::DownloadConfig,
+    errors::DownloadError,
+    filename_policy::numbered_filename,
+    speed_limiter::SpeedLimiter,
+    types::{DownloadRequest, DownloadStatus},
+    utils::normalize_url,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::{Arc, atomic::Ordering},
+};
 use tokio::{
-    sync::mpsc,
-    time::{Duration, interval},
+    sync::{broadcast, mpsc},
+    time::{Duration, Instant, interval, interval_at},
 };
+use tracing::{error, info, warn};
 use utils::{
-    conversion::{convert_to_download_proto, convert_to_download_req},
-    rpc::server::{ManagerCommand, RpcServerHandle as DownloadManagerHandle},
+    conversion::{
+        convert_from_download_priority_proto, convert_to_download_proto, convert_to_download_req,
+        convert_to_error_proto,
+    },
+    rpc::server::{
+        EVENT_CAPACITY, ManagerCommand, RpcServerHandle as DownloadManagerHandle, hello_reply,
+    },
     rpc_types::{
-        DownloadList, Error as ErrorProto, GetDownload, RpcResponse, rpc_request::Request,
+        Authenticated, ChangeDownloadOptions, DownloadEvent as DownloadEventProto,
+        DownloadEventType, DownloadIds, DownloadList, DuplicatePolicy as DuplicatePolicyProto,
+        Error as ErrorProto, ErrorCode, GetDownload, GlobalOptions, QueueMove, RpcResponse,
+        Schedules, Stats, StatsBucket as StatsBucketProto, Unsubscribed, rpc_request::Request,
         rpc_response::Response,
     },
 };
+use uuid::Uuid;
 
 pub struct DownloadManager {
     all_downloads: Vec<Download>,
+    /// number of downloads allowed to run at the same time, the rest wait as Queued
+    max_concurrent_downloads: usize,
+    /// connections across all downloads, 0 means unlimited
+    max_overall_connections: usize,
+    /// connections all downloads from one host may open together, 0 means unlimited
+    max_connections_per_host: usize,
+    /// max_connections_per_host for specific hosts
+    host_connection_limits: HashMap<String, usize>,
+    /// overall speed limit in bytes per second set by the user, 0 means unlimited
+    max_overall_download_limit: u64,
+    /// shared by all downloads, enforces the overall and scheduled speed limits
+    speed_limiter: Arc<SpeedLimiter>,
+    /// time based rules, see `Schedule`
+    schedules: Vec<Schedule>,
+    /// what the schedules wanted at the last tick, changes are logged and applied
+    schedule_state: ScheduleState,
+    /// what AddDownload does with duplicates if the request doesn't say
+    duplicate_policy: DuplicatePolicy,
+    /// finished downloads are purged after this many days, 0 keeps them forever
+    history_days: u64,
+    /// finished downloads kept in the list, the oldest are purged first, 0 for no limit
+    max_history: usize,
+    /// commands run on download events
+    hooks: Hooks,
+    /// status of each download when events were last looked for, see `report_events`
+    reported_status: HashMap<Uuid, DownloadStatus>,
+    /// where DownloadEvents are published for RPC subscribers
+    event_sender: broadcast::Sender<DownloadEventProto>,
+    /// config every new download starts with
+    download_config: DownloadConfig,
+    /// where downloads are persisted, None if the database couldn't be opened
+    db: Option<DatabaseManager>,
+    /// status of each download at its last save, a change saves it right away
+    saved_status: HashMap<Uuid, DownloadStatus>,
+    /// where the requests `start_download` sends in the background report back
+    answer_sender: mpsc::UnboundedSender<(Uuid, ServerAnswer)>,
+}
+
+/// What the server said about the file of a download, asked in the background
+/// so a slow server doesn't hold up the manager
+enum ServerAnswer {
+    /// about a new download, see `Download::fetch_info`
+    Info(Result<FileInfo, DownloadError>),
+    /// about a restored download, see `Download::probe`
+    Probe(Result<RemoteFile, DownloadError>),
+}
+
+/// how often the progress of running downloads is written to the database
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// What a control request does to each of its downloads
+#[derive(Debug, Clone, Copy)]
+enum Control {
+    Pause,
+    Resume,
+    Cancel,
+    Remove { delete_file: bool },
+    Retry,
 }
 
 impl DownloadManager {
-    pub fn new() -> DownloadManagerHandle {
+    pub fn start(config: &NetManthanConfig) -> DownloadManagerHandle {
         let (sender, receiver) = mpsc::channel(10);
+        let (mut manager, answer_receiver) = Self::new(config);
         let handle = DownloadManagerHandle {
             command_sender: sender,
+            event_sender: manager.event_sender.clone(),
         };
 
-        // Create and start the manager in its own thread
+        if let Some(database_file) = &config.database_file {
+            // only a daemon picks up where it left off, a one-off run sticks to its urls
+            let auto_resume = match config.daemon {
+                true => &config.auto_resume,
+                false => &AutoResume::None,
+            };
+            manager.load_database(PathBuf::from(database_file), auto_resume);
+        }
+        tokio::spawn(manager.run(receiver, answer_receiver));
+
+        handle
+    }
+
+    /// A manager with the options of `config`, without downloads or a database,
+    /// and the receiving end of its requests to servers
+    fn new(config: &NetManthanConfig) -> (Self, mpsc::UnboundedReceiver<(Uuid, ServerAnswer)>) {
+        let (event_sender, _) = broadcast::channel(EVENT_CAPACITY);
+        let (answer_sender, answer_receiver) = mpsc::unbounded_channel();
         let manager = Self {
             all_downloads: Vec::new(),
+            max_concurrent_downloads: config.max_concurrent_downloads,
+            max_overall_connections: config.max_overall_connections,
+            max_connections_per_host: config.max_connections_per_host,
+            host_connection_limits: config.host_connection_limits.clone(),
+            max_overall_download_limit: config.max_overall_download_limit,
+            speed_limiter: Arc::new(SpeedLimiter::new(config.max_overall_download_limit)),
+            schedules: config.schedules.clone(),
+            schedule_state: ScheduleState::default(),
+            duplicate_policy: config.duplicate_policy.clone(),
+            history_days: config.history_days,
+            max_history: config.max_history,
+            hooks: config.hooks.clone(),
+            reported_status: HashMap::new(),
+            event_sender,
+            download_config: config.download_config.clone(),
+            db: None,
+            saved_status: HashMap::new(),
+            answer_sender,
         };
-        tokio::spawn(manager.run(receiver));
-
-        handle
+        (manager, answer_receiver)
     }
 
-    async fn run(mut self, mut receiver: mpsc::Receiver<ManagerCommand>) {
+    async fn run(
+        mut self,
+        mut receiver: mpsc::Receiver<ManagerCommand>,
+        mut answer_receiver: mpsc::UnboundedReceiver<(Uuid, ServerAnswer)>,
+    ) {
         let mut interval = interval(Duration::from_millis(250));
+        let mut checkpoint_interval =
+            interval_at(Instant::now() + CHECKPOINT_INTERVAL, CHECKPOINT_INTERVAL);
         loop {
             // Biased selection ensures the interval is checked first
             tokio::select! {
@@ -42,9 +174,33 @@ impl DownloadManager {
                 _ = interval.tick() => {
                     for download in self.all_downloads.iter_mut() {
                         download.update_progress().await;
+                        // before the events, hooks get the final path and the row saves it
+                        if let Err(e) = download.finish_file().await {
+                            error!("Failed to rename the file of download {}: {}", download.id, e);
+                        }
+                    }
+                    self.apply_schedules().await;
+                    // completed, failed or paused downloads free their slot here
+                    self.start_queued_downloads().await;
+                    self.balance_connections().await;
+                    self.report_events();
+                    self.save_downloads(|download, saved_status| {
+                        saved_status != Some(&download.get_status())
+                    });
+                }
+
+                Some((id, answer)) = answer_receiver.recv() => {
+                    match answer {
+                        ServerAnswer::Info(result) => self.finish_info(id, result).await,
+                        ServerAnswer::Probe(result) => self.finish_probe(id, result).await,
                     }
                 }
 
+                _ = checkpoint_interval.tick() => {
+                    self.save_downloads(|download, _| is_active(&download.get_status()));
+                    self.apply_retention();
+                }
+
                 // Process commands only if interval is not ready
                 cmd = receiver.recv() => {
                     if let Some(cmd) = cmd {
@@ -59,25 +215,1107 @@ impl DownloadManager {
         }
     }
 
+    /// Open the database and restore the downloads saved in it, the ones
+    /// `auto_resume` picks go back into the queue
+    fn load_database(&mut self, database_file: PathBuf, auto_resume: &AutoResume) {
+        let db = match connect_to_database(&database_file) {
+            Ok(db) => db,
+            Err(e) => {
+                error!(
+                    "Failed to open database {:?}, downloads won't be saved: {:#}",
+                    database_file, e
+                );
+                return;
+            }
+        };
+
+        match db.get_all_downloads(&self.download_config) {
+            Ok(downloads) => {
+                info!(
+                    "Restored {} downloads from {:?}",
+                    downloads.len(),
+                    database_file
+                );
+                for mut download in downloads {
+                    restore_interrupted(&mut download, auto_resume);
+                    download.speed_limiter = self.speed_limiter.clone();
+                    // category options aren't saved, they come from the current config
+                    if let Some(category) = download.category.as_ref().and_then(|name| {
+                        self.download_config
+                            .categories
+                            .iter()
+                            .find(|category| &category.name == name)
+                    }) {
+                        download.apply_category(category);
+                    }
+                    self.saved_status.insert(download.id, download.get_status());
+                    self.reported_status
+                        .insert(download.id, download.get_status());
+                    self.all_downloads.push(download);
+                }
+            }
+            Err(e) => error!("Failed to load downloads from {:?}: {:#}", database_file, e),
+        }
+
+        // rules given in the config win over the ones last set over RPC
+        if self.schedules.is_empty() {
+            match db.get_schedules() {
+                Ok(rules) => {
+                    self.schedules = rules
+                        .iter()
+                        .filter_map(|rule| match rule.parse() {
+                            Ok(schedule) => Some(schedule),
+                            Err(e) => {
+                                warn!("Ignoring saved schedule: {}", e);
+                                None
+                            }
+                        })
+                        .collect();
+                }
+                Err(e) => error!("Failed to load schedules: {:#}", e),
+            }
+        }
+        self.db = Some(db);
+    }
+
+    /// Fire the events of the downloads whose status changed since the last call
+    /// and publish the progress of running ones, hook commands run in the
+    /// background so this never waits for them
+    fn report_events(&mut self) {
+        for download in self.all_downloads.iter() {
+            let status = download.get_status();
+            let previous = self.reported_status.insert(download.id, status.clone());
+            for event in HookEvent::from_status_change(previous.as_ref(), &status) {
+                self.hooks.run(event, download);
+            }
+            if previous.is_some_and(|previous| previous != status) {
+                self.publish(DownloadEventType::StateChanged, download);
+            } else if is_active(&status) {
+                self.publish(DownloadEventType::Progress, download);
+            }
+        }
+    }
+
+    /// Send an event about `download` to the RPC subscribers
+    fn publish(&self, event_type: DownloadEventType, download: &Download) {
+        // converting the download isn't worth it without anyone listening
+        if self.event_sender.receiver_count() == 0 {
+            return;
+        }
+        let _ = self.event_sender.send(DownloadEventProto {
+            r#type: event_type as i32,
+            id: download.id.to_string(),
+            download: Some(convert_to_download_proto(download)),
+        });
+    }
+
+    /// Save the downloads `should_save` picks, it gets the status of the download
+    /// at its last save (None if it was never saved)
+    fn save_downloads(&mut self, should_save: impl Fn(&Download, Option<&DownloadStatus>) -> bool) {
+        let Some(db) = self.db.as_mut() else {
+            return;
+        };
+
+        let downloads: Vec<(usize, &Download)> = self
+            .all_downloads
+            .iter()
+            .enumerate()
+            .filter(|(_, download)| should_save(download, self.saved_status.get(&download.id)))
+            .collect();
+        if downloads.is_empty() {
+            return;
+        }
+
+        let saved: Vec<(Uuid, DownloadStatus)> = downloads
+            .iter()
+            .map(|(_, download)| (download.id, download.get_status()))
+            .collect();
+        match db.save_downloads(downloads) {
+            Ok(_) => self.saved_status.extend(saved),
+            Err(e) => error!("Failed to save downloads: {:#}", e),
+        }
+    }
+
+    /// Fire due start rules and apply run windows and speed limits,
+    /// every change is logged
+    async fn apply_schedules(&mut self) {
+        let now = Local::now();
+
+        let due = due_starts(&mut self.schedules, &now);
+        if !due.is_empty() {
+            // start rules only fire once, keep the saved rules in sync
+            self.save_schedules();
+        }
+        for id in due {
+            match self.start_download_now(&id).await {
+                Ok(_) => info!("Schedule: started download {}", id),
+                Err(e) => warn!("Schedule: failed to start download {}: {}", id, e),
+            }
+        }
+
+        let state = schedule_state(&self.schedules, &now);
+        if state.queue_open != self.schedule_state.queue_open {
+            if state.queue_open {
+                info!("Schedule: run window opened, starting queued downloads");
+            } else {
+                info!("Schedule: run window closed, queueing running downloads");
+                self.queue_running_downloads().await;
+            }
+        }
+        if state.speed_limit != self.schedule_state.speed_limit {
+            info!(
+                "Schedule: speed limit changed from {} to {} bytes/s (0 is unlimited)",
+                self.schedule_state.speed_limit, state.speed_limit
+            );
+        }
+        self.schedule_state = state;
+        self.update_speed_limit();
+    }
+
+    /// Put the running downloads back in the queue rather than pausing them, so
+    /// they start again when a window opens, even after a restart
+    ///
+    /// downloads that can't resume would lose their bytes, they run on until
+    /// they finish
+    async fn queue_running_downloads(&mut self) {
+        for download in self.all_downloads.iter_mut() {
+            if !is_active(&download.get_status()) {
+                continue;
+            }
+            if matches!(download.progress, DownloadPartsProgress::NonResumable(_)) {
+                info!(
+                    "Schedule: download {} can't resume, letting it finish",
+                    download.id
+                );
+                continue;
+            }
+            download.pause().await;
+            download.requeue().await;
+        }
+    }
+
+    /// The stricter of the user set and the scheduled speed limit
+    fn update_speed_limit(&self) {
+        let limit = [
+            self.max_overall_download_limit,
+            self.schedule_state.speed_limit,
+        ]
+        .into_iter()
+        .filter(|limit| *limit != 0)
+        .min()
+        .unwrap_or(0);
+        self.speed_limiter.set_limit(limit);
+    }
+
+    fn save_schedules(&mut self) {
+        let rules: Vec<String> = self.schedules.iter().map(Schedule::to_string).collect();
+        if let Some(db) = self.db.as_mut()
+            && let Err(e) = db.save_schedules(&rules)
+        {
+            error!("Failed to save schedules: {:#}", e);
+        }
+    }
+
+    /// Number of downloads currently holding a slot
+    fn active_downloads(&self) -> usize {
+        self.all_downloads
+            .iter()
+            .filter(|d| is_active(&d.get_status()))
+            .count()
+    }
+
+    /// Start Queued downloads until all slots are taken, see `downloads_to_start`
+    async fn start_queued_downloads(&mut self) {
+        if !self.schedule_state.queue_open {
+            return;
+        }
+        for index in self.downloads_to_start() {
+            let _ = self.start_download(index).await;
+        }
+    }
+
+    /// Indices of the Queued downloads that fit in the free slots, higher priority
+    /// first and in queue order within the same priority. A busy host doesn't hold
+    /// up queued downloads from other hosts
+    ///
+    /// a host with a free connection gets a download, `start_download` then only
+    /// gives it what is left of the host's budget
+    fn downloads_to_start(&self) -> Vec<usize> {
+        let mut free_slots = self
+            .max_concurrent_downloads
+            .saturating_sub(self.active_downloads());
+        if free_slots == 0 {
+            return Vec::new();
+        }
+
+        let mut queued: Vec<usize> = (0..self.all_downloads.len())
+            .filter(|i| matches!(self.all_downloads[*i].get_status(), DownloadStatus::Queued))
+            .collect();
+        // stable sort keeps the queue order for equal priorities
+        queued.sort_by_key(|i| std::cmp::Reverse(self.all_downloads[*i].priority));
+
+        // every running download needs at least one connection of its host's budget
+        let mut running: HashMap<Option<String>, usize> = HashMap::new();
+        for download in self.all_downloads.iter() {
+            if is_active(&download.get_status()) {
+                *running.entry(download.host()).or_default() += 1;
+            }
+        }
+
+        let mut to_start = Vec::new();
+        for index in queued {
+            if free_slots == 0 {
+                break;
+            }
+            let host = self.all_downloads[index].host();
+            let limit = self.host_connection_limit(host.as_deref());
+            let running = running.entry(host).or_default();
+            if *running >= limit {
+                continue;
+            }
+            *running += 1;
+            free_slots -= 1;
+            to_start.push(index);
+        }
+        to_start
+    }
+
+    /// Start the download at `index`, it is marked Failed if it can't start
+    ///
+    /// a new download asks the server about its file and a download restored
+    /// from the database checks it with the server, both in the background. The
+    /// download holds its slot as Connecting meanwhile and starts once
+    /// `finish_info` or `finish_probe` gets the answer
+    ///
+    /// it starts with the connections left in its host's and the overall budget,
+    /// `balance_connections` gives it a fair share later
+    async fn start_download(&mut self, index: usize) -> Result<(), DownloadError> {
+        let free_connections = self.free_connections(index);
+        let download = &mut self.all_downloads[index];
+        if matches!(download.progress, DownloadPartsProgress::None) {
+            info!("Asking the server about download {}", download.id);
+            download.mark_unfinished(DownloadStatus::Connecting).await;
+            let (id, info) = (download.id, download.fetch_info());
+            let sender = self.answer_sender.clone();
+            tokio::spawn(async move {
+                let _ = sender.send((id, ServerAnswer::Info(info.await)));
+            });
+            return Ok(());
+        }
+        if download.restored {
+            info!("Checking restored download {} with the server", download.id);
+            download.mark_unfinished(DownloadStatus::Connecting).await;
+            let (id, probe) = (download.id, download.probe());
+            let sender = self.answer_sender.clone();
+            tokio::spawn(async move {
+                let _ = sender.send((id, ServerAnswer::Probe(probe.await)));
+            });
+            return Ok(());
+        }
+
+        info!("Starting queued download {}", download.id);
+        download
+            .connection_limit
+            .store(free_connections, Ordering::Relaxed);
+        if let Err(e) = download.start().await {
+            error!("Failed to start download {}: {}", download.id, e);
+            download.mark_unfinished(DownloadStatus::Failed).await;
+            return Err(e);
+        }
+        download.update_progress().await;
+        Ok(())
+    }
+
+    /// Index of the download `id` if it still waits for the server, it may have
+    /// been removed, paused or cancelled while the server was asked
+    fn waiting_for_server(&self, id: Uuid) -> Option<usize> {
+        self.all_downloads
+            .iter()
+            .position(|d| d.id == id && d.get_status() == DownloadStatus::Connecting)
+    }
+
+    /// Start a new download with what the server said about its file
+    async fn finish_info(&mut self, id: Uuid, result: Result<FileInfo, DownloadError>) {
+        let Some(index) = self
+            .waiting_for_server(id)
+            .filter(|index| matches!(self.all_downloads[*index].parts, DownloadParts::None))
+        else {
+            return;
+        };
+        let download = &mut self.all_downloads[index];
+        let result = match result {
+            Ok(file) => download.apply_info(file).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => {
+                let _ = self.start_download(index).await;
+            }
+            Err(e) => {
+                error!("Failed to start download {}: {}", id, e);
+                download.mark_unfinished(DownloadStatus::Failed).await;
+            }
+        }
+    }
+
+    /// Start a restored download with what the server said about its file
+    async fn finish_probe(&mut self, id: Uuid, result: Result<RemoteFile, DownloadError>) {
+        let Some(index) = self
+            .waiting_for_server(id)
+            .filter(|index| self.all_downloads[*index].restored)
+        else {
+            return;
+        };
+        match result {
+            Ok(file) => {
+                self.all_downloads[index].apply_probe(file);
+                let _ = self.start_download(index).await;
+            }
+            Err(e) => {
+                error!("Failed to check download {} with the server: {}", id, e);
+                self.all_downloads[index]
+                    .mark_unfinished(DownloadStatus::Failed)
+                    .await;
+            }
+        }
+    }
+
+    /// Split max_overall_connections and the per host budgets between the
+    /// running downloads, see `split_connections`
+    async fn balance_connections(&mut self) {
+        let mut active: Vec<usize> = (0..self.all_downloads.len())
+            .filter(|i| is_active(&self.all_downloads[*i].get_status()))
+            .collect();
+        active.sort_by_key(|i| std::cmp::Reverse(self.all_downloads[*i].priority));
+
+        let wanted: Vec<(Option<String>, usize)> = active
+            .iter()
+            .map(|i| {
+                let download = &self.all_downloads[*i];
+                (download.host(), download.config.connections_per_server)
+            })
+            .collect();
+        let overall_limit = match self.max_overall_connections {
+            0 => usize::MAX,
+            max => max,
+        };
+        let connections = split_connections(&wanted, overall_limit, |host| {
+            self.host_connection_limit(host)
+        });
+
+        for (index, connections) in active.into_iter().zip(connections) {
+            self.all_downloads[index]
+                .limit_connections(connections)
+                .await;
+        }
+    }
+
+    /// Connections the download at `index` may open without going over its host's
+    /// or the overall budget, at least one. The other running downloads use up to
+    /// their current limit, ones still waiting for the server will need one
+    fn free_connections(&self, index: usize) -> usize {
+        let download = &self.all_downloads[index];
+        let host = download.host();
+        let (mut host_used, mut overall_used) = (0, 0);
+        for (i, other) in self.all_downloads.iter().enumerate() {
+            let status = other.get_status();
+            if i == index || !is_active(&status) {
+                continue;
+            }
+            let used = match status {
+                DownloadStatus::Connecting => 1,
+                _ => other
+                    .connection_limit
+                    .load(Ordering::Relaxed)
+                    .min(other.config.connections_per_server),
+            };
+            overall_used += used;
+            if other.host() == host {
+                host_used += used;
+            }
+        }
+
+        let overall_limit = match self.max_overall_connections {
+            0 => usize::MAX,
+            max => max,
+        };
+        download
+            .config
+            .connections_per_server
+            .min(
+                self.host_connection_limit(host.as_deref())
+                    .saturating_sub(host_used),
+            )
+            .min(overall_limit.saturating_sub(overall_used))
+            .max(1)
+    }
+
+    /// Connections all downloads from `host` may open together, usize::MAX if unlimited
+    fn host_connection_limit(&self, host: Option<&str>) -> usize {
+        let limit = host
+            .and_then(|host| self.host_connection_limits.get(host))
+            .unwrap_or(&self.max_connections_per_host);
+        match limit {
+            0 => usize::MAX,
+            limit => *limit,
+        }
+    }
+
+    /// Queue a new download, unless it duplicates one in the list and `policy`
+    /// says to do something else with it
+    async fn add_download(
+        &mut self,
+        mut request: DownloadRequest,
+        policy: &DuplicatePolicy,
+    ) -> Response {
+        if let Some(name) = &request.category
+            && !self
+                .download_config
+                .categories
+                .iter()
+                .any(|category| &category.name == name)
+        {
+            return Response::Error(
+                ErrorProto::new(
+                    ErrorCode::InvalidArgument,
+                    format!("unknown category {}", name),
+                )
+                .with_detail("category", name),
+            );
+        }
+        self.resolve_directory(&mut request);
+        if let Some(index) = self.find_duplicate(&request) {
+            let existing = &mut self.all_downloads[index];
+            info!(
+                "{} duplicates download {}, policy {:?}",
+                request.url, existing.id, policy
+            );
+            match policy {
+                DuplicatePolicy::Reject => {
+                    return Response::Error(
+                        ErrorProto::new(
+                            ErrorCode::AlreadyExists,
+                            format!("duplicates download {}", existing.id),
+                        )
+                        .with_detail("existing_id", existing.id.to_string()),
+                    );
+                }
+                DuplicatePolicy::ReturnExisting => {
+                    return Response::DownloadCreated(GetDownload {
+                        id: existing.id.to_string(),
+                    });
+                }
+                DuplicatePolicy::Restart => {
+                    existing.restart().await;
+                    let id = existing.id;
+                    self.save_downloads(|download, _| download.id == id);
+                    self.start_queued_downloads().await;
+                    return Response::DownloadCreated(GetDownload { id: id.to_string() });
+                }
+                DuplicatePolicy::Rename => self.rename_duplicate(&mut request),
+            }
+        }
+
+        let mut download = Download::new(request, &self.download_config);
+        download.status = DownloadStatus::Queued;
+        download.speed_limiter = self.speed_limiter.clone();
+        let id = download.id.to_string();
+
+        self.reported_status
+            .insert(download.id, download.get_status());
+        self.publish(DownloadEventType::Added, &download);
+        self.all_downloads.push(download);
+        self.save_downloads(|_, saved_status| saved_status.is_none());
+        self.start_queued_downloads().await;
+        Response::DownloadCreated(GetDownload { id })
+    }
+
+    /// Fill in the directory of a request that left it to the config, so its
+    /// target can be compared with the other downloads. Without a file name the
+    /// category may depend on the server's answer and it stays empty
+    fn resolve_directory(&self, request: &mut DownloadRequest) {
+        if !request.file_dir.as_os_str().is_empty() {
+            return;
+        }
+        let Some(file_name) = &request.file_name else {
+            return;
+        };
+        let categories = &self.download_config.categories;
+        let category = match &request.category {
+            Some(name) => categories.iter().find(|category| &category.name == name),
+            None => find_category(categories, &request.url, &file_name.to_string_lossy(), None),
+        };
+        request.file_dir = self.download_config.directory_for(category);
+        request.category = category.map(|category| category.name.clone());
+    }
+
+    /// Index of the download `request` duplicates: the same url (ignoring the
+    /// fragment), the same target file or the same checksum. Finished downloads
+    /// don't count, adding a url again after it completed, failed or was
+    /// cancelled means downloading it again
+    fn find_duplicate(&self, request: &DownloadRequest) -> Option<usize> {
+        let url = normalize_url(&request.url);
+        // without a name the target is only known once the server answers, a
+        // clash then gets a numbered name when the file is created
+        let target = request
+            .file_name
+            .as_ref()
+            .map(|name| request.file_dir.join(name));
+
+        self.all_downloads.iter().position(|download| {
+            !is_finished(&download.get_status())
+                && (normalize_url(&download.url) == url
+                    || (target.is_some() && download.target_file() == target)
+                    || (request.checksum.is_some() && download.checksum == request.checksum))
+        })
+    }
+
+    /// Give a duplicate request a numbered file name no other download targets,
+    /// files already on disk are skipped when the download creates its file
+    fn rename_duplicate(&self, request: &mut DownloadRequest) {
+        let Some(name) = request
+            .file_name
+            .as_ref()
+            .map(|name| name.to_string_lossy().into_owned())
+        else {
+            return;
+        };
+        let targets: HashSet<PathBuf> = self
+            .all_downloads
+            .iter()
+            .filter_map(Download::target_file)
+            .collect();
+        let name = (1..)
+            .map(|n| numbered_filename(&name, n))
+            .find(|name| !targets.contains(&request.file_dir.join(name)))
+            .expect("ran out of numbered names");
+        request.file_name = Some(PathBuf::from(name));
+    }
+
+    /// Move a Queued download within the queue, relative to the other Queued downloads
+    fn move_download(&mut self, id: &str, queue_move: QueueMove) -> Result<(), ErrorProto> {
+        let index = self
+            .all_downloads
+            .iter()
+            .position(|d| d.id.to_string() == id)
+            .ok_or_else(|| ErrorProto::not_found(id))?;
+        if !matches!(
+            self.all_downloads[index].get_status(),
+            DownloadStatus::Queued
+        ) {
+            return Err(not_queued(id));
+        }
+
+        let queued: Vec<usize> = (0..self.all_downloads.len())
+            .filter(|i| matches!(self.all_downloads[*i].get_status(), DownloadStatus::Queued))
+            .collect();
+        let position = queued
+            .iter()
+            .position(|i| *i == index)
+            .expect("download is queued");
+        let new_position = match queue_move {
+            QueueMove::MoveTop => 0,
+            QueueMove::MoveBottom => queued.len() - 1,
+            QueueMove::MoveUp => position.saturating_sub(1),
+            QueueMove::MoveDown => (position + 1).min(queued.len() - 1),
+            QueueMove::MoveUnspecified => {
+                return Err(ErrorProto::new(
+                    ErrorCode::InvalidArgument,
+                    "no queue move given",
+                ));
+            }
+        };
+        if new_position == position {
+            return Ok(());
+        }
+
+        // moving down, removing shifts the target one left so inserting at its old
+        // index lands right after it, moving up it lands right before it
+        let download = self.all_downloads.remove(index);
+        self.all_downloads.insert(queued[new_position], download);
+        Ok(())
+    }
+
+    /// Start a Queued download right away, even if all slots are taken
+    async fn start_download_now(&mut self, id: &str) -> Result<(), ErrorProto> {
+        let index = self
+            .all_downloads
+            .iter()
+            .position(|d| d.id.to_string() == id)
+            .ok_or_else(|| ErrorProto::not_found(id))?;
+        if !matches!(
+            self.all_downloads[index].get_status(),
+            DownloadStatus::Queued
+        ) {
+            return Err(not_queued(id));
+        }
+        self.start_download(index)
+            .await
+            .map_err(|e| convert_to_error_proto(&e).with_detail("id", id))
+    }
+
+    /// Change the options of one download, like aria2's changeOption. The file
+    /// moves first since that can fail, then nothing else is changed
+    async fn change_download_options(
+        &mut self,
+        options: ChangeDownloadOptions,
+    ) -> Result<(), ErrorProto> {
+        let id = options.id.as_str();
+        let download = self
+            .all_downloads
+            .iter_mut()
+            .find(|d| d.id.to_string() == id)
+            .ok_or_else(|| ErrorProto::not_found(id))?;
+
+        if options.connections == Some(0) {
+            return Err(invalid_option(
+                "connections",
+                "needs at least one connection",
+            ));
+        }
+        if options.dir.as_ref().is_some_and(|dir| dir.is_empty()) {
+            return Err(invalid_option("dir", "the directory can't be empty"));
+        }
+        let file_name = match &options.file_name {
+            Some(name) => {
+                let sanitized = download.config.filename_policy.sanitize(name);
+                if sanitized.is_empty() {
+                    return Err(invalid_option("file_name", "not a usable file name"));
+                }
+                Some(sanitized)
+            }
+            None => None,
+        };
+
+        if options.dir.is_some() || file_name.is_some() {
+            let status = download.get_status();
+            if matches!(status, DownloadStatus::Complete) {
+                return Err(ErrorProto::new(
+                    ErrorCode::FailedPrecondition,
+                    format!("download {} is complete, its file can't move", id),
+                )
+                .with_detail("id", id));
+            }
+            if matches!(download.parts, DownloadParts::NonResumable(_)) && is_active(&status) {
+                return Err(ErrorProto::new(
+                    ErrorCode::FailedPrecondition,
+                    format!("download {} can't resume, pause it before it moves", id),
+                )
+                .with_detail("id", id));
+            }
+            download
+                .relocate(options.dir.as_ref().map(PathBuf::from), file_name)
+                .await
+                .map_err(|e| convert_to_error_proto(&e).with_detail("id", id))?;
+        }
+
+        if let Some(connections) = options.connections {
+            download.set_connections(connections as usize).await;
+        }
+        if let Some(limit) = options.speed_limit {
+            info!("Download {} speed limit changed to {}", id, limit);
+            download.own_speed_limiter.set_limit(limit);
+        }
+        if let Some(headers) = &options.headers {
+            download.headers = (!headers.headers.is_empty()).then(|| headers.headers.clone());
+        }
+        if options.priority.is_some() {
+            download.priority = convert_from_download_priority_proto(&options.priority());
+        }
+        Ok(())
+    }
+
+    /// Abort all tasks of a download and mark it Cancelled, the download stays listed
+    async fn cancel_download(&mut self, id: &str) -> Result<(), DownloadError> {
+        match self
+            .all_downloads
+            .iter_mut()
+            .find(|d| d.id.to_string() == id)
+        {
+            Some(download) => {
+                download.cancel().await;
+                Ok(())
+            }
+            None => Err(DownloadError::general(format!("download {} not found", id))),
+        }
+    }
+
+    /// Cancel a download and drop it from the list, deleting its file if asked to
+    async fn remove_download(&mut self, id: &str, delete_file: bool) -> Result<(), DownloadError> {
+        let index = self
+            .all_downloads
+            .iter()
+            .position(|d| d.id.to_string() == id)
+            .ok_or_else(|| DownloadError::general(format!("download {} not found", id)))?;
+        let mut download = self.forget_download(index);
+        download.remove(delete_file).await
+    }
+
+    /// Ids of the downloads a control request picks, every download if `all` is set
+    fn select_downloads(&self, ids: &[String], all: bool) -> Result<Vec<Uuid>, ErrorProto> {
+        if all {
+            return Ok(self.all_downloads.iter().map(|d| d.id).collect());
+        }
+        ids.iter()
+            .map(|id| {
+                self.all_downloads
+                    .iter()
+                    .find(|d| &d.id.to_string() == id)
+                    .map(|d| d.id)
+                    .ok_or_else(|| ErrorProto::not_found(id))
+            })
+            .collect()
+    }
+
+    /// Apply `control` to the selected downloads it makes sense for, returns the
+    /// ids of the downloads it changed
+    async fn control_downloads(
+        &mut self,
+        control: Control,
+        ids: &[String],
+        all: bool,
+    ) -> Result<Vec<String>, ErrorProto> {
+        let mut changed = Vec::new();
+        for id in self.select_downloads(ids, all)? {
+            // an id listed twice is gone after the first remove
+            let Some(index) = self.all_downloads.iter().position(|d| d.id == id) else {
+                continue;
+            };
+            let status = self.all_downloads[index].get_status();
+            let applies = match control {
+                Control::Pause => is_active(&status) || status == DownloadStatus::Queued,
+                Control::Resume => status == DownloadStatus::Paused,
+                Control::Cancel => !is_finished(&status),
+                Control::Remove { .. } => true,
+                Control::Retry => {
+                    matches!(status, DownloadStatus::Failed | DownloadStatus::Cancelled)
+                }
+            };
+            if !applies {
+                continue;
+            }
+
+            let result = match control {
+                Control::Pause => {
+                    self.all_downloads[index].pause().await;
+                    Ok(())
+                }
+                Control::Resume | Control::Retry => {
+                    self.all_downloads[index].requeue().await;
+                    Ok(())
+                }
+                Control::Cancel => self.cancel_download(&id.to_string()).await,
+                Control::Remove { delete_file } => {
+                    self.remove_download(&id.to_string(), delete_file).await
+                }
+            };
+            // the download is stopped or gone even if e.g. its file couldn't be deleted
+            if let Err(e) = result {
+                error!("{:?} of download {} failed: {}", control, id, e);
+            }
+            changed.push(id.to_string());
+        }
+
+        info!("{:?} applied to {} downloads", control, changed.len());
+        // resumed downloads start and paused ones free their slots and connections
+        self.start_queued_downloads().await;
+        self.balance_connections().await;
+        self.save_downloads(|download, saved_status| saved_status != Some(&download.get_status()));
+        Ok(changed)
+    }
+
+    /// Drop a download from the list and the database, its file is left alone
+    fn forget_download(&mut self, index: usize) -> Download {
+        let download = self.all_downloads.remove(index);
+        self.saved_status.remove(&download.id);
+        self.reported_status.remove(&download.id);
+        let _ = self.event_sender.send(DownloadEventProto {
+            r#type: DownloadEventType::Removed as i32,
+            id: download.id.to_string(),
+            download: None,
+        });
+        if let Some(db) = &self.db
+            && let Err(e) = db.delete_download(&download.id)
+        {
+            error!(
+                "Failed to delete download {} from database: {:#}",
+                download.id, e
+            );
+        }
+        download
+    }
+
+    /// Drop finished downloads from the list, all of them if `ids` is empty.
+    /// Returns the ids of the dropped ones, they still count in the statistics
+    fn purge_downloads(&mut self, ids: &[String]) -> Vec<String> {
+        let purge: Vec<Uuid> = self
+            .all_downloads
+            .iter()
+            .filter(|d| {
+                is_finished(&d.get_status()) && (ids.is_empty() || ids.contains(&d.id.to_string()))
+            })
+            .map(|d| d.id)
+            .collect();
+        self.forget_downloads(&purge)
+    }
+
+    /// Purge the finished downloads older than `history_days` and the oldest
+    /// ones beyond `max_history`
+    fn apply_retention(&mut self) {
+        let mut finished: Vec<(Uuid, DateTime<Utc>)> = self
+            .all_downloads
+            .iter()
+            .filter(|d| is_finished(&d.get_status()))
+            .filter_map(|d| d.date_finished.map(|date| (d.id, date)))
+            .collect();
+        // newest first, so the ones beyond max_history are at the end
+        finished.sort_by_key(|(_, date)| std::cmp::Reverse(*date));
+
+        let cutoff = Utc::now() - chrono::Duration::days(self.history_days as i64);
+        let purge: Vec<Uuid> = finished
+            .iter()
+            .enumerate()
+            .filter(|(index, (_, date))| {
+                (self.history_days > 0 && *date < cutoff)
+                    || (self.max_history > 0 && *index >= self.max_history)
+            })
+            .map(|(_, (id, _))| *id)
+            .collect();
+        if !purge.is_empty() {
+            info!("Purging {} finished downloads from history", purge.len());
+            self.forget_downloads(&purge);
+        }
+    }
+
+    fn forget_downloads(&mut self, ids: &[Uuid]) -> Vec<String> {
+        let mut forgotten = Vec::new();
+        for id in ids {
+            if let Some(index) = self.all_downloads.iter().position(|d| &d.id == id) {
+                forgotten.push(self.forget_download(index).id.to_string());
+            }
+        }
+        forgotten
+    }
+
+    /// Change the global options set in `options`, nothing is changed if one of
+    /// them is invalid. Returns the options as they are now
+    async fn change_global_options(
+        &mut self,
+        options: GlobalOptions,
+    ) -> Result<GlobalOptions, ErrorProto> {
+        if options.max_concurrent_downloads == Some(0) {
+            return Err(invalid_option(
+                "max_concurrent_downloads",
+                "needs at least one download",
+            ));
+        }
+
+        if let Some(max_concurrent_downloads) = options.max_concurrent_downloads {
+            info!(
+                "max_concurrent_downloads changed from {} to {}",
+                self.max_concurrent_downloads, max_concurrent_downloads
+            );
+            self.max_concurrent_downloads = max_concurrent_downloads as usize;
+        }
+        if let Some(max_overall_connections) = options.max_overall_connections {
+            info!(
+                "max_overall_connections changed from {} to {}",
+                self.max_overall_connections, max_overall_connections
+            );
+            self.max_overall_connections = max_overall_connections as usize;
+        }
+        if let Some(limit) = options.max_connections_per_host {
+            info!(
+                "max_connections_per_host changed from {} to {}",
+                self.max_connections_per_host, limit
+            );
+            self.max_connections_per_host = limit as usize;
+        }
+        if let Some(limit) = options.max_overall_download_limit {
+            info!(
+                "max_overall_download_limit changed from {} to {}",
+                self.max_overall_download_limit, limit
+            );
+            self.max_overall_download_limit = limit;
+            self.update_speed_limit();
+        }
+        if options.duplicate_policy.is_some()
+            && let Some(policy) = duplicate_policy_from_proto(&options.duplicate_policy())
+        {
+            info!(
+                "duplicate_policy changed from {:?} to {:?}",
+                self.duplicate_policy, policy
+            );
+            self.duplicate_policy = policy;
+        }
+        // lowering the limit lets running downloads finish, raising it
+        // starts queued ones right away
+        self.start_queued_downloads().await;
+        self.balance_connections().await;
+        Ok(self.global_options())
+    }
+
     async fn handle_command(&mut self, command: ManagerCommand) {
         let respond_to = command.respond_to;
         let request_id = command.request.request_id;
         if let Some(req) = command.request.request {
             match req {
                 Request::AddDownload(download_request) => {
-                    let mut download = Download::new(
-                        convert_to_download_req(download_request),
-                        &DownloadConfig::default(),
-                    );
-
+                    let policy = duplicate_policy_from_proto(&download_request.duplicate_policy())
+                        .unwrap_or_else(|| self.duplicate_policy.clone());
+                    let response = self
+                        .add_download(convert_to_download_req(download_request), &policy)
+                        .await;
                     let _ = respond_to.send(RpcResponse {
                         request_id,
-                        response: Some(Response::DownloadCreated(GetDownload {
-                            id: download.id.to_string(),
-                        })),
+                        response: Some(response),
+                    });
+                }
+                Request::ChangeGlobalOptions(options) => {
+                    let response = match self.change_global_options(options).await {
+                        Ok(options) => Response::GlobalOptions(options),
+                        Err(error) => Response::Error(error),
+                    };
+                    let _ = respond_to.send(RpcResponse {
+                        request_id,
+                        response: Some(response),
+                    });
+                }
+                Request::MoveDownload(request) => {
+                    let result = self.move_download(&request.id, request.r#move());
+                    // queue positions are saved with the downloads
+                    self.save_downloads(|_, _| true);
+                    let _ =
+                        respond_to.send(self.download_response(request_id, &request.id, result));
+                }
+                Request::StartDownloadNow(request) => {
+                    let result = self.start_download_now(&request.id).await;
+                    self.balance_connections().await;
+                    let _ =
+                        respond_to.send(self.download_response(request_id, &request.id, result));
+                }
+                Request::SetDownloadPriority(request) => {
+                    let priority = convert_from_download_priority_proto(&request.priority());
+                    let result = match self
+                        .all_downloads
+                        .iter_mut()
+                        .find(|d| d.id.to_string() == request.id)
+                    {
+                        Some(download) => {
+                            download.priority = priority;
+                            Ok(())
+                        }
+                        None => Err(ErrorProto::not_found(&request.id)),
+                    };
+                    // connections are shared out by priority
+                    self.balance_connections().await;
+                    self.save_downloads(|download, _| download.id.to_string() == request.id);
+                    let _ =
+                        respond_to.send(self.download_response(request_id, &request.id, result));
+                }
+                Request::ChangeDownloadOptions(options) => {
+                    let id = options.id.clone();
+                    let result = self.change_download_options(options).await;
+                    // connections are shared out by priority and per download maximum
+                    self.balance_connections().await;
+                    self.save_downloads(|download, _| download.id.to_string() == id);
+                    let _ = respond_to.send(self.download_response(request_id, &id, result));
+                }
+                Request::GetSchedules(_) => {
+                    let _ = respond_to.send(RpcResponse {
+                        request_id,
+                        response: Some(Response::Schedules(self.schedules_proto())),
+                    });
+                }
+                Request::SetSchedules(schedules) => {
+                    let response = match schedules
+                        .rules
+                        .iter()
+                        .map(|rule| rule.parse::<Schedule>())
+                        .collect::<Result<Vec<_>, _>>()
+                    {
+                        Ok(rules) => {
+                            info!("Schedules replaced, {} rules", rules.len());
+                            self.schedules = rules;
+                            self.save_schedules();
+                            self.apply_schedules().await;
+                            self.start_queued_downloads().await;
+                            Response::Schedules(self.schedules_proto())
+                        }
+                        Err(e) => Response::Error(ErrorProto::new(ErrorCode::InvalidArgument, e)),
+                    };
+                    let _ = respond_to.send(RpcResponse {
+                        request_id,
+                        response: Some(response),
+                    });
+                }
+                Request::PurgeDownloads(request) => {
+                    let ids = self.purge_downloads(&request.ids);
+                    info!("Purged {} downloads", ids.len());
+                    let _ = respond_to.send(ids_response(request_id, Ok(ids)));
+                }
+                Request::PauseDownloads(request) => {
+                    let result = self
+                        .control_downloads(Control::Pause, &request.ids, request.all)
+                        .await;
+                    let _ = respond_to.send(ids_response(request_id, result));
+                }
+                Request::ResumeDownloads(request) => {
+                    let result = self
+                        .control_downloads(Control::Resume, &request.ids, request.all)
+                        .await;
+                    let _ = respond_to.send(ids_response(request_id, result));
+                }
+                Request::CancelDownloads(request) => {
+                    let result = self
+                        .control_downloads(Control::Cancel, &request.ids, request.all)
+                        .await;
+                    let _ = respond_to.send(ids_response(request_id, result));
+                }
+                Request::RemoveDownloads(request) => {
+                    let control = Control::Remove {
+                        delete_file: request.delete_file,
+                    };
+                    let result = self
+                        .control_downloads(control, &request.ids, request.all)
+                        .await;
+                    let _ = respond_to.send(ids_response(request_id, result));
+                }
+                Request::RetryDownloads(request) => {
+                    let result = self
+                        .control_downloads(Control::Retry, &request.ids, request.all)
+                        .await;
+                    let _ = respond_to.send(ids_response(request_id, result));
+                }
+                Request::GetStats(request) => {
+                    // save first so the running downloads are counted with their latest bytes
+                    self.save_downloads(|download, _| is_active(&download.get_status()));
+                    let response = match &self.db {
+                        Some(db) => match db.get_download_stats(request.days.unwrap_or(30)) {
+                            Ok(stats) => Response::Stats(stats_proto(stats)),
+                            Err(e) => {
+                                error!("Failed to get download stats: {:#}", e);
+                                Response::Error(ErrorProto::internal(format!(
+                                    "failed to read the statistics: {:#}",
+                                    e
+                                )))
+                            }
+                        },
+                        None => Response::Error(ErrorProto::new(
+                            ErrorCode::Unavailable,
+                            "statistics need a database",
+                        )),
+                    };
+                    let _ = respond_to.send(RpcResponse {
+                        request_id,
+                        response: Some(response),
+                    });
+                }
+                Request::GetGlobalOptions(_) => {
+                    let _ = respond_to.send(RpcResponse {
+                        request_id,
+                        response: Some(Response::GlobalOptions(self.global_options())),
                     });
-                    let _ = download.start().await;
-                    self.all_downloads.push(download);
                 }
                 Request::HeartBeat(heartbeat) => {
                     let _ = respond_to.send(RpcResponse {
@@ -104,26 +1342,734 @@ impl DownloadManager {
                         None => {
                             let _ = respond_to.send(RpcResponse {
                                 request_id,
-                                response: Some(Response::Error(ErrorProto {
-                                    kind: "NOT FOUND".to_string(),
-                                })),
+                                response: Some(Response::Error(ErrorProto::not_found(&id))),
                             });
                         }
                     }
                 }
-                Request::GetDownloads(_) => {
-                    let downloads: Vec<utils::rpc_types::Download> = self
+                Request::Hello(_) => {
+                    // answered by the RPC server, it knows whether its transport
+                    // needs a secret
+                    let _ = respond_to.send(RpcResponse {
+                        request_id,
+                        response: Some(Response::Hello(hello_reply(false))),
+                    });
+                }
+                Request::Unsubscribe(_) => {
+                    // subscriptions are kept by the RPC server
+                    let _ = respond_to.send(RpcResponse {
+                        request_id,
+                        response: Some(Response::Unsubscribed(Unsubscribed {})),
+                    });
+                }
+                Request::Authenticate(_) => {
+                    // the secret is checked by the RPC server, a request that
+                    // gets here is already allowed
+                    let _ = respond_to.send(RpcResponse {
+                        request_id,
+                        response: Some(Response::Authenticated(Authenticated {})),
+                    });
+                }
+                Request::Subscribe(filter) => {
+                    // the events are pushed by the RPC server, the subscriber
+                    // starts from the downloads as they are now
+                    let downloads = self
                         .all_downloads
-                        .clone()
                         .iter()
-                        .map(|d| convert_to_download_proto(d))
-                        .collect();
+                        .filter(|download| {
+                            filter.ids.is_empty() || filter.ids.contains(&download.id.to_string())
+                        })
+                        .map(convert_to_download_proto)
+                        .collect::<Vec<_>>();
+                    let _ = respond_to.send(RpcResponse {
+                        request_id,
+                        response: Some(Response::Downloads(DownloadList {
+                            total: downloads.len() as u32,
+                            list: downloads,
+                            next_cursor: None,
+                        })),
+                    });
+                }
+                Request::GetDownloads(query) => {
+                    let response = match list_downloads(&self.all_downloads, &query) {
+                        Ok(downloads) => Response::Downloads(downloads),
+                        Err(error) => Response::Error(error),
+                    };
                     let _ = respond_to.send(RpcResponse {
                         request_id,
-                        response: Some(Response::Downloads(DownloadList { list: downloads })),
+                        response: Some(response),
                     });
                 }
             }
         }
     }
+
+    fn global_options(&self) -> GlobalOptions {
+        GlobalOptions {
+            max_concurrent_downloads: Some(self.max_concurrent_downloads as u64),
+            max_overall_connections: Some(self.max_overall_connections as u64),
+            max_overall_download_limit: Some(self.max_overall_download_limit),
+            max_connections_per_host: Some(self.max_connections_per_host as u64),
+            duplicate_policy: Some(duplicate_policy_to_proto(&self.duplicate_policy) as i32),
+        }
+    }
+
+    fn schedules_proto(&self) -> Schedules {
+        Schedules {
+            rules: self.schedules.iter().map(Schedule::to_string).collect(),
+        }
+    }
+
+    /// Response with the download after a request changed it, or the error
+    fn download_response(
+        &self,
+        request_id: u64,
+        id: &str,
+        result: Result<(), ErrorProto>,
+    ) -> RpcResponse {
+        let response = match result.and_then(|_| {
+            self.all_downloads
+                .iter()
+                .find(|d| d.id.to_string() == id)
+                .ok_or_else(|| ErrorProto::not_found(id))
+        }) {
+            Ok(download) => Response::Download(convert_to_download_proto(download)),
+            Err(error) => Response::Error(error),
+        };
+        RpcResponse {
+            request_id,
+            response: Some(response),
+        }
+    }
+}
+
+fn duplicate_policy_from_proto(policy: &DuplicatePolicyProto) -> Option<DuplicatePolicy> {
+    match policy {
+        DuplicatePolicyProto::Unspecified => None,
+        DuplicatePolicyProto::Reject => Some(DuplicatePolicy::Reject),
+        DuplicatePolicyProto::ReturnExisting => Some(DuplicatePolicy::ReturnExisting),
+        DuplicatePolicyProto::Rename => Some(DuplicatePolicy::Rename),
+        DuplicatePolicyProto::Restart => Some(DuplicatePolicy::Restart),
+    }
+}
+
+fn duplicate_policy_to_proto(policy: &DuplicatePolicy) -> DuplicatePolicyProto {
+    match policy {
+        DuplicatePolicy::Reject => DuplicatePolicyProto::Reject,
+        DuplicatePolicy::ReturnExisting => DuplicatePolicyProto::ReturnExisting,
+        DuplicatePolicy::Rename => DuplicatePolicyProto::Rename,
+        DuplicatePolicy::Restart => DuplicatePolicyProto::Restart,
+    }
+}
+
+/// Response listing the downloads a request changed, or the error
+fn ids_response(request_id: u64, result: Result<Vec<String>, ErrorProto>) -> RpcResponse {
+    let response = match result {
+        Ok(ids) => Response::DownloadIds(DownloadIds { ids }),
+        Err(error) => Response::Error(error),
+    };
+    RpcResponse {
+        request_id,
+        response: Some(response),
+    }
+}
+
+fn invalid_option(option: &str, message: &str) -> ErrorProto {
+    ErrorProto::new(
+        ErrorCode::InvalidArgument,
+        format!("{}: {}", option, message),
+    )
+    .with_detail("option", option)
+}
+
+fn not_queued(id: &str) -> ErrorProto {
+    ErrorProto::new(
+        ErrorCode::FailedPrecondition,
+        format!("download {} is not queued", id),
+    )
+    .with_detail("id", id)
+}
+
+fn stats_proto(stats: DownloadStats) -> Stats {
+    let bucket_proto = |bucket: StatsBucket| StatsBucketProto {
+        key: bucket.key,
+        bytes: bucket.bytes,
+        succeeded: bucket.succeeded,
+        failed: bucket.failed,
+        cancelled: bucket.cancelled,
+    };
+    Stats {
+        total_downloads: stats.total_downloads,
+        active_downloads: stats.active_downloads,
+        paused_downloads: stats.paused_downloads,
+        total_downloaded_bytes: stats.total_downloaded_bytes,
+        succeeded: stats.succeeded,
+        failed: stats.failed,
+        cancelled: stats.cancelled,
+        history_bytes: stats.history_bytes,
+        average_speed: stats.average_speed,
+        per_day: stats.per_day.into_iter().map(bucket_proto).collect(),
+        per_host: stats.per_host.into_iter().map(bucket_proto).collect(),
+    }
+}
+
+/// whether a download in this status is done, successfully or not
+fn is_finished(status: &DownloadStatus) -> bool {
+    matches!(
+        status,
+        DownloadStatus::Complete | DownloadStatus::Failed | DownloadStatus::Cancelled
+    )
+}
+
+/// Connections each of the running `downloads` (host and the connections it
+/// wants, higher priority first) may open within `overall_limit` and the
+/// `host_limit` of its host, usize::MAX for either means unlimited
+///
+/// every download keeps at least one connection, the rest go to the first
+/// downloads so lower priority ones lose their connections first. Downloads
+/// grow again on a later tick once other ones free their connections
+fn split_connections(
+    downloads: &[(Option<String>, usize)],
+    overall_limit: usize,
+    host_limit: impl Fn(Option<&str>) -> usize,
+) -> Vec<usize> {
+    let mut budget = overall_limit.saturating_sub(downloads.len());
+    let mut host_budgets: HashMap<&Option<String>, usize> = HashMap::new();
+    for (host, _) in downloads {
+        let host_budget = host_budgets
+            .entry(host)
+            .or_insert_with(|| host_limit(host.as_deref()));
+        *host_budget = host_budget.saturating_sub(1);
+    }
+
+    downloads
+        .iter()
+        .map(|(host, wanted)| {
+            let host_budget = host_budgets.get_mut(host).expect("budget for every host");
+            let extra = budget.min(*host_budget).min(wanted.saturating_sub(1));
+            budget -= extra;
+            *host_budget -= extra;
+            1 + extra
+        })
+        .collect()
+}
+
+/// whether a download in this status takes up one of the concurrent slots
+fn is_active(status: &DownloadStatus) -> bool {
+    matches!(
+        status,
+        DownloadStatus::Connecting | DownloadStatus::Retrying | DownloadStatus::Downloading
+    )
+}
+
+/// Downloads that didn't finish before the daemon stopped come back Paused, or
+/// Queued if `auto_resume` picks them. Finished parts keep their status and the
+/// rest resume from their saved bytes once the server confirms the file is unchanged
+fn restore_interrupted(download: &mut Download, auto_resume: &AutoResume) {
+    download.restored = !matches!(download.parts, DownloadParts::None);
+    let status = download.get_status();
+    if is_finished(&status) {
+        return;
+    }
+
+    let resume = match auto_resume {
+        AutoResume::All => true,
+        // the ones waiting in the queue never ran, and the user may have paused
+        // the rest
+        AutoResume::Active => is_active(&status),
+        AutoResume::None => false,
+    };
+    let status = match resume {
+        true => DownloadStatus::Queued,
+        false => DownloadStatus::Paused,
+    };
+
+    match &mut download.parts {
+        DownloadParts::Resumable(parts) => {
+            for part in parts.iter_mut() {
+                if part.status != DownloadStatus::Complete {
+                    part.status = status.clone();
+                }
+            }
+        }
+        DownloadParts::NonResumable(part) => {
+            // without ranges the download starts over from the first byte
+            part.status = status.clone();
+            part.bytes_downloaded = 0;
+        }
+        DownloadParts::None => {}
+    }
+    info!("Restored download {} as {:?}", download.id, status);
+    download.status = status;
+    download.progress = DownloadPartsProgress::from(&download.parts);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use download_engine::{
+        category::{Category, CategoryRule},
+        types::DownloadPriority,
+    };
+
+    fn download(url: &str, status: DownloadStatus, priority: DownloadPriority) -> Download {
+        let mut download = Download::new(
+            DownloadRequest {
+                url: url.into(),
+                file_dir: "/downloads".into(),
+                file_name: None,
+                referrer: None,
+                headers: None,
+                priority,
+                checksum: None,
+                category: None,
+            },
+            &DownloadConfig::default(),
+        );
+        download.status = status;
+        download
+    }
+
+    fn request(url: &str, file_name: &str) -> DownloadRequest {
+        DownloadRequest {
+            url: url.into(),
+            file_dir: "/downloads".into(),
+            file_name: Some(file_name.into()),
+            referrer: None,
+            headers: None,
+            priority: DownloadPriority::Normal,
+            checksum: None,
+            category: None,
+        }
+    }
+
+    /// A manager that never starts anything, so no request goes out
+    fn stopped_manager() -> DownloadManager {
+        let (manager, _) = DownloadManager::new(&NetManthanConfig {
+            max_concurrent_downloads: 0,
+            ..Default::default()
+        });
+        manager
+    }
+
+    async fn add(
+        manager: &mut DownloadManager,
+        request: DownloadRequest,
+        policy: DuplicatePolicy,
+    ) -> Result<String, ErrorProto> {
+        match manager.add_download(request, &policy).await {
+            Response::DownloadCreated(created) => Ok(created.id),
+            Response::Error(error) => Err(error),
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+
+    fn queue_ids(manager: &DownloadManager) -> Vec<Uuid> {
+        manager.all_downloads.iter().map(|d| d.id).collect()
+    }
+
+    #[test]
+    fn splits_connections_by_priority_and_host() {
+        let host = |name: &str| Some(name.to_string());
+        let limit = |host: Option<&str>| match host {
+            Some("slow.org") => 3,
+            _ => usize::MAX,
+        };
+
+        // unlimited, everyone gets what they want
+        let downloads = [(host("a.com"), 8), (host("b.com"), 4)];
+        assert_eq!(split_connections(&downloads, usize::MAX, limit), [8, 4]);
+
+        // the first download takes what is left after everyone got one
+        assert_eq!(split_connections(&downloads, 6, limit), [5, 1]);
+        // over budget, still one connection each
+        assert_eq!(split_connections(&downloads, 1, limit), [1, 1]);
+
+        let downloads = [
+            (host("slow.org"), 8),
+            (host("a.com"), 2),
+            (host("slow.org"), 8),
+        ];
+        assert_eq!(split_connections(&downloads, usize::MAX, limit), [2, 2, 1]);
+    }
+
+    #[test]
+    fn starts_queued_downloads_by_priority_within_host_limits() {
+        let (mut manager, _) = DownloadManager::new(&NetManthanConfig {
+            max_concurrent_downloads: 4,
+            host_connection_limits: HashMap::from([("busy.com".to_string(), 1)]),
+            ..Default::default()
+        });
+        manager.all_downloads = vec![
+            download(
+                "https://a.com/1",
+                DownloadStatus::Downloading,
+                DownloadPriority::Normal,
+            ),
+            download(
+                "https://busy.com/1",
+                DownloadStatus::Downloading,
+                DownloadPriority::Normal,
+            ),
+            download(
+                "https://busy.com/2",
+                DownloadStatus::Queued,
+                DownloadPriority::High,
+            ),
+            download(
+                "https://b.com/1",
+                DownloadStatus::Queued,
+                DownloadPriority::Low,
+            ),
+            download(
+                "https://c.com/1",
+                DownloadStatus::Queued,
+                DownloadPriority::Normal,
+            ),
+            download(
+                "https://d.com/1",
+                DownloadStatus::Queued,
+                DownloadPriority::High,
+            ),
+        ];
+        assert_eq!(manager.downloads_to_start(), [5, 4]);
+
+        manager.max_concurrent_downloads = 2;
+        assert!(manager.downloads_to_start().is_empty());
+    }
+
+    #[tokio::test]
+    async fn lets_downloads_that_cant_resume_finish_when_the_window_closes() {
+        let mut manager = stopped_manager();
+        let running = |resumable: bool| {
+            let mut download = download(
+                "https://a.com/1",
+                DownloadStatus::Downloading,
+                DownloadPriority::Normal,
+            );
+            download.create_parts(100, resumable);
+            download.set_status(DownloadStatus::Downloading);
+            download.progress = DownloadPartsProgress::from(&download.parts);
+            download
+        };
+        manager.all_downloads = vec![running(true), running(false)];
+
+        manager.queue_running_downloads().await;
+        assert_eq!(
+            manager.all_downloads[0].get_status(),
+            DownloadStatus::Queued
+        );
+        assert_eq!(
+            manager.all_downloads[1].get_status(),
+            DownloadStatus::Downloading
+        );
+    }
+
+    #[test]
+    fn auto_resumes_the_downloads_the_mode_picks() {
+        let statuses = [
+            DownloadStatus::Downloading,
+            DownloadStatus::Connecting,
+            DownloadStatus::Queued,
+            DownloadStatus::Created,
+            DownloadStatus::Paused,
+            DownloadStatus::Complete,
+        ];
+        let restore = |auto_resume: AutoResume| {
+            statuses
+                .iter()
+                .map(|status| {
+                    let mut download =
+                        download("https://a.com/1", status.clone(), DownloadPriority::Normal);
+                    restore_interrupted(&mut download, &auto_resume);
+                    download.get_status()
+                })
+                .collect::<Vec<_>>()
+        };
+        use DownloadStatus::*;
+
+        assert_eq!(
+            restore(AutoResume::All),
+            [Queued, Queued, Queued, Queued, Queued, Complete]
+        );
+        assert_eq!(
+            restore(AutoResume::Active),
+            [Queued, Queued, Paused, Paused, Paused, Complete]
+        );
+        assert_eq!(
+            restore(AutoResume::None),
+            [Paused, Paused, Paused, Paused, Paused, Complete]
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_zero_concurrent_downloads() {
+        let mut manager = stopped_manager();
+        let error = manager
+            .change_global_options(GlobalOptions {
+                max_concurrent_downloads: Some(0),
+                max_overall_connections: Some(5),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), ErrorCode::InvalidArgument);
+        assert_eq!(error.details["option"], "max_concurrent_downloads");
+        // nothing else changed either
+        assert_eq!(manager.max_overall_connections, 0);
+
+        let options = manager
+            .change_global_options(GlobalOptions {
+                max_concurrent_downloads: Some(2),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(options.max_concurrent_downloads, Some(2));
+    }
+
+    #[tokio::test]
+    async fn started_downloads_stay_within_their_host_budget() {
+        use download_engine::ResumableDownloadPart;
+
+        // accepts connections but never answers, the parts keep running
+        let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/file", server.local_addr().unwrap());
+        let (mut manager, _) = DownloadManager::new(&NetManthanConfig {
+            max_connections_per_host: 4,
+            ..Default::default()
+        });
+        let running = download(&url, DownloadStatus::Downloading, DownloadPriority::Normal);
+        running.connection_limit.store(3, Ordering::Relaxed);
+        let mut queued = download(&url, DownloadStatus::Queued, DownloadPriority::Normal);
+        queued.file = std::env::temp_dir().join(format!("{}.nm", queued.id));
+        queued.file_name = queued.file.file_name().map(PathBuf::from);
+        queued.parts = DownloadParts::Resumable(
+            (0..4)
+                .map(|i| ResumableDownloadPart {
+                    id: Uuid::new_v4(),
+                    status: DownloadStatus::Queued,
+                    start_byte: i * 100,
+                    end_byte: i * 100 + 99,
+                    bytes_downloaded: 0,
+                    current_speed: 0,
+                })
+                .collect(),
+        );
+        queued.progress = DownloadPartsProgress::from(&queued.parts);
+        manager.all_downloads = vec![running, queued];
+
+        assert_eq!(manager.downloads_to_start(), [1]);
+        manager.start_download(1).await.unwrap();
+        // the running download keeps 3 of the host's 4 connections
+        assert_eq!(manager.all_downloads[1].active_connections().await, 1);
+
+        manager.all_downloads[1].remove(true).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn asks_about_new_downloads_in_the_background() {
+        // accepts connections but never answers
+        let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/file", server.local_addr().unwrap());
+        let (mut manager, mut answers) = DownloadManager::new(&NetManthanConfig::default());
+        manager.all_downloads = vec![download(
+            &url,
+            DownloadStatus::Queued,
+            DownloadPriority::Normal,
+        )];
+
+        tokio::time::timeout(Duration::from_secs(1), manager.start_download(0))
+            .await
+            .expect("start_download waited for the server")
+            .unwrap();
+        assert_eq!(
+            manager.all_downloads[0].get_status(),
+            DownloadStatus::Connecting
+        );
+
+        // a download paused meanwhile stays paused
+        manager.all_downloads[0].pause().await;
+        let id = manager.all_downloads[0].id;
+        manager
+            .finish_info(id, Err(DownloadError::general("no answer")))
+            .await;
+        assert_eq!(
+            manager.all_downloads[0].get_status(),
+            DownloadStatus::Paused
+        );
+        assert!(answers.try_recv().is_err());
+    }
+
+    #[test]
+    fn moves_downloads_between_queued_ones() {
+        let (mut manager, _) = DownloadManager::new(&NetManthanConfig::default());
+        manager.all_downloads = vec![
+            download(
+                "https://a.com/1",
+                DownloadStatus::Queued,
+                DownloadPriority::Normal,
+            ),
+            download(
+                "https://a.com/2",
+                DownloadStatus::Downloading,
+                DownloadPriority::Normal,
+            ),
+            download(
+                "https://a.com/3",
+                DownloadStatus::Queued,
+                DownloadPriority::Normal,
+            ),
+            download(
+                "https://a.com/4",
+                DownloadStatus::Queued,
+                DownloadPriority::Normal,
+            ),
+        ];
+        let [first, running, third, last] = queue_ids(&manager)[..] else {
+            unreachable!()
+        };
+
+        manager
+            .move_download(&last.to_string(), QueueMove::MoveUp)
+            .unwrap();
+        assert_eq!(queue_ids(&manager), [first, running, last, third]);
+
+        manager
+            .move_download(&third.to_string(), QueueMove::MoveTop)
+            .unwrap();
+        assert_eq!(queue_ids(&manager), [third, first, running, last]);
+
+        manager
+            .move_download(&first.to_string(), QueueMove::MoveBottom)
+            .unwrap();
+        assert_eq!(queue_ids(&manager), [third, running, last, first]);
+
+        let err = manager
+            .move_download(&running.to_string(), QueueMove::MoveTop)
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::FailedPrecondition);
+    }
+
+    #[tokio::test]
+    async fn applies_the_duplicate_policy() {
+        let mut manager = stopped_manager();
+        let url = "https://example.com/a.zip";
+        let first = add(&mut manager, request(url, "a.zip"), DuplicatePolicy::Reject)
+            .await
+            .unwrap();
+
+        let err = add(
+            &mut manager,
+            request(&format!("{}#part", url), "other.zip"),
+            DuplicatePolicy::Reject,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::AlreadyExists);
+
+        let existing = add(
+            &mut manager,
+            request(url, "a.zip"),
+            DuplicatePolicy::ReturnExisting,
+        )
+        .await
+        .unwrap();
+        assert_eq!(existing, first);
+
+        // the same target file from another url
+        let renamed = add(
+            &mut manager,
+            request("https://mirror.org/a.zip", "a.zip"),
+            DuplicatePolicy::Rename,
+        )
+        .await
+        .unwrap();
+        assert_ne!(renamed, first);
+        assert_eq!(
+            manager.all_downloads[1].file_name,
+            Some(PathBuf::from("a (1).zip"))
+        );
+
+        manager.all_downloads[0].pause().await;
+        let restarted = add(
+            &mut manager,
+            request(url, "a.zip"),
+            DuplicatePolicy::Restart,
+        )
+        .await
+        .unwrap();
+        assert_eq!(restarted, first);
+        assert_eq!(
+            manager.all_downloads[0].get_status(),
+            DownloadStatus::Queued
+        );
+        assert_eq!(manager.all_downloads.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn finished_downloads_are_not_duplicates() {
+        let mut manager = stopped_manager();
+        let url = "https://example.com/a.zip";
+        let first = add(&mut manager, request(url, "a.zip"), DuplicatePolicy::Reject)
+            .await
+            .unwrap();
+        manager.all_downloads[0].status = DownloadStatus::Complete;
+
+        let second = add(
+            &mut manager,
+            request(url, "a.zip"),
+            DuplicatePolicy::ReturnExisting,
+        )
+        .await
+        .unwrap();
+        assert_ne!(second, first);
+    }
+
+    #[tokio::test]
+    async fn resolves_the_directory_before_looking_for_duplicates() {
+        let (mut manager, _) = DownloadManager::new(&NetManthanConfig {
+            max_concurrent_downloads: 0,
+            download_config: DownloadConfig {
+                download_dir: "/downloads".into(),
+                categories: vec![Category {
+                    name: "Archives".into(),
+                    rules: vec![CategoryRule::Extension("zip".into())],
+                    directory: Some("archives".into()),
+                    connections: None,
+                    speed_limit: None,
+                }],
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let empty_dir = |url: &str| DownloadRequest {
+            file_dir: PathBuf::new(),
+            ..request(url, "a.zip")
+        };
+        add(
+            &mut manager,
+            empty_dir("https://example.com/a.zip"),
+            DuplicatePolicy::Reject,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            manager.all_downloads[0].target_file(),
+            Some(PathBuf::from("/downloads/archives/a.zip"))
+        );
+        assert_eq!(
+            manager.all_downloads[0].category.as_deref(),
+            Some("Archives")
+        );
+
+        let err = add(
+            &mut manager,
+            empty_dir("https://mirror.org/a.zip"),
+            DuplicatePolicy::Reject,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::AlreadyExists);
+    }
 }
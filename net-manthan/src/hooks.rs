@@ -0,0 +1,292 @@
+use download_engine::{Download, types::DownloadStatus};
+use std::{
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{process::Command, task::JoinSet};
+use tracing::{error, info, warn};
+
+/// Download events a hook command can run on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HookEvent {
+    Start,
+    Complete,
+    Error,
+    Pause,
+}
+
+/// User commands run on download events, like aria2's `--on-download-complete`
+///
+/// the command is run directly (not through a shell) with the download id, the
+/// path of the file and the url as arguments. The same values, the status and the
+/// event are also set as `NET_MANTHAN_*` environment variables
+#[derive(Debug, Clone)]
+pub struct Hooks {
+    pub on_start: Option<String>,
+    pub on_complete: Option<String>,
+    pub on_error: Option<String>,
+    pub on_pause: Option<String>,
+    /// commands still running after this long are killed
+    pub timeout: Duration,
+    /// commands still running, shared by the clones so one can wait for them
+    pub(crate) running: Arc<Mutex<JoinSet<()>>>,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Self {
+            on_start: None,
+            on_complete: None,
+            on_error: None,
+            on_pause: None,
+            timeout: Duration::from_secs(60),
+            running: Arc::default(),
+        }
+    }
+}
+
+impl HookEvent {
+    /// The events a change from `previous` to `current` status fires, none for
+    /// downloads that were only just added or restored
+    ///
+    /// a download that finished without ever being seen running (e.g. Queued to
+    /// Complete between two checks) still fires Start first
+    pub fn from_status_change(
+        previous: Option<&DownloadStatus>,
+        current: &DownloadStatus,
+    ) -> Vec<Self> {
+        let Some(previous) = previous else {
+            return Vec::new();
+        };
+        let Some(event) = Self::from_status_transition(previous, current) else {
+            return Vec::new();
+        };
+        let was_running = matches!(
+            previous,
+            DownloadStatus::Connecting | DownloadStatus::Retrying | DownloadStatus::Downloading
+        );
+        match event {
+            HookEvent::Complete | HookEvent::Error if !was_running => {
+                vec![HookEvent::Start, event]
+            }
+            _ => vec![event],
+        }
+    }
+
+    fn from_status_transition(previous: &DownloadStatus, current: &DownloadStatus) -> Option<Self> {
+        if previous == current {
+            return None;
+        }
+        match current {
+            // retrying and reconnecting belong to the same run
+            DownloadStatus::Connecting | DownloadStatus::Retrying | DownloadStatus::Downloading
+                if !matches!(
+                    previous,
+                    DownloadStatus::Connecting
+                        | DownloadStatus::Retrying
+                        | DownloadStatus::Downloading
+                ) =>
+            {
+                Some(HookEvent::Start)
+            }
+            DownloadStatus::Complete => Some(HookEvent::Complete),
+            DownloadStatus::Failed => Some(HookEvent::Error),
+            DownloadStatus::Paused => Some(HookEvent::Pause),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            HookEvent::Start => "start",
+            HookEvent::Complete => "complete",
+            HookEvent::Error => "error",
+            HookEvent::Pause => "pause",
+        }
+    }
+}
+
+impl Hooks {
+    fn command(&self, event: HookEvent) -> Option<&str> {
+        match event {
+            HookEvent::Start => self.on_start.as_deref(),
+            HookEvent::Complete => self.on_complete.as_deref(),
+            HookEvent::Error => self.on_error.as_deref(),
+            HookEvent::Pause => self.on_pause.as_deref(),
+        }
+    }
+
+    /// Run the command for `event` in the background, its output goes to the log
+    pub fn run(&self, event: HookEvent, download: &Download) {
+        let Some(command) = self.command(event) else {
+            return;
+        };
+        let id = download.id.to_string();
+        // a complete download was renamed to its final name already
+        let path = download.file.to_string_lossy().into_owned();
+        let status = format!("{:?}", download.get_status()).to_lowercase();
+
+        let mut child = Command::new(command);
+        child
+            .args([&id, &path, &download.url])
+            .env("NET_MANTHAN_EVENT", event.name())
+            .env("NET_MANTHAN_ID", &id)
+            .env("NET_MANTHAN_PATH", &path)
+            .env("NET_MANTHAN_URL", &download.url)
+            .env("NET_MANTHAN_STATUS", status)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // dropping the output future on timeout kills it
+            .kill_on_drop(true);
+
+        let command = command.to_string();
+        let timeout = self.timeout;
+        let mut running = self.running.lock().unwrap();
+        // forget the commands that are done
+        while running.try_join_next().is_some() {}
+        running.spawn(async move {
+            info!("Running {} hook {:?} for {}", event.name(), command, id);
+            let child = match child.spawn() {
+                Ok(child) => child,
+                Err(e) => {
+                    error!("Failed to run {} hook {:?}: {}", event.name(), command, e);
+                    return;
+                }
+            };
+
+            match tokio::time::timeout(timeout, child.wait_with_output()).await {
+                Ok(Ok(output)) => {
+                    for line in String::from_utf8_lossy(&output.stdout).lines() {
+                        info!("[{} hook {}] {}", event.name(), id, line);
+                    }
+                    for line in String::from_utf8_lossy(&output.stderr).lines() {
+                        warn!("[{} hook {}] {}", event.name(), id, line);
+                    }
+                    if !output.status.success() {
+                        warn!(
+                            "{} hook {:?} for {} exited with {}",
+                            event.name(),
+                            command,
+                            id,
+                            output.status
+                        );
+                    }
+                }
+                Ok(Err(e)) => error!("{} hook {:?} failed: {}", event.name(), command, e),
+                Err(_) => warn!(
+                    "{} hook {:?} for {} killed after {:?}",
+                    event.name(),
+                    command,
+                    id,
+                    timeout
+                ),
+            }
+        });
+    }
+
+    /// Wait for the commands still running, they are killed on timeout anyway
+    pub async fn wait(&self) {
+        let mut running = std::mem::take(&mut *self.running.lock().unwrap());
+        while running.join_next().await.is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_from_status_changes() {
+        use DownloadStatus::*;
+        assert_eq!(HookEvent::from_status_change(None, &Complete), []);
+        assert_eq!(
+            HookEvent::from_status_change(Some(&Queued), &Connecting),
+            [HookEvent::Start]
+        );
+        assert_eq!(
+            HookEvent::from_status_change(Some(&Retrying), &Downloading),
+            []
+        );
+        assert_eq!(
+            HookEvent::from_status_change(Some(&Downloading), &Complete),
+            [HookEvent::Complete]
+        );
+        assert_eq!(
+            HookEvent::from_status_change(Some(&Queued), &Complete),
+            [HookEvent::Start, HookEvent::Complete]
+        );
+        assert_eq!(
+            HookEvent::from_status_change(Some(&Downloading), &Failed),
+            [HookEvent::Error]
+        );
+        assert_eq!(
+            HookEvent::from_status_change(Some(&Downloading), &Paused),
+            [HookEvent::Pause]
+        );
+        assert_eq!(
+            HookEvent::from_status_change(Some(&Queued), &Paused),
+            [HookEvent::Pause]
+        );
+        assert_eq!(HookEvent::from_status_change(Some(&Paused), &Queued), []);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn complete_hook_gets_the_finished_file() -> anyhow::Result<()> {
+        use download_engine::{
+            DownloadParts, NonResumableDownloadPart, download_config::DownloadConfig,
+            types::DownloadRequest,
+        };
+        use std::os::unix::fs::PermissionsExt;
+        use uuid::Uuid;
+
+        let dir = tempfile::tempdir()?;
+        let mut download = Download::new(
+            DownloadRequest {
+                url: "https://example.com/a.zip".into(),
+                file_dir: dir.path().into(),
+                file_name: Some("a.zip".into()),
+                referrer: None,
+                headers: None,
+                priority: Default::default(),
+                checksum: None,
+                category: None,
+            },
+            &DownloadConfig::default(),
+        );
+        download.file = dir.path().join("a.zip.nm");
+        std::fs::write(&download.file, "data")?;
+        download.parts = DownloadParts::NonResumable(NonResumableDownloadPart {
+            id: Uuid::new_v4(),
+            status: DownloadStatus::Complete,
+            total_size: 4,
+            bytes_downloaded: 4,
+            current_speed: 0,
+        });
+        download.finish_file().await?;
+        assert_eq!(download.file, dir.path().join("a.zip"));
+
+        // the hook notes the path it got if there is a file
+        let seen = dir.path().join("seen");
+        let script = dir.path().join("hook.sh");
+        std::fs::write(
+            &script,
+            format!("#!/bin/sh\n[ -e \"$2\" ] && echo \"$2\" > {:?}\n", seen),
+        )?;
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755))?;
+        let hooks = Hooks {
+            on_complete: Some(script.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        hooks.run(HookEvent::Complete, &download);
+        hooks.wait().await;
+
+        assert_eq!(
+            std::fs::read_to_string(&seen)?.trim_end(),
+            download.file.to_string_lossy()
+        );
+        Ok(())
+    }
+}
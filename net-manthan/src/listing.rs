@@ -0,0 +1,273 @@
+use download_engine::Download;
+use std::cmp::Ordering;
+use utils::{
+    conversion::{
+        convert_to_download_priority_proto, convert_to_download_proto,
+        convert_to_download_status_proto,
+    },
+    rpc_types::{
+        Download as DownloadProto, DownloadList, Error as ErrorProto, ErrorCode, GetDownloads,
+        SortBy, SortKey,
+    },
+};
+
+/// Field mask paths of `Download`, id is always filled in
+const FIELDS: [&str; 21] = [
+    "id",
+    "url",
+    "file",
+    "file_name",
+    "headers",
+    "referrer",
+    "date_added",
+    "active_time",
+    "status",
+    "parts",
+    "smoothed_speed",
+    "eta",
+    "speed_history",
+    "priority",
+    "checksum",
+    "category",
+    "date_finished",
+    "total_bytes",
+    "bytes_downloaded",
+    "connections",
+    "speed_limit",
+];
+
+/// The page of `downloads` (in queue order) a `GetDownloads` asks for
+pub fn list_downloads(
+    downloads: &[Download],
+    query: &GetDownloads,
+) -> Result<DownloadList, ErrorProto> {
+    let fields = query
+        .fields
+        .as_ref()
+        .map(|mask| mask.paths.as_slice())
+        .unwrap_or_default();
+    if let Some(field) = fields
+        .iter()
+        .find(|field| !FIELDS.contains(&field.as_str()))
+    {
+        return Err(ErrorProto::new(
+            ErrorCode::InvalidArgument,
+            format!("unknown field {}", field),
+        )
+        .with_detail("field", field));
+    }
+
+    let mut matching: Vec<&Download> = downloads
+        .iter()
+        .filter(|download| matches(query, download))
+        .collect();
+    // stable, downloads equal in every key stay in queue order
+    matching.sort_by(|a, b| {
+        query
+            .sort
+            .iter()
+            .map(|key| compare(key, a, b))
+            .find(|order| order.is_ne())
+            .unwrap_or(Ordering::Equal)
+    });
+
+    let start = match &query.cursor {
+        Some(cursor) => {
+            matching
+                .iter()
+                .position(|download| download.id.to_string() == *cursor)
+                .ok_or_else(|| {
+                    ErrorProto::new(
+                        ErrorCode::InvalidArgument,
+                        format!("cursor {} is not in the list anymore", cursor),
+                    )
+                    .with_detail("cursor", cursor)
+                })?
+                + 1
+        }
+        None => query.offset as usize,
+    };
+    let end = match query.limit {
+        0 => matching.len(),
+        limit => start.saturating_add(limit as usize).min(matching.len()),
+    };
+    let page = matching.get(start..end).unwrap_or_default();
+
+    Ok(DownloadList {
+        list: page
+            .iter()
+            .map(|download| mask(convert_to_download_proto(download), fields))
+            .collect(),
+        total: matching.len() as u32,
+        next_cursor: (end < matching.len())
+            .then(|| page.last().map(|download| download.id.to_string()))
+            .flatten(),
+    })
+}
+
+/// Whether `download` passes every filter set in `query`
+fn matches(query: &GetDownloads, download: &Download) -> bool {
+    let status = convert_to_download_status_proto(&download.get_status()) as i32;
+    let search = query.search.as_ref().map(|text| text.to_lowercase());
+    (query.category.is_none() || download.category == query.category)
+        && (query.statuses.is_empty() || query.statuses.contains(&status))
+        && query.host.as_ref().is_none_or(|host| {
+            download
+                .host()
+                .is_some_and(|download_host| download_host.eq_ignore_ascii_case(host))
+        })
+        && search.is_none_or(|text| {
+            download.url.to_lowercase().contains(&text)
+                || name(download).to_lowercase().contains(&text)
+        })
+}
+
+fn compare(key: &SortKey, a: &Download, b: &Download) -> Ordering {
+    let order = match key.by() {
+        SortBy::Unspecified => Ordering::Equal,
+        SortBy::Name => name(a).to_lowercase().cmp(&name(b).to_lowercase()),
+        SortBy::Size => a.get_total_size().cmp(&b.get_total_size()),
+        SortBy::Progress => a
+            .get_progress_percentage()
+            .total_cmp(&b.get_progress_percentage()),
+        SortBy::Speed => a.get_smoothed_speed().cmp(&b.get_smoothed_speed()),
+        SortBy::Status => convert_to_download_status_proto(&a.get_status())
+            .cmp(&convert_to_download_status_proto(&b.get_status())),
+        SortBy::Priority => convert_to_download_priority_proto(&a.priority)
+            .cmp(&convert_to_download_priority_proto(&b.priority)),
+        SortBy::DateAdded => a.date_added.cmp(&b.date_added),
+        // unfinished downloads after the finished ones
+        SortBy::DateFinished => match (a.date_finished, b.date_finished) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            (a, b) => b.is_some().cmp(&a.is_some()),
+        },
+    };
+    if key.descending {
+        order.reverse()
+    } else {
+        order
+    }
+}
+
+fn name(download: &Download) -> String {
+    download
+        .file_name
+        .as_ref()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Only the id and the fields in `fields` of `download`, all of them if empty
+fn mask(mut download: DownloadProto, fields: &[String]) -> DownloadProto {
+    if fields.is_empty() {
+        return download;
+    }
+    let mut masked = DownloadProto {
+        id: std::mem::take(&mut download.id),
+        ..Default::default()
+    };
+    for field in fields {
+        match field.as_str() {
+            "url" => masked.url = std::mem::take(&mut download.url),
+            "file" => masked.file = std::mem::take(&mut download.file),
+            "file_name" => masked.file_name = std::mem::take(&mut download.file_name),
+            "headers" => masked.headers = std::mem::take(&mut download.headers),
+            "referrer" => masked.referrer = download.referrer.take(),
+            "date_added" => masked.date_added = download.date_added.take(),
+            "active_time" => masked.active_time = download.active_time.take(),
+            "status" => masked.status = download.status,
+            "parts" => masked.parts = download.parts.take(),
+            "smoothed_speed" => masked.smoothed_speed = download.smoothed_speed,
+            "eta" => masked.eta = download.eta.take(),
+            "speed_history" => masked.speed_history = std::mem::take(&mut download.speed_history),
+            "priority" => masked.priority = download.priority,
+            "checksum" => masked.checksum = download.checksum.take(),
+            "category" => masked.category = download.category.take(),
+            "date_finished" => masked.date_finished = download.date_finished.take(),
+            "total_bytes" => masked.total_bytes = download.total_bytes,
+            "bytes_downloaded" => masked.bytes_downloaded = download.bytes_downloaded,
+            "connections" => masked.connections = download.connections,
+            "speed_limit" => masked.speed_limit = download.speed_limit,
+            _ => {}
+        }
+    }
+    masked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use download_engine::{
+        download_config::DownloadConfig,
+        types::{DownloadPriority, DownloadRequest},
+    };
+    use prost_types::FieldMask;
+
+    fn download(url: &str, file_name: &str) -> Download {
+        Download::new(
+            DownloadRequest {
+                url: url.into(),
+                file_dir: "/downloads".into(),
+                file_name: Some(file_name.into()),
+                referrer: None,
+                headers: Some(vec!["Cookie: a=b".into()]),
+                priority: DownloadPriority::Normal,
+                checksum: None,
+                category: None,
+            },
+            &DownloadConfig::default(),
+        )
+    }
+
+    #[test]
+    fn filters_sorts_and_pages() {
+        let downloads = [
+            download("https://example.com/b.zip", "b.zip"),
+            download("https://other.org/movie.mkv", "movie.mkv"),
+            download("https://EXAMPLE.com/a.zip", "a.zip"),
+            download("https://example.com/c.iso", "c.iso"),
+        ];
+        let query = GetDownloads {
+            host: Some("example.com".into()),
+            search: Some(".ZIP".into()),
+            ..Default::default()
+        };
+        let list = list_downloads(&downloads, &query).unwrap();
+        let names: Vec<_> = list.list.iter().map(|d| d.file_name.as_str()).collect();
+        assert_eq!(names, ["b.zip", "a.zip"]);
+        assert_eq!(list.total, 2);
+
+        let mut query = GetDownloads {
+            sort: vec![SortKey {
+                by: SortBy::Name as i32,
+                descending: true,
+            }],
+            limit: 2,
+            fields: Some(FieldMask {
+                paths: vec!["file_name".into()],
+            }),
+            ..Default::default()
+        };
+        let first = list_downloads(&downloads, &query).unwrap();
+        let names: Vec<_> = first.list.iter().map(|d| d.file_name.as_str()).collect();
+        assert_eq!(names, ["movie.mkv", "c.iso"]);
+        assert!(
+            first
+                .list
+                .iter()
+                .all(|d| d.url.is_empty() && d.headers.is_empty())
+        );
+        assert_eq!(first.total, 4);
+
+        query.cursor = first.next_cursor;
+        let second = list_downloads(&downloads, &query).unwrap();
+        let names: Vec<_> = second.list.iter().map(|d| d.file_name.as_str()).collect();
+        assert_eq!(names, ["b.zip", "a.zip"]);
+        assert_eq!(second.next_cursor, None);
+
+        query.fields = Some(FieldMask {
+            paths: vec!["size".into()],
+        });
+        assert!(list_downloads(&downloads, &query).is_err());
+    }
+}
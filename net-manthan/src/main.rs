@@ -1,22 +1,45 @@
-use std::{path::PathBuf, time::Duration};
+use std::{path::PathBuf, str::FromStr, time::Duration};
 
-use crate::pretty_print_downloads::pretty_print_downloads;
+use crate::pretty_print_downloads::{pretty_print_downloads, pretty_print_stats};
+use categories::{default_categories, parse_category};
 use clap::{ArgAction, Parser};
-use download_engine::{download_config::DownloadConfig, types::DownloadRequest};
+use download_db_manager::default_database_file;
+use download_engine::category::Category;
+use download_engine::{
+    download_config::DownloadConfig,
+    filename_policy::FilenamePolicy,
+    types::{DownloadPriority, DownloadRequest, DownloadStatus},
+};
 use download_manager::DownloadManager;
-use net_manthan_config::NetManthanConfig;
-use tokio::{self, time::sleep};
-use tracing::{Level, debug, error, info};
+use hooks::Hooks;
+use net_manthan_config::{
+    AutoResume, DEFAULT_MAX_CONCURRENT_DOWNLOADS, DuplicatePolicy, NetManthanConfig,
+};
+use scheduler::{Schedule, parse_rate};
+use tokio::{self, time::interval};
+use tracing::{Level, debug, error, info, warn};
 use utils::{
-    conversion::{convert_from_download_proto, convert_to_download_req_proto},
+    conversion::convert_to_download_req_proto,
     logging::{self, Component, LogConfig},
-    rpc::{NativeRpcSettings, RpcConfig, client::send_rpc_request, server::RpcServer},
-    rpc_types::rpc_request::Request,
+    rpc::{
+        NativeRpcSettings, RpcConfig, RpcSettings, SECRET_ENV,
+        client::{NativeRpcClient, apply_event, send_rpc_request},
+        default_native_address,
+        server::RpcServer,
+    },
+    rpc_types::{
+        ErrorCode, GetStats, PurgeDownloads, rpc_request::Request, rpc_response::Response,
+    },
 };
 
+mod categories;
+mod download_db_manager;
 mod download_manager;
+mod hooks;
+mod listing;
 mod net_manthan_config;
 mod pretty_print_downloads;
+mod scheduler;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -29,30 +52,173 @@ pub struct Cli {
     #[arg(short = 'o', long = "out", value_name = "FILE")]
     out: Option<String>,
 
+    /// Replace non-ASCII characters in filenames with ASCII look-alikes
+    #[arg(long = "transliterate-filenames", action = ArgAction::SetTrue)]
+    transliterate_filenames: bool,
+
     /// Download a file using N connections
     #[arg(short = 's', long = "split", value_name = "N", default_value = "10")]
     split: usize,
 
+    /// Maximum number of downloads running at the same time, the rest are queued
+    #[arg(
+        short = 'j',
+        long = "max-concurrent-downloads",
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..),
+        default_value_t = DEFAULT_MAX_CONCURRENT_DOWNLOADS as u64
+    )]
+    max_concurrent_downloads: u64,
+
+    /// Maximum number of connections across all downloads, 0 for unlimited
+    #[arg(
+        long = "max-overall-connections",
+        value_name = "N",
+        default_value = "0"
+    )]
+    max_overall_connections: usize,
+
+    /// Maximum number of connections to one host across all downloads, 0 for unlimited
+    #[arg(
+        long = "max-connections-per-host",
+        value_name = "N",
+        default_value = "16"
+    )]
+    max_connections_per_host: usize,
+
+    /// Connection limit for a specific host, overrides --max-connections-per-host,
+    /// can be repeated
+    #[arg(long = "host-connection-limit", value_name = "HOST=N",
+          value_parser = parse_host_limit, action = ArgAction::Append)]
+    host_connection_limits: Vec<(String, usize)>,
+
+    /// Overall download speed limit in bytes per second (K, M and G suffixes
+    /// allowed), 0 for unlimited
+    #[arg(long = "max-overall-download-limit", value_name = "SPEED",
+          value_parser = parse_rate, default_value = "0")]
+    max_overall_download_limit: u64,
+
+    /// Scheduler rule, can be repeated: "run [DAYS] HH:MM-HH:MM",
+    /// "limit RATE [DAYS] HH:MM-HH:MM" or "start ID YYYY-MM-DDTHH:MM"
+    #[arg(long = "schedule", value_name = "RULE", value_parser = Schedule::from_str,
+          action = ArgAction::Append)]
+    schedules: Vec<Schedule>,
+
+    /// Download category, can be repeated:
+    /// "NAME [dir:PATH] [ext:EXT,..] [mime:TYPE,..] [host:HOST,..] [url:PATTERN]
+    /// [connections:N] [limit:RATE]"
+    #[arg(long = "category", value_name = "CATEGORY", value_parser = parse_category,
+          action = ArgAction::Append)]
+    categories: Vec<Category>,
+
+    /// Sort downloads into the built-in categories (Video, Audio, Images, ...)
+    /// after the ones given with --category
+    #[arg(long = "default-categories", action = ArgAction::SetTrue)]
+    default_categories: bool,
+
     /// Stay running even if all task at hand are done
     #[arg(long = "daemon", action = ArgAction::SetTrue)]
     daemon: bool,
 
+    /// Unfinished downloads to resume when the daemon starts, "active" only
+    /// resumes the ones that were running when it stopped
+    #[arg(long = "auto-resume", value_name = "WHICH",
+          value_parser = ["all", "active", "none"],
+          default_value = "none")]
+    auto_resume: String,
+
+    /// What to do when adding a download that is already in the list
+    #[arg(long = "duplicate-policy", value_name = "POLICY",
+          value_parser = ["reject", "existing", "rename", "restart"],
+          default_value = "existing")]
+    duplicate_policy: String,
+
+    /// Command to run when a download starts, it gets the download id, the file
+    /// path and the url as arguments
+    #[arg(long = "on-download-start", value_name = "COMMAND")]
+    on_download_start: Option<String>,
+
+    /// Command to run when a download completes
+    #[arg(long = "on-download-complete", value_name = "COMMAND")]
+    on_download_complete: Option<String>,
+
+    /// Command to run when a download fails
+    #[arg(long = "on-download-error", value_name = "COMMAND")]
+    on_download_error: Option<String>,
+
+    /// Command to run when a download is paused
+    #[arg(long = "on-download-pause", value_name = "COMMAND")]
+    on_download_pause: Option<String>,
+
+    /// Purge finished downloads from the list after this many days, 0 keeps them
+    #[arg(long = "keep-history-days", value_name = "DAYS", default_value = "0")]
+    keep_history_days: u64,
+
+    /// Number of finished downloads to keep in the list, 0 for no limit
+    #[arg(long = "max-history", value_name = "N", default_value = "0")]
+    max_history: usize,
+
+    /// Remove all finished downloads from the list, they still count in --stats
+    #[arg(long = "purge", action = ArgAction::SetTrue)]
+    purge: bool,
+
+    /// Print download statistics
+    #[arg(long = "stats", action = ArgAction::SetTrue)]
+    stats: bool,
+
+    /// Seconds a hook command may run before it is killed
+    #[arg(long = "hook-timeout", value_name = "SEC", default_value = "60")]
+    hook_timeout: u64,
+
     /// Enable JSON-RPC/PROTOBUFF-RPC server
     #[arg(long = "enable-rpc", action = ArgAction::SetTrue)]
     enable_rpc: bool,
 
+    /// Protocol of the RPC server, json speaks aria2's JSON-RPC
+    #[arg(long = "rpc-protocol", value_name = "PROTOCOL", value_parser = ["grpc", "json"],
+          default_value = "grpc")]
+    rpc_protocol: String,
+
+    /// Let web pages from any origin call the JSON-RPC server
+    #[arg(long = "rpc-allow-origin-all", action = ArgAction::SetTrue)]
+    rpc_allow_origin_all: bool,
+
     /// Specify port for RPC server
     #[arg(long = "rpc-listen-port", value_name = "PORT", default_value = "6800")]
     rpc_port: u16,
 
-    /// Set RPC secret authorization token
+    /// Accept RPC connections on all network interfaces, not only localhost
+    #[arg(long = "rpc-listen-all", action = ArgAction::SetTrue)]
+    rpc_listen_all: bool,
+
+    /// Set RPC secret authorization token, NET_MANTHAN_RPC_SECRET if unset. Clients
+    /// on every transport have to present it
     #[arg(long = "rpc-secret", value_name = "TOKEN")]
     rpc_secret: Option<String>,
 
+    /// Socket (a pipe name on Windows) the local clients talk to the daemon over,
+    /// net-manthan.sock in $XDG_RUNTIME_DIR by default
+    #[arg(long = "rpc-socket", value_name = "PATH")]
+    rpc_socket: Option<String>,
+
+    /// Let every local user connect to the socket
+    #[arg(long = "rpc-allow-all-users", action = ArgAction::SetTrue)]
+    rpc_allow_all_users: bool,
+
+    /// uid of another user allowed to connect to the socket, can be repeated
+    #[arg(long = "rpc-allow-user", value_name = "UID", action = ArgAction::Append)]
+    rpc_allowed_users: Vec<u32>,
+
     /// Log file
     #[arg(short = 'l', long = "log", value_name = "LOG")]
     log: Option<String>,
 
+    /// Database file downloads and their progress are saved in, a daemon defaults
+    /// to net-manthan/net-manthan.db in $XDG_DATA_HOME (~/.local/share), one-off
+    /// downloads aren't saved without it
+    #[arg(long = "database", value_name = "FILE")]
+    database: Option<String>,
+
     /// Set console log level
     #[arg(long = "console-log-level", value_name = "LEVEL",
           value_parser = ["trace", "debug", "info", "warn", "error"],
@@ -67,19 +233,81 @@ pub struct Cli {
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
+    // the secret guards every RPC transport, the native one included
+    let rpc_secret = cli
+        .rpc_secret
+        .clone()
+        .or_else(|| std::env::var(SECRET_ENV).ok())
+        .unwrap_or_default();
+    let remote_rpc_settings = RpcSettings {
+        listen_all: cli.rpc_listen_all,
+        allow_origin_all: cli.rpc_allow_origin_all,
+        listen_port: cli.rpc_port,
+        secret: rpc_secret.clone(),
+    };
+    let native_rpc_settings = NativeRpcSettings {
+        address: cli
+            .rpc_socket
+            .clone()
+            .unwrap_or_else(default_native_address),
+        secret: rpc_secret,
+        allow_all_users: cli.rpc_allow_all_users,
+        allowed_users: cli.rpc_allowed_users.clone(),
+    };
     let net_manthan_config = NetManthanConfig {
-        download_dir: cli.dir,
         log_file: cli.log,
+        // one-off downloads stay out of the daemon's list unless asked for
+        database_file: cli
+            .database
+            .or_else(|| cli.daemon.then(default_database_file)),
         log_level: cli.log_level,
-        download_config: DownloadConfig::default(),
-        max_concurrent_downloads: 10,
-        rpc_config: RpcConfig::Native(NativeRpcSettings {
-            address: "/tmp/net-manthan-ipc".into(),
-            allow_all_users: true,
-            secret: "".into(),
-        }),
+        download_config: DownloadConfig {
+            connections_per_server: cli.split.max(1),
+            download_dir: cli.dir.map(PathBuf::from).unwrap_or("/tmp/".into()),
+            categories: match cli.default_categories {
+                true => [cli.categories, default_categories()].concat(),
+                false => cli.categories,
+            },
+            filename_policy: FilenamePolicy {
+                transliterate: cli.transliterate_filenames,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        max_concurrent_downloads: cli.max_concurrent_downloads as usize,
+        max_overall_connections: cli.max_overall_connections,
+        max_connections_per_host: cli.max_connections_per_host,
+        host_connection_limits: cli.host_connection_limits.into_iter().collect(),
+        max_overall_download_limit: cli.max_overall_download_limit,
+        schedules: cli.schedules,
+        rpc_config: RpcConfig::Native(native_rpc_settings.clone()),
+        remote_rpc_config: match (cli.enable_rpc, &cli.rpc_protocol[..]) {
+            (false, _) => RpcConfig::Disabled,
+            (true, "json") => RpcConfig::JsonRpc(remote_rpc_settings),
+            (true, _) => RpcConfig::Grpc(remote_rpc_settings),
+        },
         daemon: cli.daemon,
-        ..Default::default()
+        auto_resume: match &cli.auto_resume[..] {
+            "all" => AutoResume::All,
+            "active" => AutoResume::Active,
+            _ => AutoResume::None,
+        },
+        duplicate_policy: match &cli.duplicate_policy[..] {
+            "reject" => DuplicatePolicy::Reject,
+            "rename" => DuplicatePolicy::Rename,
+            "restart" => DuplicatePolicy::Restart,
+            _ => DuplicatePolicy::ReturnExisting,
+        },
+        hooks: Hooks {
+            on_start: cli.on_download_start,
+            on_complete: cli.on_download_complete,
+            on_error: cli.on_download_error,
+            on_pause: cli.on_download_pause,
+            timeout: Duration::from_secs(cli.hook_timeout),
+            ..Default::default()
+        },
+        history_days: cli.keep_history_days,
+        max_history: cli.max_history,
     };
 
     // Initialize logging
@@ -87,6 +315,7 @@ async fn main() {
         component: Component::NetManthan,
         log_dir: net_manthan_config
             .log_file
+            .as_ref()
             .map(PathBuf::from)
             .unwrap_or(".dev/logs".into()),
         silent_deps: vec!["hyper_util".into(), "mio".into()],
@@ -113,11 +342,16 @@ async fn main() {
         }
     }
 
-    let mut manager_handle = DownloadManager::new();
+    let manager_handle = DownloadManager::start(&net_manthan_config);
 
     // if ipc is Disable it will be handled in the server only
     let mut ipc_server = RpcServer::new(&net_manthan_config.rpc_config, manager_handle.clone());
     ipc_server.start().await;
+    let mut remote_rpc_server = RpcServer::new(
+        &net_manthan_config.remote_rpc_config,
+        manager_handle.clone(),
+    );
+    remote_rpc_server.start().await;
 
     for url in cli.urls {
         match
@@ -136,63 +370,135 @@ async fn main() {
         //         headers: None,
         //     }))
         //     .await
-        send_rpc_request(&NativeRpcSettings {
-            address: "/tmp/net-manthan-ipc".into(),
-            allow_all_users: true,
-            secret: "".into(),
-        }, Request::AddDownload(convert_to_download_req_proto(DownloadRequest {
+        send_rpc_request(&native_rpc_settings, Request::AddDownload(convert_to_download_req_proto(DownloadRequest {
             url,
-            file_dir: (&net_manthan_config.download_dir)
-                .clone()
-                .unwrap_or("/tmp/".into())
-                .into(),
-            file_name: match &cli.out {
-                Some(out) => Some(out.into()),
-                None => None,
-            },
+            // left to the category rules and the download directory
+            file_dir: PathBuf::new(),
+            file_name: cli.out.as_ref().map(|out| out.into()),
             referrer: None,
             headers: None,
-        }))).await
+            priority: DownloadPriority::default(),
+            checksum: None,
+            category: None,
+        }))).await.map(|response| response.response)
         {
-            Ok(res) => {
-                info!("Downlaod started for {:?}", res);
+            Ok(Some(Response::DownloadCreated(created))) => {
+                info!("Download added as {}", created.id);
+            }
+            Ok(Some(Response::Error(e))) if e.code() == ErrorCode::AlreadyExists => {
+                warn!(
+                    "Download is already in the list as {}",
+                    e.details.get("existing_id").map_or("?", String::as_str)
+                );
             }
+            Ok(Some(Response::Error(e))) => error!("Failed to add download: {}", e),
+            Ok(response) => error!("Failed to add download: {:?}", response),
             Err(err) => {
-                error!("Something went wrong when adding download: {}", err);
+                error!("Failed to add download: {}", err);
             }
         }
     }
 
-    let mut downloads: Vec<download_engine::Download>;
-
-    loop {
-        sleep(Duration::from_millis(500)).await;
-        downloads = match manager_handle.get_downloads().await {
-            Ok(downloads) => downloads
-                .iter()
-                .map(|d| convert_from_download_proto(d))
-                .collect(),
-            Err(e) => {
-                error!("Failed to get downloads: {}", e);
-                continue;
+    if cli.purge {
+        match send_rpc_request(
+            &native_rpc_settings,
+            Request::PurgeDownloads(PurgeDownloads { ids: Vec::new() }),
+        )
+        .await
+        .map(|response| response.response)
+        {
+            Ok(Some(Response::DownloadIds(purged))) => {
+                info!("Purged {} finished downloads", purged.ids.len())
             }
-        };
+            Ok(response) => error!("Failed to purge downloads: {:?}", response),
+            Err(err) => error!("Failed to purge downloads: {}", err),
+        }
+    }
+    if cli.stats {
+        match send_rpc_request(
+            &native_rpc_settings,
+            Request::GetStats(GetStats { days: None }),
+        )
+        .await
+        .map(|response| response.response)
+        {
+            Ok(Some(Response::Stats(stats))) => pretty_print_stats(&stats),
+            Ok(response) => error!("Failed to get stats: {:?}", response),
+            Err(err) => error!("Failed to get stats: {}", err),
+        }
+    }
 
-        pretty_print_downloads(&mut downloads, true);
+    show_progress(&native_rpc_settings, net_manthan_config.daemon).await;
+    // the complete and error hooks of the last downloads may still be running
+    net_manthan_config.hooks.wait().await;
 
-        if downloads
-            .iter()
-            .all(|download| match download.get_status() {
-                download_engine::types::DownloadStatus::Complete => true,
-                _ => false,
+    ipc_server.shutdown().await;
+    remote_rpc_server.shutdown().await;
+}
+
+/// Print the downloads as the daemon pushes their changes, until all of them
+/// are done unless running as a daemon
+async fn show_progress(settings: &NativeRpcSettings, daemon: bool) {
+    let subscribed = match NativeRpcClient::connect(settings).await {
+        Ok(client) => client.subscribe(Vec::new(), Vec::new()).await,
+        Err(e) => Err(e),
+    };
+    let (mut downloads, mut events) = match subscribed {
+        Ok(subscribed) => subscribed,
+        Err(e) => {
+            error!("Failed to follow the downloads: {}", e);
+            if daemon {
+                // nothing to show, keep serving until stopped
+                let _ = tokio::signal::ctrl_c().await;
+            }
+            return;
+        }
+    };
+
+    let mut redraw = interval(Duration::from_millis(500));
+    let mut changed = true;
+    loop {
+        // failed, cancelled and paused (e.g. restored from the database) downloads are
+        // done too, waiting for them would hang forever
+        if !daemon
+            && downloads.iter().all(|download| {
+                matches!(
+                    download.get_status(),
+                    DownloadStatus::Complete
+                        | DownloadStatus::Failed
+                        | DownloadStatus::Cancelled
+                        | DownloadStatus::Paused
+                )
             })
-            && !net_manthan_config.daemon
         {
             break;
         }
+
+        tokio::select! {
+            event = events.next() => {
+                let Some(event) = event else {
+                    error!("Connection to the daemon closed");
+                    break;
+                };
+                apply_event(&mut downloads, &event);
+                changed = true;
+            }
+            _ = redraw.tick(), if changed => {
+                pretty_print_downloads(&mut downloads, true);
+                changed = false;
+            }
+        }
     }
 
     pretty_print_downloads(&mut downloads, false);
+}
 
-    ipc_server.shutdown().await;
+fn parse_host_limit(value: &str) -> Result<(String, usize), String> {
+    let (host, limit) = value
+        .split_once('=')
+        .ok_or(format!("expected HOST=N, got \"{}\"", value))?;
+    let limit = limit
+        .parse()
+        .map_err(|_| format!("invalid connection limit \"{}\"", limit))?;
+    Ok((host.to_lowercase(), limit))
 }
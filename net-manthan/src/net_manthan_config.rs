@@ -1,23 +1,75 @@
+use crate::{hooks::Hooks, scheduler::Schedule};
 use download_engine::download_config::DownloadConfig;
+use std::collections::HashMap;
 use utils::rpc::RpcConfig;
 
+/// Downloads running at the same time unless configured otherwise
+pub const DEFAULT_MAX_CONCURRENT_DOWNLOADS: usize = 10;
+
+/// Which unfinished downloads a daemon puts back into the queue when it starts
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum AutoResume {
+    /// everything that didn't finish, including downloads paused by the user
+    All,
+    /// downloads that were running when it stopped, the rest are restored as Paused
+    Active,
+    /// restore everything as Paused
+    #[default]
+    None,
+}
+
+/// What AddDownload does with a download that is already in the list, see
+/// `DownloadManager::find_duplicate` for what counts as one
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum DuplicatePolicy {
+    /// refuse the new download
+    Reject,
+    /// answer with the id of the existing download
+    #[default]
+    ReturnExisting,
+    /// add it anyway under a numbered file name
+    Rename,
+    /// start the existing download over and answer with its id
+    Restart,
+}
+
 #[derive(Debug, Clone)]
 pub struct NetManthanConfig {
     /// whether to close after downloads are done
     pub daemon: bool,
+    /// downloads to resume when the daemon starts
+    pub auto_resume: AutoResume,
     /// configuration for RPC
     pub rpc_config: RpcConfig,
-    /// default download directory
-    pub download_dir: Option<String>,
+    /// network RPC server run next to `rpc_config`, which local clients use
+    pub remote_rpc_config: RpcConfig,
     /// where to store logs
     pub log_file: Option<String>,
+    /// sqlite database downloads are saved in, None keeps them in memory only
+    pub database_file: Option<String>,
     /// log level in tracing
     pub log_level: String,
     /// number of downloads that can run cocurrently (not threads)
-    #[allow(unused)]
     pub max_concurrent_downloads: usize,
+    /// connections shared by all running downloads, 0 means unlimited
+    pub max_overall_connections: usize,
+    /// connections all downloads from one host may open together, 0 means unlimited
+    pub max_connections_per_host: usize,
+    /// max_connections_per_host for specific hosts, e.g. a mirror that allows more
+    pub host_connection_limits: HashMap<String, usize>,
+    /// overall speed limit in bytes per second, 0 means unlimited
+    pub max_overall_download_limit: u64,
+    /// time based rules for running the queue and limiting the speed
+    pub schedules: Vec<Schedule>,
+    /// policy for duplicate downloads whose request doesn't pick one
+    pub duplicate_policy: DuplicatePolicy,
+    /// commands run on download events
+    pub hooks: Hooks,
+    /// finished downloads are purged from the list after this many days, 0 keeps them
+    pub history_days: u64,
+    /// finished downloads kept in the list, the oldest are purged first, 0 for no limit
+    pub max_history: usize,
     /// config for single download - comes from download_engine
-    #[allow(unused)]
     pub download_config: DownloadConfig,
 }
 
@@ -25,11 +77,22 @@ impl Default for NetManthanConfig {
     fn default() -> Self {
         Self {
             daemon: false,
+            auto_resume: AutoResume::default(),
             rpc_config: RpcConfig::Disabled,
-            download_dir: None,
+            remote_rpc_config: RpcConfig::Disabled,
             log_file: None,
+            database_file: None,
             log_level: "info".into(),
-            max_concurrent_downloads: 10,
+            max_concurrent_downloads: DEFAULT_MAX_CONCURRENT_DOWNLOADS,
+            max_overall_connections: 0,
+            max_connections_per_host: 16,
+            host_connection_limits: HashMap::new(),
+            max_overall_download_limit: 0,
+            schedules: Vec::new(),
+            duplicate_policy: DuplicatePolicy::default(),
+            hooks: Hooks::default(),
+            history_days: 0,
+            max_history: 0,
             download_config: DownloadConfig::default(),
         }
     }
@@ -6,13 +6,14 @@ use download_engine::{
     types::DownloadStatus,
     utils::{format_bytes, format_duration},
 };
+use utils::rpc_types::Stats;
 
 const TAB_SPACE: &str = "  ";
 const CLEAR_LINE: &str = "\x1B[K";
 const MOVE_UP: &str = "\x1B[1A";
 
 /// Prints the progress of a vector of downloads in pretty format in terminal
-pub fn pretty_print_downloads(downloads: &mut Vec<Download>, clear_after_print: bool) {
+pub fn pretty_print_downloads(downloads: &mut [Download], clear_after_print: bool) {
     // there are 4 goals here
     // 1. printing the downloads in a pretty way spaced 2 lines away from the top
     // 2. the logs should keep flowing from the top while the downloads keep updating
@@ -63,20 +64,16 @@ pub fn pretty_print_downloads(downloads: &mut Vec<Download>, clear_after_print:
         }
         .to_string();
         let current_speed = match download.get_status() {
-            DownloadStatus::Downloading => {
-                format!("{}", download.get_formatted_current_speed()).green()
-            }
-            _ => format!("{}", download.get_formatted_average_speed()).normal(),
+            DownloadStatus::Downloading => download.get_formatted_smoothed_speed().green(),
+            _ => download.get_formatted_average_speed().normal(),
         };
         let eta = if matches!(download.get_status(), DownloadStatus::Complete) {
             "".into()
-        } else if download.get_current_speed() == 0 {
-            "∞".to_string()
         } else {
-            format_duration(
-                (download.get_total_size() - download.get_bytes_downloaded())
-                    / (download.get_current_speed() as u64),
-            )
+            match download.get_eta() {
+                Some(eta) => format_duration(eta.num_seconds().max(0) as u64),
+                None => "∞".to_string(),
+            }
         };
         let time_elapsed = if download.active_time.as_seconds_f64() < 0. {
             format_duration(0)
@@ -114,7 +111,7 @@ pub fn pretty_print_downloads(downloads: &mut Vec<Download>, clear_after_print:
 
     println!("{CLEAR_LINE}");
     if clear_after_print {
-        print!("{}", format!("{MOVE_UP}").repeat((downloads.len() * 4) + 2));
+        print!("{}", MOVE_UP.repeat((downloads.len() * 4) + 2));
     }
 }
 
@@ -122,12 +119,54 @@ fn print_progress_string(progress: f64, width: usize) {
     let progress = if progress == 100.0 {
         100.0
     } else {
-        progress % (100 as f64)
+        progress % 100.0
     };
-    let green_bars = ((width as f64) * (progress / (100 as f64))).round() as usize;
+    let green_bars = ((width as f64) * (progress / 100.0)).round() as usize;
     println!(
         "{TAB_SPACE}{}{}",
         "━".repeat(green_bars).green(),
         "━".repeat(width - green_bars).bright_black()
     )
 }
+
+/// Prints download statistics, per host and per day tables included
+pub fn pretty_print_stats(stats: &Stats) {
+    println!("{CLEAR_LINE}");
+    println!(
+        "{}{} downloads, {} active, {} paused, {} downloaded",
+        TAB_SPACE,
+        stats.total_downloads,
+        stats.active_downloads,
+        stats.paused_downloads,
+        format_bytes(stats.total_downloaded_bytes)
+    );
+    println!(
+        "{}History: {} succeeded, {} failed, {} cancelled, {} at {}/s on average",
+        TAB_SPACE,
+        stats.succeeded.to_string().green(),
+        stats.failed.to_string().red(),
+        stats.cancelled,
+        format_bytes(stats.history_bytes),
+        format_bytes(stats.average_speed)
+    );
+
+    for (title, buckets) in [("Per day", &stats.per_day), ("Per host", &stats.per_host)] {
+        if buckets.is_empty() {
+            continue;
+        }
+        println!("{CLEAR_LINE}");
+        println!("{}{}", TAB_SPACE, title.bold());
+        for bucket in buckets {
+            println!(
+                "{}{}{:<40} {:>12} {:>5} ok {:>5} failed {:>5} cancelled",
+                TAB_SPACE,
+                TAB_SPACE,
+                bucket.key,
+                format_bytes(bucket.bytes),
+                bucket.succeeded,
+                bucket.failed,
+                bucket.cancelled
+            );
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDateTime, NaiveTime, TimeZone, Weekday};
+use std::{fmt, str::FromStr};
+
+/// A rule the scheduler applies, written in a small cron-like syntax:
+///
+/// - `run [DAYS] HH:MM-HH:MM` downloads only run inside the window
+/// - `limit RATE [DAYS] HH:MM-HH:MM` caps the overall speed inside the window
+/// - `start ID YYYY-MM-DDTHH:MM` starts a download once at the given local time
+///
+/// DAYS is a comma separated list of days or day ranges (`mon-fri`, `sat,sun`),
+/// every day if left out. RATE is in bytes per second with an optional K, M or G
+/// suffix. A window ending before it starts runs past midnight.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Schedule {
+    /// queued downloads only start inside one of the run windows, running
+    /// ones are queued again when the last window closes unless they can't resume
+    Run(TimeWindow),
+    /// overall speed limit in bytes per second inside the window
+    SpeedLimit(u64, TimeWindow),
+    /// start a download at the given time, even outside run windows
+    StartDownload(String, DateTime<Local>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeWindow {
+    /// days the window starts on, empty means every day
+    pub days: Vec<Weekday>,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+/// What the rules want at a given time
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduleState {
+    /// whether queued downloads may run
+    pub queue_open: bool,
+    /// overall speed limit in bytes per second, 0 means unlimited
+    pub speed_limit: u64,
+}
+
+impl Default for ScheduleState {
+    fn default() -> Self {
+        Self {
+            queue_open: true,
+            speed_limit: 0,
+        }
+    }
+}
+
+/// Evaluate the windowed rules at `now`, start rules are handled by `due_starts`
+pub fn schedule_state(schedules: &[Schedule], now: &DateTime<Local>) -> ScheduleState {
+    let mut run_windows = schedules
+        .iter()
+        .filter_map(|schedule| match schedule {
+            Schedule::Run(window) => Some(window),
+            _ => None,
+        })
+        .peekable();
+    // without run rules the queue is always open
+    let queue_open = run_windows.peek().is_none() || run_windows.any(|w| w.contains(now));
+
+    // overlapping limits, the strictest one wins
+    let speed_limit = schedules
+        .iter()
+        .filter_map(|schedule| match schedule {
+            Schedule::SpeedLimit(limit, window) if window.contains(now) => Some(*limit),
+            _ => None,
+        })
+        .min()
+        .unwrap_or(0);
+
+    ScheduleState {
+        queue_open,
+        speed_limit,
+    }
+}
+
+/// Remove the start rules that are due at `now` and return their download ids
+pub fn due_starts(schedules: &mut Vec<Schedule>, now: &DateTime<Local>) -> Vec<String> {
+    let mut due = Vec::new();
+    schedules.retain(|schedule| match schedule {
+        Schedule::StartDownload(id, at) if at <= now => {
+            due.push(id.clone());
+            false
+        }
+        _ => true,
+    });
+    due
+}
+
+impl TimeWindow {
+    pub fn contains(&self, now: &DateTime<Local>) -> bool {
+        let time = now.time();
+        let today = now.weekday();
+        let on = |day: Weekday| self.days.is_empty() || self.days.contains(&day);
+
+        if self.start == self.end {
+            on(today)
+        } else if self.start < self.end {
+            on(today) && self.start <= time && time < self.end
+        } else {
+            // runs past midnight, the early hours belong to yesterday's window
+            (on(today) && time >= self.start) || (on(today.pred()) && time < self.end)
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        let tokens: Vec<&str> = rule.split_whitespace().collect();
+        match tokens.as_slice() {
+            ["run", window @ ..] => Ok(Schedule::Run(parse_window(window)?)),
+            ["limit", rate, window @ ..] => Ok(Schedule::SpeedLimit(
+                parse_rate(rate)?,
+                parse_window(window)?,
+            )),
+            ["start", id, at] => Ok(Schedule::StartDownload(id.to_string(), parse_datetime(at)?)),
+            _ => Err(format!("invalid schedule \"{}\"", rule)),
+        }
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Schedule::Run(window) => write!(f, "run {}", window),
+            Schedule::SpeedLimit(limit, window) => write!(f, "limit {} {}", limit, window),
+            Schedule::StartDownload(id, at) => {
+                write!(f, "start {} {}", id, at.format("%Y-%m-%dT%H:%M"))
+            }
+        }
+    }
+}
+
+impl fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.days.is_empty() {
+            let days: Vec<String> = self
+                .days
+                .iter()
+                .map(|day| day.to_string().to_lowercase())
+                .collect();
+            write!(f, "{} ", days.join(","))?;
+        }
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+fn parse_window(tokens: &[&str]) -> Result<TimeWindow, String> {
+    let (days, times) = match tokens {
+        [times] => (Vec::new(), *times),
+        [days, times] => (parse_days(days)?, *times),
+        _ => return Err("expected [DAYS] HH:MM-HH:MM".into()),
+    };
+    let (start, end) = times
+        .split_once('-')
+        .ok_or(format!("invalid time window \"{}\"", times))?;
+
+    Ok(TimeWindow {
+        days,
+        start: parse_time(start)?,
+        end: parse_time(end)?,
+    })
+}
+
+fn parse_days(days: &str) -> Result<Vec<Weekday>, String> {
+    let mut parsed = Vec::new();
+    for item in days.split(',') {
+        match item.split_once('-') {
+            Some((first, last)) => {
+                let (mut day, last) = (parse_day(first)?, parse_day(last)?);
+                // ranges may wrap around the week, e.g. fri-mon
+                while day != last {
+                    parsed.push(day);
+                    day = day.succ();
+                }
+                parsed.push(last);
+            }
+            None => parsed.push(parse_day(item)?),
+        }
+    }
+    parsed.dedup();
+    Ok(parsed)
+}
+
+fn parse_day(day: &str) -> Result<Weekday, String> {
+    day.parse::<Weekday>()
+        .map_err(|_| format!("invalid day \"{}\"", day))
+}
+
+fn parse_time(time: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(time, "%H:%M").map_err(|_| format!("invalid time \"{}\"", time))
+}
+
+/// Bytes per second with an optional K, M or G suffix, e.g. `512K`
+pub fn parse_rate(rate: &str) -> Result<u64, String> {
+    let (number, multiplier) = match rate.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&rate[..i], 1024),
+        Some((i, 'M' | 'm')) => (&rate[..i], 1024 * 1024),
+        Some((i, 'G' | 'g')) => (&rate[..i], 1024 * 1024 * 1024),
+        _ => (rate, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid rate \"{}\"", rate))
+}
+
+fn parse_datetime(at: &str) -> Result<DateTime<Local>, String> {
+    if let Ok(at) = DateTime::parse_from_rfc3339(at) {
+        return Ok(at.with_timezone(&Local));
+    }
+    let naive = NaiveDateTime::parse_from_str(at, "%Y-%m-%dT%H:%M")
+        .map_err(|_| format!("invalid date \"{}\"", at))?;
+    // a time skipped by a DST change starts as soon as the clocks have moved on
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .or_else(|| {
+            Local
+                .from_local_datetime(&(naive + Duration::hours(1)))
+                .earliest()
+        })
+        .ok_or(format!("invalid local time \"{}\"", at))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(datetime: &str) -> DateTime<Local> {
+        parse_datetime(datetime).unwrap()
+    }
+
+    #[test]
+    fn parses_rules() {
+        assert_eq!(
+            "run 01:00-07:00".parse::<Schedule>().unwrap().to_string(),
+            "run 01:00-07:00"
+        );
+        assert_eq!(
+            "limit 1M mon-fri 09:00-18:00"
+                .parse::<Schedule>()
+                .unwrap()
+                .to_string(),
+            "limit 1048576 mon,tue,wed,thu,fri 09:00-18:00"
+        );
+        assert_eq!(
+            "start abc 2026-01-02T03:04"
+                .parse::<Schedule>()
+                .unwrap()
+                .to_string(),
+            "start abc 2026-01-02T03:04"
+        );
+        assert!("run 25:00-07:00".parse::<Schedule>().is_err());
+        assert!("limit fast 01:00-02:00".parse::<Schedule>().is_err());
+        assert!(
+            "limit 99999999999999G 01:00-02:00"
+                .parse::<Schedule>()
+                .is_err()
+        );
+        assert!("stop 01:00-02:00".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn windows_past_midnight() {
+        // 2026-10-19 is a monday
+        let schedules = vec!["run mon 22:00-02:00".parse::<Schedule>().unwrap()];
+        assert!(!schedule_state(&schedules, &at("2026-10-19T21:59")).queue_open);
+        assert!(schedule_state(&schedules, &at("2026-10-19T23:00")).queue_open);
+        assert!(schedule_state(&schedules, &at("2026-10-20T01:30")).queue_open);
+        assert!(!schedule_state(&schedules, &at("2026-10-20T23:00")).queue_open);
+    }
+
+    #[test]
+    fn strictest_limit_wins() {
+        let schedules = vec![
+            "limit 1M 09:00-18:00".parse::<Schedule>().unwrap(),
+            "limit 512K sat,sun 12:00-13:00"
+                .parse::<Schedule>()
+                .unwrap(),
+        ];
+        // 2026-10-24 is a saturday
+        assert_eq!(
+            schedule_state(&schedules, &at("2026-10-24T12:30")),
+            ScheduleState {
+                queue_open: true,
+                speed_limit: 512 * 1024
+            }
+        );
+        assert_eq!(
+            schedule_state(&schedules, &at("2026-10-24T19:00")).speed_limit,
+            0
+        );
+    }
+
+    #[test]
+    fn start_rules_fire_once() {
+        let mut schedules = vec!["start abc 2026-10-19T01:00".parse::<Schedule>().unwrap()];
+        assert!(due_starts(&mut schedules, &at("2026-10-19T00:59")).is_empty());
+        assert_eq!(due_starts(&mut schedules, &at("2026-10-19T01:00")), ["abc"]);
+        assert!(schedules.is_empty());
+    }
+}
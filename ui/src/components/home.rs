@@ -1,61 +1,27 @@
 use std::path::Path;
 
 use crate::helpers::client::Client;
-use download_engine::Download;
-use gpui::{AsyncApp, ClipboardItem, IntoElement, WeakEntity, Window, div, prelude::*, rgb};
-use std::time::Duration;
+use download_engine::{Download, utils::format_duration};
+use gpui::{ClipboardItem, Entity, IntoElement, Window, div, prelude::*, rgb};
 use ui::{DefiniteLength, ParentElement, SharedString};
 
 pub struct Home {
-    downloads: Vec<Download>,
+    downloads: Entity<Vec<Download>>,
 }
 
 impl Home {
     pub fn new(cx: &mut Context<Home>) -> Self {
-        cx.spawn(|this: WeakEntity<Home>, cx: &mut AsyncApp| {
-            // Clone the app context before moving into async block
-            let mut app_context = cx.clone();
-            async move {
-                loop {
-                    println!("before hihihihihih");
-
-                    let downloads = Client::get_downloads(&mut app_context).await;
-                    let data = Some(downloads);
-
-                    // Schedule update on main thread
-                    app_context
-                        .update(|cx| {
-                            if let Some(strong_this) = this.upgrade() {
-                                strong_this.update(cx, |downloads, cx| {
-                                    match data {
-                                        Some(data) => {
-                                            downloads.downloads = data;
-                                        }
-                                        None => {}
-                                    }
-                                    cx.notify();
-                                });
-                            }
-                        })
-                        .ok(); // Handle potential error
-
-                    app_context
-                        .background_executor()
-                        .timer(Duration::from_millis(400))
-                        .await;
-                    println!("hihihihihi");
-                }
-            }
-        })
-        .detach();
-
-        Self { downloads: vec![] }
+        let downloads = cx.global::<Client>().downloads.clone();
+        // the client's subscription updates the downloads, redraw when it does
+        cx.observe(&downloads, |_, _, cx| cx.notify()).detach();
+        Self { downloads }
     }
 }
 
 impl Render for Home {
     fn render(&mut self, _window: &mut Window, cx: &mut Context<Self>) -> impl IntoElement {
-        div().children(self.downloads.iter().map(|download| {
+        let downloads = self.downloads.read(cx).clone();
+        div().children(downloads.iter().map(|download| {
             div()
                 .border_1()
                 .rounded_md()
@@ -113,6 +79,14 @@ impl Render for Home {
                         .flex()
                         .justify_between()
                         .child(div().child(format!("{:.2}%", download.get_progress_percentage())))
+                        .child(div().child(format!(
+                            "{}/s - {}",
+                            format_bytes(download.get_smoothed_speed() as u64),
+                            download
+                                .get_eta()
+                                .map(|eta| format_duration(eta.num_seconds().max(0) as u64))
+                                .unwrap_or("∞".into())
+                        )))
                         .child(div().child(format!(
                             "{}/{}",
                             format_bytes(download.get_bytes_downloaded()),
@@ -132,10 +106,29 @@ impl Render for Home {
                             ),
                         )),
                 )
+                .child(speed_graph(download.get_speed_history()))
         }))
     }
 }
 
+/// bar graph of the recent speed samples, scaled to the highest sample
+fn speed_graph(history: Vec<usize>) -> impl IntoElement {
+    let max_speed = history.iter().copied().max().unwrap_or(0).max(1);
+    div()
+        .mt_2()
+        .h_8()
+        .w_full()
+        .flex()
+        .items_end()
+        .gap_px()
+        .children(history.into_iter().map(|speed| {
+            div()
+                .w_1()
+                .bg(rgb(0xff00ff))
+                .h(DefiniteLength::Fraction(speed as f32 / max_speed as f32))
+        }))
+}
+
 pub fn format_bytes(bytes: u64) -> String {
     const UNITS: [&str; 9] = ["B", "KB", "MB", "GB", "TB", "PB", "EB", "ZB", "YB"];
     let mut size = bytes as f64;
@@ -1,31 +1,44 @@
 use download_engine::Download;
 use gpui::*;
 use gpui_tokio::Tokio;
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use utils::rpc::{NativeRpcSettings, client::NativeRpcClient};
+use tokio::sync::mpsc;
+use tracing::error;
+use utils::rpc::{
+    NativeRpcSettings,
+    client::{NativeRpcClient, apply_event},
+};
+use utils::rpc_types::DownloadEvent;
 
 pub enum Handle {
     Connecting,
-    Connected(Arc<Mutex<NativeRpcClient>>),
+    Connected(NativeRpcClient),
     Failed,
 }
 
 pub struct Client {
     pub handle: Entity<Handle>,
+    /// the downloads of the daemon, kept up to date by a subscription
+    pub downloads: Entity<Vec<Download>>,
+}
+
+/// What the subscription of `follow_downloads` reports
+enum DownloadsUpdate {
+    All(Vec<Download>),
+    Event(DownloadEvent),
 }
 
 impl Client {
     pub fn init(app: &mut App, settings: NativeRpcSettings) {
         let handle = app.new(|_| Handle::Connecting);
-        app.set_global(Client { handle });
+        let downloads = app.new(|_| Vec::new());
+        app.set_global(Client { handle, downloads });
         app.spawn(move |app: &mut AsyncApp| {
             let mut app = app.clone();
             async move {
                 let thread_handle = Tokio::spawn(&app, async move {
                     let client = NativeRpcClient::connect(&settings).await;
                     match client {
-                        Ok(client) => Handle::Connected(Arc::new(Mutex::new(client))),
+                        Ok(client) => Handle::Connected(client),
                         Err(_) => Handle::Failed,
                     }
                 });
@@ -37,6 +50,10 @@ impl Client {
                     Err(_) => Handle::Failed,
                 };
 
+                let rpc_client = match &handle {
+                    Handle::Connected(rpc_client) => Some(rpc_client.clone()),
+                    _ => None,
+                };
                 Client::update(
                     |this, cx| {
                         this.handle.update(cx, |h, _| {
@@ -45,47 +62,52 @@ impl Client {
                     },
                     &mut app,
                 );
+                if let Some(rpc_client) = rpc_client {
+                    Client::follow_downloads(rpc_client, &mut app).await;
+                }
             }
         })
         .detach();
     }
 
-    pub async fn get_downloads(cx: &mut AsyncApp) -> Vec<Download> {
-        let rpc_client = cx.try_read_global(|client: &Client, cx: &App| {
-            let handle = client.handle.read(cx);
-            if let Handle::Connected(rpc_client) = handle {
-                return Some(rpc_client.clone());
-            } else {
-                return None;
+    /// Keep `downloads` up to date with the events the daemon pushes, until the
+    /// connection to it closes
+    async fn follow_downloads(rpc_client: NativeRpcClient, app: &mut AsyncApp) {
+        let (sender, mut updates) = mpsc::unbounded_channel();
+        // the subscription lives on the tokio runtime, its events are applied here
+        let cx = app.clone();
+        let subscription = Tokio::spawn(&cx, async move {
+            let (downloads, mut events) = match rpc_client.subscribe(Vec::new(), Vec::new()).await {
+                Ok(subscribed) => subscribed,
+                Err(e) => {
+                    error!("Failed to follow the downloads: {}", e);
+                    return;
+                }
+            };
+            let _ = sender.send(DownloadsUpdate::All(downloads));
+            while let Some(event) = events.next().await {
+                if sender.send(DownloadsUpdate::Event(event)).is_err() {
+                    break;
+                }
             }
         });
 
-        match rpc_client.unwrap_or(None) {
-            Some(rpc_client) => {
-                let cx = cx.clone();
-                let thread_handle = Tokio::spawn(&cx, {
-                    let client = rpc_client.clone();
-                    async move {
-                        let mut client = client.lock().await;
-                        match client.get_downloads().await {
-                            Ok(downloads) => Some(downloads),
-                            Err(_) => None,
+        while let Some(update) = updates.recv().await {
+            Client::update(
+                |this, cx| {
+                    this.downloads.update(cx, |downloads, cx| {
+                        match update {
+                            DownloadsUpdate::All(all) => *downloads = all,
+                            DownloadsUpdate::Event(event) => apply_event(downloads, &event),
                         }
-                    }
-                });
-
-                match thread_handle {
-                    Ok(h) => match h.await {
-                        Ok(d) => return d.unwrap_or(vec![]),
-                        Err(_) => {}
-                    },
-                    Err(_) => {}
-                }
-            }
-            None => {}
+                        cx.notify();
+                    })
+                },
+                app,
+            );
         }
-
-        vec![]
+        // dropping the task would stop the subscription, keep it until it ends
+        drop(subscription);
     }
 
     pub fn update(f: impl FnOnce(&mut Self, &mut App), cx: &mut AsyncApp) {
@@ -10,7 +10,7 @@ use gpui::{App, Application, KeyBinding, prelude::*};
 use std::process::Command;
 use tracing::info;
 use utils::logging::{Component, get_ui_config, init_logging};
-use utils::rpc::NativeRpcSettings;
+use utils::rpc::{NativeRpcSettings, default_native_address};
 
 pub mod components;
 pub mod helpers;
@@ -71,9 +71,10 @@ fn main() {
                 Client::init(
                     app,
                     NativeRpcSettings {
-                        address: "/tmp/net-manthan-ipc".into(),
-                        allow_all_users: true,
+                        address: default_native_address(),
                         secret: "".into(),
+                        allow_all_users: false,
+                        allowed_users: Vec::new(),
                     },
                 );
                 app.new(|cx| NetManthanUi::new(window, cx))
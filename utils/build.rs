@@ -2,7 +2,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("cargo:rerun-if-changed=proto/rpc.proto");
 
     tonic_build::configure()
-        .build_server(false) // Disable server code generation
+        .build_server(true)
         .compile_protos(&["proto/rpc.proto"], &["proto/"])?;
 
     Ok(())
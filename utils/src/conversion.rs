@@ -1,18 +1,28 @@
 use std::{
+    collections::HashMap,
     path::PathBuf,
-    sync::{Arc, atomic::AtomicBool},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize},
+    },
 };
 
 use crate::rpc_types::{
-    Download as DownloadProto, DownloadRequest, DownloadStatus as DownloadStatusProto,
+    Download as DownloadProto, DownloadPriority as DownloadPriorityProto, DownloadRequest,
+    DownloadStatus as DownloadStatusProto, Error as ErrorProto, ErrorCode,
     NonResumablePart as NoneRseumablePartProto, None as NoneProto,
     ResumablePart as ResumablePartProto, ResumableParts as ResumablePartsProto,
     download::Parts as PartsProto,
 };
 use download_engine::{
     Download, DownloadParts, NonResumableDownloadPart, ResumableDownloadPart,
-    download_config::DownloadConfig, types::DownloadStatus,
+    download_config::DownloadConfig,
+    errors::DownloadError,
+    speed_limiter::SpeedLimiter,
+    speed_tracker::SpeedTracker,
+    types::{DownloadPriority, DownloadStatus},
 };
+use tokio::sync::Mutex;
 use uuid::Uuid;
 
 use chrono::{DateTime, Duration as ChronoDuration, TimeZone, Utc};
@@ -71,7 +81,7 @@ pub fn convert_from_timestamp_proto(timestamp: Timestamp) -> DateTime<Utc> {
 
 pub fn convert_from_duration_proto(duration: ProtoDuration) -> ChronoDuration {
     // Convert seconds and nanoseconds separately
-    let seconds = duration.seconds.max(0) as i64;
+    let seconds = duration.seconds.max(0);
     let nanos = duration.nanos.max(0) as i64;
 
     // ChronoDuration can handle large values and negative durations
@@ -79,12 +89,16 @@ pub fn convert_from_duration_proto(duration: ProtoDuration) -> ChronoDuration {
 }
 
 pub fn convert_to_download_req(req: DownloadRequest) -> download_engine::types::DownloadRequest {
+    let priority = convert_from_download_priority_proto(&req.priority());
     download_engine::types::DownloadRequest {
         url: req.url,
         file_dir: PathBuf::from(req.file_dir),
         file_name: req.filename.map(PathBuf::from),
         referrer: req.referrer,
         headers: Some(req.headers),
+        priority,
+        checksum: req.checksum,
+        category: req.category,
     }
 }
 
@@ -105,6 +119,10 @@ pub fn convert_to_download_req_proto(
         }),
         referrer: req.referrer,
         headers: req.headers.unwrap_or(vec![]),
+        priority: convert_to_download_priority_proto(&req.priority) as i32,
+        checksum: req.checksum,
+        duplicate_policy: 0,
+        category: req.category,
     }
 }
 
@@ -153,6 +171,26 @@ pub fn convert_to_download_proto(download: &Download) -> DownloadProto {
             }),
             DownloadParts::None => PartsProto::None(NoneProto {}),
         }),
+        smoothed_speed: download.get_smoothed_speed() as u64,
+        eta: download
+            .get_eta()
+            .map(|eta| convert_to_duration_proto(&eta)),
+        speed_history: download
+            .get_speed_history()
+            .iter()
+            .map(|speed| *speed as u64)
+            .collect(),
+        priority: convert_to_download_priority_proto(&download.priority) as i32,
+        checksum: download.checksum.clone(),
+        category: download.category.clone(),
+        date_finished: download
+            .date_finished
+            .as_ref()
+            .map(convert_to_timestamp_proto),
+        total_bytes: download.get_total_size(),
+        bytes_downloaded: download.get_bytes_downloaded(),
+        connections: download.config.connections_per_server as u32,
+        speed_limit: download.own_speed_limiter.limit(),
     }
 }
 
@@ -209,6 +247,28 @@ pub fn convert_from_download_proto(download: &DownloadProto) -> Download {
         last_update_time: None,
         progress: download_engine::DownloadPartsProgress::None,
         stop_token: Arc::new(AtomicBool::new(false)),
+        tasks: Arc::new(Mutex::new(HashMap::new())),
+        connection_limit: Arc::new(AtomicUsize::new(
+            DownloadConfig::default().connections_per_server,
+        )),
+        priority: convert_from_download_priority_proto(&download.priority()),
+        checksum: download.checksum.clone(),
+        category: download.category.clone(),
+        date_finished: download.date_finished.map(convert_from_timestamp_proto),
+        speed_limiter: Arc::new(SpeedLimiter::default()),
+        own_speed_limiter: Arc::new(SpeedLimiter::new(download.speed_limit)),
+        connections: None,
+        etag: None,
+        last_modified: None,
+        restored: false,
+        speed: SpeedTracker::new(
+            download.smoothed_speed as usize,
+            download
+                .speed_history
+                .iter()
+                .map(|speed| *speed as usize)
+                .collect(),
+        ),
     }
 }
 
@@ -240,3 +300,46 @@ pub fn convert_from_download_status_proto(status: &DownloadStatusProto) -> Downl
         DownloadStatusProto::Cancelled => DownloadStatus::Cancelled,
     }
 }
+
+pub fn convert_to_download_priority_proto(priority: &DownloadPriority) -> DownloadPriorityProto {
+    match priority {
+        DownloadPriority::Low => DownloadPriorityProto::Low,
+        DownloadPriority::Normal => DownloadPriorityProto::Normal,
+        DownloadPriority::High => DownloadPriorityProto::High,
+    }
+}
+
+pub fn convert_from_download_priority_proto(priority: &DownloadPriorityProto) -> DownloadPriority {
+    match priority {
+        DownloadPriorityProto::PriorityUnspecified => DownloadPriority::Normal,
+        DownloadPriorityProto::Low => DownloadPriority::Low,
+        DownloadPriorityProto::Normal => DownloadPriority::Normal,
+        DownloadPriorityProto::High => DownloadPriority::High,
+    }
+}
+
+pub fn convert_to_error_proto(error: &DownloadError) -> ErrorProto {
+    let code = match error {
+        DownloadError::HttpRequestError(e) => match e.status().map(|status| status.as_u16()) {
+            Some(404 | 410) => ErrorCode::NotFound,
+            Some(401 | 403) => ErrorCode::PermissionDenied,
+            _ => ErrorCode::Unavailable,
+        },
+        DownloadError::UnknownContentLength => ErrorCode::FailedPrecondition,
+        DownloadError::FileSystemError(e) => match e.kind() {
+            std::io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+            std::io::ErrorKind::AlreadyExists => ErrorCode::AlreadyExists,
+            _ => ErrorCode::Internal,
+        },
+        DownloadError::DownloadInterrupted => ErrorCode::Aborted,
+        DownloadError::WriteError(_) | DownloadError::GeneralError(_) => ErrorCode::Internal,
+    };
+    let error_proto = ErrorProto::new(code, error.to_string());
+    match error {
+        DownloadError::HttpRequestError(e) => match e.status() {
+            Some(status) => error_proto.with_detail("http_status", status.as_u16().to_string()),
+            None => error_proto,
+        },
+        _ => error_proto,
+    }
+}
@@ -1,6 +1,8 @@
 pub mod conversion;
 pub mod logging;
 pub mod rpc;
+// generated by prost, the size of the oneof variants is not ours to change
+#[allow(clippy::large_enum_variant)]
 pub mod rpc_types {
     tonic::include_proto!("rpc");
 }
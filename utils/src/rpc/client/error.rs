@@ -0,0 +1,63 @@
+use crate::rpc::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use crate::rpc_types::{Error as ErrorProto, ErrorCode};
+use std::io;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    /// The daemon couldn't be reached or the connection broke.
+    #[error("Connection to the daemon failed: {0}")]
+    Connection(#[from] io::Error),
+
+    /// A message couldn't be encoded or decoded.
+    #[error("Malformed message: {0}")]
+    Protocol(String),
+
+    /// The daemon answered with an error.
+    #[error("{0}")]
+    Rpc(ErrorProto),
+
+    /// The daemon and this client have no protocol version in common, versions
+    /// are 0 for daemons older than the hello exchange.
+    #[error(
+        "Incompatible daemon, it speaks protocol versions {min_protocol_version} to \
+         {protocol_version} and this client {} to {}",
+        MIN_PROTOCOL_VERSION,
+        PROTOCOL_VERSION
+    )]
+    Incompatible {
+        protocol_version: u32,
+        min_protocol_version: u32,
+    },
+
+    /// The daemon answered with something other than the request asks for.
+    #[error("Unexpected response from the daemon")]
+    UnexpectedResponse,
+}
+
+impl ClientError {
+    /// Code of the error the daemon answered with, `Unavailable` if it couldn't
+    /// be reached
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ClientError::Connection(_) => ErrorCode::Unavailable,
+            ClientError::Protocol(_) | ClientError::UnexpectedResponse => ErrorCode::Internal,
+            ClientError::Incompatible { .. } => ErrorCode::FailedPrecondition,
+            ClientError::Rpc(error) => error.code(),
+        }
+    }
+
+    /// Detail of the error the daemon answered with, e.g. "id"
+    pub fn detail(&self, key: &str) -> Option<&str> {
+        match self {
+            ClientError::Rpc(error) => error.details.get(key).map(String::as_str),
+            _ => None,
+        }
+    }
+}
+
+impl From<ErrorProto> for ClientError {
+    fn from(error: ErrorProto) -> Self {
+        ClientError::Rpc(error)
+    }
+}
@@ -1,18 +1,37 @@
-use crate::conversion::{convert_from_download_proto, convert_to_timestamp_proto};
-use crate::rpc::NativeRpcSettings;
-use crate::rpc::message_codec::MessageCodec;
+use crate::conversion::{
+    convert_from_download_proto, convert_to_download_priority_proto, convert_to_timestamp_proto,
+};
+use crate::rpc::message_codec::{MAX_MESSAGE_SIZE, MessageCodec};
+use crate::rpc::server::EVENT_CAPACITY;
+use crate::rpc::{MIN_PROTOCOL_VERSION, NativeRpcSettings, PROTOCOL_VERSION, SECRET_ENV};
+use crate::rpc_types::Error as ErrorProto;
 use crate::rpc_types::rpc_request::Request;
 use crate::rpc_types::rpc_response::Response;
-use crate::rpc_types::{GetDownloads, HeartBeat, RpcRequest, RpcResponse, rpc_response};
-use anyhow::{Context, Result};
+use crate::rpc_types::{
+    Authenticate, CancelDownloads, ChangeDownloadOptions, DownloadEvent, DownloadEventType,
+    DownloadList, ErrorCode, GetDownloads, GetSchedules, GlobalOptions, HeartBeat, Hello,
+    HelloReply, MoveDownload, PauseDownloads, QueueMove, RemoveDownloads, ResumeDownloads,
+    RetryDownloads, RpcRequest, RpcResponse, Schedules, SetDownloadPriority, StartDownloadNow,
+    Subscribe, Unsubscribe,
+};
 use bytes::{Buf, BytesMut};
 use chrono::Utc;
-use download_engine::Download;
+use download_engine::{Download, types::DownloadPriority};
 use prost::Message;
 use rand::random;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{Mutex, mpsc, oneshot};
+use tokio::task::AbortHandle;
 use tokio_util::codec::{Decoder, Encoder};
-use tracing::debug;
+use tracing::{debug, warn};
+
+pub use error::ClientError;
+
+mod error;
 
 #[cfg(unix)]
 use tokio::net::UnixStream;
@@ -20,37 +39,178 @@ use tokio::net::UnixStream;
 #[cfg(windows)]
 use tokio::net::windows::named_pipe::{ClientOptions, NamedPipeClient};
 
-/// Frame format: [length: u32][data: bytes]
-/// This must match the server's MessageCodec exactly
+#[cfg(unix)]
+type Stream = UnixStream;
 
-#[derive(Debug)]
+#[cfg(windows)]
+type Stream = NamedPipeClient;
+
+// never connected, `open` fails on other platforms
+#[cfg(not(any(unix, windows)))]
+type Stream = tokio::io::DuplexStream;
+
+pub type Result<T, E = ClientError> = std::result::Result<T, E>;
+
+/// Handle to one connection to the native server. Clones share the connection,
+/// any number of calls and subscriptions can be in flight at the same time and
+/// their responses are matched by request_id. The connection is closed once
+/// the last clone and `Subscription` is dropped
+#[derive(Debug, Clone)]
 pub struct NativeRpcClient {
-    #[cfg(unix)]
-    stream: UnixStream,
-    #[cfg(windows)]
-    pipe: NamedPipeClient,
-    codec: MessageCodec,
-    buffer: BytesMut,
+    connection: Arc<Connection>,
+}
+
+#[derive(Debug)]
+struct Connection {
+    writer: Mutex<WriteHalf<Stream>>,
+    routes: Arc<StdMutex<Routes>>,
+    /// the task reading responses and events
+    reader: AbortHandle,
+    /// what the daemon said about itself on connect
+    daemon: HelloReply,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// Where the reader hands incoming messages to, by request_id
+#[derive(Debug, Default)]
+struct Routes {
+    pending: HashMap<u64, oneshot::Sender<RpcResponse>>,
+    subscriptions: HashMap<u64, mpsc::Sender<DownloadEvent>>,
+    /// set once the reader stopped, nothing will be answered anymore
+    closed: bool,
 }
 
 impl NativeRpcClient {
-    /// Connect to the native server (Unix socket or Windows named pipe)
+    /// Connect to the native server, check it speaks a protocol version this
+    /// client understands and authenticate with the secret of `settings`, or
+    /// the one in the `NET_MANTHAN_RPC_SECRET` environment variable if that is
+    /// empty
     pub async fn connect(settings: &NativeRpcSettings) -> Result<Self> {
+        let (reader, writer) = tokio::io::split(Self::open(settings).await?);
+        let routes = Arc::new(StdMutex::new(Routes::default()));
+        let reader = tokio::spawn(read_messages(reader, routes.clone())).abort_handle();
+        let mut client = NativeRpcClient {
+            connection: Arc::new(Connection {
+                writer: Mutex::new(writer),
+                routes,
+                reader,
+                daemon: HelloReply::default(),
+            }),
+        };
+
+        let daemon = client.hello().await?;
+        Arc::get_mut(&mut client.connection)
+            .expect("connection isn't shared yet")
+            .daemon = daemon;
+
+        if client.daemon().authentication_required {
+            let secret = match settings.secret.is_empty() {
+                true => std::env::var(SECRET_ENV).unwrap_or_default(),
+                false => settings.secret.clone(),
+            };
+            if secret.is_empty() {
+                return Err(ClientError::Rpc(ErrorProto::new(
+                    ErrorCode::Unauthenticated,
+                    format!("the daemon needs a secret, set it in {}", SECRET_ENV),
+                )));
+            }
+            client.authenticate(secret).await?;
+        }
+        Ok(client)
+    }
+
+    /// Protocol version, features and limits of the daemon
+    pub fn daemon(&self) -> &HelloReply {
+        &self.connection.daemon
+    }
+
+    /// Whether the daemon supports an optional part of the protocol, see `FEATURES`
+    pub fn supports(&self, feature: &str) -> bool {
+        self.daemon().features.iter().any(|f| f == feature)
+    }
+
+    async fn hello(&self) -> Result<HelloReply> {
+        let client_name = std::env::current_exe()
+            .ok()
+            .and_then(|exe| {
+                exe.file_stem()
+                    .map(|name| name.to_string_lossy().into_owned())
+            })
+            .unwrap_or_default();
+        let request = Request::Hello(Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_name: format!("{} {}", client_name, env!("CARGO_PKG_VERSION")),
+        });
+        let response = self.send_request(request).await?;
+        match response.response {
+            Some(Response::Hello(daemon)) => {
+                if daemon.protocol_version < MIN_PROTOCOL_VERSION {
+                    return Err(ClientError::Incompatible {
+                        protocol_version: daemon.protocol_version,
+                        min_protocol_version: daemon.min_protocol_version,
+                    });
+                }
+                debug!(
+                    "Connected to daemon {} with protocol {}",
+                    daemon.daemon_version, daemon.protocol_version
+                );
+                Ok(daemon)
+            }
+            // a daemon older than the hello exchange doesn't know the request
+            Some(Response::Error(error))
+                if matches!(
+                    error.code(),
+                    ErrorCode::Unimplemented | ErrorCode::Unavailable
+                ) =>
+            {
+                Err(ClientError::Incompatible {
+                    protocol_version: 0,
+                    min_protocol_version: 0,
+                })
+            }
+            // this client is older than the daemon still supports
+            Some(Response::Error(error)) if error.code() == ErrorCode::FailedPrecondition => {
+                let detail = |key: &str| {
+                    error
+                        .details
+                        .get(key)
+                        .and_then(|value| value.parse().ok())
+                        .unwrap_or_default()
+                };
+                Err(ClientError::Incompatible {
+                    protocol_version: detail("protocol_version"),
+                    min_protocol_version: detail("min_protocol_version"),
+                })
+            }
+            other => Err(unexpected(other)),
+        }
+    }
+
+    async fn authenticate(&self, secret: String) -> Result<()> {
+        let response = self
+            .send_request(Request::Authenticate(Authenticate { secret }))
+            .await?;
+        match response.response {
+            Some(Response::Authenticated(_)) => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Open the Unix socket or Windows named pipe of the native server
+    async fn open(settings: &NativeRpcSettings) -> Result<Stream> {
         #[cfg(unix)]
         {
             let stream = UnixStream::connect(&settings.address)
                 .await
-                .with_context(|| {
-                    format!("Failed to connect to Unix socket: {}", settings.address)
-                })?;
+                .map_err(|e| connection_error(&settings.address, e))?;
 
             debug!("Connected to Unix socket: {}", settings.address);
-
-            Ok(Self {
-                stream,
-                codec: MessageCodec,
-                buffer: BytesMut::new(),
-            })
+            Ok(stream)
         }
 
         #[cfg(windows)]
@@ -58,166 +218,632 @@ impl NativeRpcClient {
             let pipe_name = format!(r"\\.\pipe\{}", settings.address);
             let pipe = ClientOptions::new()
                 .open(&pipe_name)
-                .with_context(|| format!("Failed to connect to named pipe: {}", pipe_name))?;
+                .map_err(|e| connection_error(&pipe_name, e))?;
 
             debug!("Connected to named pipe: {}", pipe_name);
-
-            Ok(Self {
-                pipe,
-                codec: MessageCodec,
-                buffer: BytesMut::new(),
-            })
+            Ok(pipe)
         }
 
         #[cfg(not(any(unix, windows)))]
         {
-            anyhow::bail!("Native IPC not supported on this platform");
+            Err(ClientError::Connection(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Native IPC not supported on this platform",
+            )))
         }
     }
 
-    /// Send a request and wait for response
-    pub async fn send_request(&mut self, req: Request) -> Result<RpcResponse> {
-        let request_id = random::<u64>();
+    /// Send a request and wait for its response, other calls on this
+    /// connection go on meanwhile
+    pub async fn send_request(&self, req: Request) -> Result<RpcResponse> {
+        self.call(random(), req).await
+    }
 
+    async fn call(&self, request_id: u64, req: Request) -> Result<RpcResponse> {
         let request = RpcRequest {
             request_id,
             request: Some(req),
         };
-        // Serialize the request
+        let limit = match self.daemon().limits {
+            Some(limits) => limits.max_message_size as usize,
+            None => MAX_MESSAGE_SIZE,
+        };
+        if request.encoded_len() > limit {
+            return Err(ClientError::Protocol(format!(
+                "request of {} bytes is over the limit of {} bytes",
+                request.encoded_len(),
+                limit
+            )));
+        }
         let mut request_data = Vec::with_capacity(request.encoded_len());
         request
             .encode(&mut request_data)
-            .context("Failed to encode request")?;
+            .map_err(|e| ClientError::Protocol(e.to_string()))?;
 
         // Encode with length prefix
         let mut encoded = BytesMut::new();
-        self.codec
-            .encode(request_data, &mut encoded)
-            .context("Failed to encode message frame")?;
+        MessageCodec.encode(request_data, &mut encoded)?;
+
+        let (respond_to, response) = oneshot::channel();
+        {
+            let mut routes = self.routes();
+            if routes.closed {
+                return Err(closed());
+            }
+            routes.pending.insert(request_id, respond_to);
+        }
 
-        // Send the request
-        self.write_all(&encoded)
+        let written = self
+            .connection
+            .writer
+            .lock()
             .await
-            .context("Failed to write request to stream")?;
+            .write_all(&encoded)
+            .await;
+        if let Err(e) = written {
+            self.routes().pending.remove(&request_id);
+            return Err(e.into());
+        }
+        debug!("Sent request: {}", request_id);
 
-        debug!("Sent request: {}", request.request_id);
+        // dropped by the reader when the connection closes
+        response.await.map_err(|_| closed())
+    }
 
-        // Read and decode response
-        self.read_response().await
+    fn routes(&self) -> std::sync::MutexGuard<'_, Routes> {
+        self.connection.routes.lock().expect("routes lock")
     }
 
-    /// Read a response from the stream
-    async fn read_response(&mut self) -> Result<RpcResponse> {
-        loop {
-            // Try to decode a complete message from buffer
-            if let Some(message_data) = self
-                .codec
-                .decode(&mut self.buffer)
-                .context("Failed to decode message frame")?
-            {
-                let response = RpcResponse::decode(message_data.chunk())
-                    .context("Failed to deserialize response")?;
+    /// Close the connection, for every clone of this client
+    pub async fn close(self) -> Result<()> {
+        self.connection.writer.lock().await.shutdown().await?;
+        Ok(())
+    }
+}
 
-                debug!("Received response: {}", response.request_id);
-                return Ok(response);
+/// Hand responses to the calls waiting for them and events to their
+/// subscriptions until the connection closes
+async fn read_messages(mut reader: ReadHalf<Stream>, routes: Arc<StdMutex<Routes>>) {
+    let mut codec = MessageCodec;
+    let mut buffer = BytesMut::new();
+    let mut temp = [0u8; 4096];
+    'connection: loop {
+        match reader.read(&mut temp).await {
+            Ok(0) => {
+                debug!("Connection closed by server");
+                break;
+            }
+            Ok(n) => buffer.extend_from_slice(&temp[..n]),
+            Err(e) => {
+                warn!("Failed to read from the daemon: {}", e);
+                break;
             }
+        }
 
-            // Need more data, read from stream
-            let mut temp = [0u8; 4096];
-            match self.read(&mut temp).await {
-                Ok(0) => {
-                    return Err(anyhow::anyhow!("Connection closed by server"));
-                }
-                Ok(n) => {
-                    self.buffer.extend_from_slice(&temp[..n]);
+        loop {
+            let message = match codec.decode(&mut buffer) {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Failed to decode message frame: {}", e);
+                    break 'connection;
                 }
+            };
+            let response = match RpcResponse::decode(message.chunk()) {
+                Ok(response) => response,
                 Err(e) => {
-                    return Err(anyhow::anyhow!("Failed to read from stream: {}", e));
+                    warn!("Failed to deserialize response: {}", e);
+                    continue;
                 }
+            };
+
+            let mut routes = routes.lock().expect("routes lock");
+            match response.response {
+                Some(Response::Event(event)) => {
+                    let Some(subscription) = routes.subscriptions.get(&response.request_id) else {
+                        continue;
+                    };
+                    match subscription.try_send(event) {
+                        Ok(()) => {}
+                        Err(TrySendError::Full(_)) => {
+                            warn!("Subscriber is too slow, dropped an event")
+                        }
+                        Err(TrySendError::Closed(_)) => {
+                            routes.subscriptions.remove(&response.request_id);
+                        }
+                    }
+                }
+                _ => match routes.pending.remove(&response.request_id) {
+                    Some(respond_to) => {
+                        let _ = respond_to.send(response);
+                    }
+                    None => debug!("Ignoring response to {}", response.request_id),
+                },
             }
         }
     }
 
-    /// Platform-agnostic read implementation
-    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
-        #[cfg(unix)]
-        {
-            self.stream.read(buf).await
+    // the waiting calls fail and the subscriptions end
+    let mut routes = routes.lock().expect("routes lock");
+    routes.closed = true;
+    routes.pending.clear();
+    routes.subscriptions.clear();
+}
+
+/// Events of one `subscribe` call, unsubscribes when dropped
+#[derive(Debug)]
+pub struct Subscription {
+    id: u64,
+    events: mpsc::Receiver<DownloadEvent>,
+    client: NativeRpcClient,
+}
+
+impl Subscription {
+    /// The next event, None once the connection is closed
+    pub async fn next(&mut self) -> Option<DownloadEvent> {
+        self.events.recv().await
+    }
+}
+
+/// Bring `downloads` up to date with an event of a `Subscription`, a download
+/// it doesn't have yet is added at the end
+pub fn apply_event(downloads: &mut Vec<Download>, event: &DownloadEvent) {
+    match (event.r#type(), &event.download) {
+        (DownloadEventType::Removed, _) => {
+            downloads.retain(|download| download.id.to_string() != event.id)
         }
-        #[cfg(windows)]
-        {
-            self.pipe.read(buf).await
+        (_, Some(download)) => {
+            let download = convert_from_download_proto(download);
+            match downloads.iter_mut().find(|d| d.id == download.id) {
+                Some(existing) => *existing = download,
+                None => downloads.push(download),
+            }
         }
+        (_, None) => {}
     }
+}
 
-    /// Platform-agnostic write_all implementation
-    async fn write_all(&mut self, buf: &[u8]) -> Result<(), std::io::Error> {
-        #[cfg(unix)]
-        {
-            self.stream.write_all(buf).await
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.client.routes().subscriptions.remove(&self.id);
+        // the daemon stops pushing the events, unless the runtime is gone too
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let client = self.client.clone();
+            let subscription = self.id;
+            runtime.spawn(async move {
+                let _ = client
+                    .send_request(Request::Unsubscribe(Unsubscribe { subscription }))
+                    .await;
+            });
         }
-        #[cfg(windows)]
-        {
-            self.pipe.write_all(buf).await
+    }
+}
+
+impl NativeRpcClient {
+    /// Have the server push DownloadEvents of the downloads in `ids` (all if
+    /// empty) and of the types in `types` (all if empty). Returns the matching
+    /// downloads as they are now and the events from then on
+    pub async fn subscribe(
+        &self,
+        ids: Vec<String>,
+        types: Vec<DownloadEventType>,
+    ) -> Result<(Vec<Download>, Subscription)> {
+        let id = random();
+        let (sender, events) = mpsc::channel(EVENT_CAPACITY);
+        // registered first, the events may follow the response right away
+        self.routes().subscriptions.insert(id, sender);
+        let subscription = Subscription {
+            id,
+            events,
+            client: self.clone(),
+        };
+
+        let request = Request::Subscribe(Subscribe {
+            ids,
+            types: types.into_iter().map(|t| t as i32).collect(),
+        });
+        let response = self.call(id, request).await?;
+        match response.response {
+            Some(Response::Downloads(d)) => Ok((
+                d.list.iter().map(convert_from_download_proto).collect(),
+                subscription,
+            )),
+            other => Err(unexpected(other)),
         }
     }
 
-    /// Close the connection
-    #[cfg(unix)]
-    pub async fn close(mut self) -> Result<()> {
-        self.stream
-            .shutdown()
-            .await
-            .context("Failed to shutdown stream")?;
-        Ok(())
+    pub async fn get_downloads(&self) -> Result<Vec<Download>> {
+        self.list_downloads(GetDownloads::default()).await
     }
 
-    #[cfg(windows)]
-    pub async fn close(self) -> Result<()> {
-        // Named pipes don't require explicit shutdown
-        // The connection will be closed when the client is dropped
-        Ok(())
+    /// Downloads in the category with this name
+    pub async fn get_downloads_in_category(&self, category: String) -> Result<Vec<Download>> {
+        self.list_downloads(GetDownloads {
+            category: Some(category),
+            ..Default::default()
+        })
+        .await
     }
-}
 
-impl NativeRpcClient {
-    pub async fn get_downloads(&mut self) -> Result<Vec<Download>> {
-        let request = Request::GetDownloads(GetDownloads {});
-        let response = self.send_request(request).await?;
+    /// A filtered, sorted page of the downloads as the daemon sends it, with a
+    /// field mask the downloads only have the fields asked for
+    pub async fn query_downloads(&self, query: GetDownloads) -> Result<DownloadList> {
+        let response = self.send_request(Request::GetDownloads(query)).await?;
         match response.response {
-            Some(response) => match response {
-                rpc_response::Response::Downloads(downloads) => Ok(downloads
-                    .list
-                    .iter()
-                    .map(|d| convert_from_download_proto(d))
-                    .collect()),
-                _ => Err(anyhow::anyhow!("Unexpected response")),
-            },
-            None => Err(anyhow::anyhow!("Failed to get downloads")),
+            Some(Response::Downloads(downloads)) => Ok(downloads),
+            other => Err(unexpected(other)),
         }
     }
 
-    pub async fn ping(&mut self) -> Result<()> {
+    async fn list_downloads(&self, filter: GetDownloads) -> Result<Vec<Download>> {
+        let downloads = self.query_downloads(filter).await?;
+        Ok(downloads
+            .list
+            .iter()
+            .map(convert_from_download_proto)
+            .collect())
+    }
+
+    /// Change manager wide options, returns the options now in effect
+    pub async fn change_global_options(&self, options: GlobalOptions) -> Result<GlobalOptions> {
+        let response = self
+            .send_request(Request::ChangeGlobalOptions(options))
+            .await?;
+        match response.response {
+            Some(Response::GlobalOptions(options)) => Ok(options),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub async fn get_schedules(&self) -> Result<Vec<String>> {
+        let response = self
+            .send_request(Request::GetSchedules(GetSchedules {}))
+            .await?;
+        match response.response {
+            Some(Response::Schedules(schedules)) => Ok(schedules.rules),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Replace all scheduler rules, returns them as the daemon understood them
+    pub async fn set_schedules(&self, rules: Vec<String>) -> Result<Vec<String>> {
+        let response = self
+            .send_request(Request::SetSchedules(Schedules { rules }))
+            .await?;
+        match response.response {
+            Some(Response::Schedules(schedules)) => Ok(schedules.rules),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub async fn ping(&self) -> Result<()> {
         let request = Request::HeartBeat(HeartBeat {
             request_timestamp: Some(convert_to_timestamp_proto(&Utc::now())),
         });
         let response = self.send_request(request).await?;
-        return match response.response {
-            Some(response) => match response {
-                Response::HearBeat(_) => Ok(()),
-                _ => Err(anyhow::anyhow!("failed to ping")),
-            },
-            None => Err(anyhow::anyhow!("failed to ping")),
-        };
+        match response.response {
+            Some(Response::HearBeat(_)) => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Move a queued download within the queue
+    pub async fn move_download(&self, id: String, queue_move: QueueMove) -> Result<Download> {
+        let request = Request::MoveDownload(MoveDownload {
+            id,
+            r#move: queue_move.into(),
+        });
+        self.send_download_request(request).await
+    }
+
+    /// Start a queued download even if max_concurrent_downloads is reached
+    pub async fn start_download_now(&self, id: String) -> Result<Download> {
+        self.send_download_request(Request::StartDownloadNow(StartDownloadNow { id }))
+            .await
+    }
+
+    pub async fn set_download_priority(
+        &self,
+        id: String,
+        priority: DownloadPriority,
+    ) -> Result<Download> {
+        let request = Request::SetDownloadPriority(SetDownloadPriority {
+            id,
+            priority: convert_to_download_priority_proto(&priority).into(),
+        });
+        self.send_download_request(request).await
+    }
+
+    /// Change the options set in `options` of the download `options.id`,
+    /// returns the download with the options in effect
+    pub async fn change_download_options(
+        &self,
+        options: ChangeDownloadOptions,
+    ) -> Result<Download> {
+        self.send_download_request(Request::ChangeDownloadOptions(options))
+            .await
+    }
+
+    /// Pause running and queued downloads, returns the ids of the paused ones
+    pub async fn pause_downloads(&self, ids: Vec<String>) -> Result<Vec<String>> {
+        self.send_ids_request(Request::PauseDownloads(PauseDownloads { ids, all: false }))
+            .await
+    }
+
+    pub async fn pause_all_downloads(&self) -> Result<Vec<String>> {
+        let request = Request::PauseDownloads(PauseDownloads {
+            ids: Vec::new(),
+            all: true,
+        });
+        self.send_ids_request(request).await
+    }
+
+    /// Queue paused downloads again, returns the ids of the resumed ones
+    pub async fn resume_downloads(&self, ids: Vec<String>) -> Result<Vec<String>> {
+        self.send_ids_request(Request::ResumeDownloads(ResumeDownloads {
+            ids,
+            all: false,
+        }))
+        .await
+    }
+
+    pub async fn resume_all_downloads(&self) -> Result<Vec<String>> {
+        let request = Request::ResumeDownloads(ResumeDownloads {
+            ids: Vec::new(),
+            all: true,
+        });
+        self.send_ids_request(request).await
+    }
+
+    /// Cancel unfinished downloads, they stay in the list as cancelled
+    pub async fn cancel_downloads(&self, ids: Vec<String>) -> Result<Vec<String>> {
+        self.send_ids_request(Request::CancelDownloads(CancelDownloads {
+            ids,
+            all: false,
+        }))
+        .await
+    }
+
+    pub async fn cancel_all_downloads(&self) -> Result<Vec<String>> {
+        let request = Request::CancelDownloads(CancelDownloads {
+            ids: Vec::new(),
+            all: true,
+        });
+        self.send_ids_request(request).await
+    }
+
+    /// Drop downloads from the list, deleting their files if `delete_file` is set
+    pub async fn remove_downloads(
+        &self,
+        ids: Vec<String>,
+        delete_file: bool,
+    ) -> Result<Vec<String>> {
+        let request = Request::RemoveDownloads(RemoveDownloads {
+            ids,
+            all: false,
+            delete_file,
+        });
+        self.send_ids_request(request).await
+    }
+
+    pub async fn remove_all_downloads(&self, delete_file: bool) -> Result<Vec<String>> {
+        let request = Request::RemoveDownloads(RemoveDownloads {
+            ids: Vec::new(),
+            all: true,
+            delete_file,
+        });
+        self.send_ids_request(request).await
+    }
+
+    /// Queue failed and cancelled downloads again
+    pub async fn retry_downloads(&self, ids: Vec<String>) -> Result<Vec<String>> {
+        self.send_ids_request(Request::RetryDownloads(RetryDownloads { ids, all: false }))
+            .await
+    }
+
+    pub async fn retry_all_downloads(&self) -> Result<Vec<String>> {
+        let request = Request::RetryDownloads(RetryDownloads {
+            ids: Vec::new(),
+            all: true,
+        });
+        self.send_ids_request(request).await
+    }
+
+    /// Send a request that answers with the ids of the downloads it changed
+    async fn send_ids_request(&self, request: Request) -> Result<Vec<String>> {
+        let response = self.send_request(request).await?;
+        match response.response {
+            Some(Response::DownloadIds(ids)) => Ok(ids.ids),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Send a request that answers with the affected download
+    async fn send_download_request(&self, request: Request) -> Result<Download> {
+        let response = self.send_request(request).await?;
+        match response.response {
+            Some(Response::Download(download)) => Ok(convert_from_download_proto(&download)),
+            other => Err(unexpected(other)),
+        }
     }
 }
 
+/// The error the daemon answered with, or `UnexpectedResponse` if it answered
+/// something else than what was asked
+fn unexpected(response: Option<Response>) -> ClientError {
+    match response {
+        Some(Response::Error(error)) => ClientError::Rpc(error),
+        _ => ClientError::UnexpectedResponse,
+    }
+}
+
+fn closed() -> ClientError {
+    ClientError::Connection(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "Connection closed by server",
+    ))
+}
+
+fn connection_error(address: &str, e: io::Error) -> ClientError {
+    ClientError::Connection(io::Error::new(e.kind(), format!("{}: {}", address, e)))
+}
+
 pub async fn send_rpc_request(
     settings: &NativeRpcSettings,
     request: Request,
 ) -> Result<RpcResponse> {
-    let mut client = NativeRpcClient::connect(settings).await?;
+    let client = NativeRpcClient::connect(settings).await?;
     let response = client.send_request(request).await?;
     client.close().await?;
     Ok(response)
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::rpc::RpcConfig;
+    use crate::rpc::server::{RpcServer, test_handle};
+    use crate::rpc_types::DownloadIds;
+
+    /// A native server on a socket of its own whose manager answers with `answer`
+    async fn start_server(
+        answer: impl Fn(Request) -> Response + Send + 'static,
+    ) -> (RpcServer, NativeRpcSettings) {
+        let settings = NativeRpcSettings {
+            address: std::env::temp_dir()
+                .join(format!("net-manthan-test-{}.sock", random::<u64>()))
+                .to_string_lossy()
+                .into_owned(),
+            secret: String::new(),
+            allow_all_users: false,
+            allowed_users: Vec::new(),
+        };
+        let mut server = RpcServer::new(&RpcConfig::Native(settings.clone()), test_handle(answer));
+        server.start().await;
+        (server, settings)
+    }
+
+    /// Answers control requests with the ids they name, or "a" and "b" for all
+    fn control(request: Request) -> Response {
+        let ids = |ids: Vec<String>, all: bool| match all {
+            true => vec!["a".to_string(), "b".to_string()],
+            false => ids,
+        };
+        let ids = match request {
+            Request::PauseDownloads(r) => ids(r.ids, r.all),
+            Request::ResumeDownloads(r) => ids(r.ids, r.all),
+            Request::CancelDownloads(r) => ids(r.ids, r.all),
+            // only removing the files of "a" is allowed
+            Request::RemoveDownloads(r) if r.delete_file && r.ids != ["a"] => {
+                return Response::Error(ErrorProto::new(
+                    ErrorCode::PermissionDenied,
+                    "can't delete that",
+                ));
+            }
+            Request::RemoveDownloads(r) => ids(r.ids, r.all),
+            Request::RetryDownloads(r) if r.ids.iter().any(|id| id == "missing") => {
+                return Response::Error(ErrorProto::not_found("missing"));
+            }
+            Request::RetryDownloads(r) => ids(r.ids, r.all),
+            request => {
+                return Response::Error(ErrorProto::internal(format!("unexpected {:?}", request)));
+            }
+        };
+        Response::DownloadIds(DownloadIds { ids })
+    }
+
+    #[tokio::test]
+    async fn controls_downloads() {
+        let (server, settings) = start_server(control).await;
+        let client = NativeRpcClient::connect(&settings).await.unwrap();
+        let ids = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(client.pause_downloads(ids(&["a"])).await.unwrap(), ["a"]);
+        assert_eq!(client.pause_all_downloads().await.unwrap(), ["a", "b"]);
+        assert_eq!(client.resume_downloads(ids(&["b"])).await.unwrap(), ["b"]);
+        assert_eq!(client.resume_all_downloads().await.unwrap(), ["a", "b"]);
+        assert_eq!(client.cancel_downloads(ids(&["a"])).await.unwrap(), ["a"]);
+        assert_eq!(client.cancel_all_downloads().await.unwrap(), ["a", "b"]);
+        assert_eq!(
+            client.remove_downloads(ids(&["a"]), true).await.unwrap(),
+            ["a"]
+        );
+        assert_eq!(
+            client.remove_all_downloads(false).await.unwrap(),
+            ["a", "b"]
+        );
+        let error = client.remove_all_downloads(true).await.unwrap_err();
+        assert_eq!(error.code(), ErrorCode::PermissionDenied);
+        assert_eq!(client.retry_all_downloads().await.unwrap(), ["a", "b"]);
+
+        let error = client
+            .retry_downloads(ids(&["a", "missing"]))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), ErrorCode::NotFound);
+        assert_eq!(error.detail("id"), Some("missing"));
+
+        client.close().await.unwrap();
+        server.shutdown().await;
+        // the server removes it in the background, the test may end before that
+        let _ = std::fs::remove_file(&settings.address);
+    }
+
+    #[tokio::test]
+    async fn server_handle_controls_downloads() {
+        let mut handle = test_handle(control);
+        assert_eq!(
+            handle
+                .pause_downloads(vec!["a".into()], false)
+                .await
+                .unwrap(),
+            ["a"]
+        );
+        assert_eq!(
+            handle.cancel_downloads(Vec::new(), true).await.unwrap(),
+            ["a", "b"]
+        );
+        let error = handle
+            .retry_downloads(vec!["missing".into()], false)
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), ErrorCode::NotFound);
+    }
+
+    #[test]
+    fn applies_events_to_the_downloads() {
+        let new_download = |url: &str| {
+            let request = download_engine::types::DownloadRequest {
+                url: url.into(),
+                file_dir: "/downloads".into(),
+                file_name: None,
+                referrer: None,
+                headers: None,
+                priority: DownloadPriority::Normal,
+                checksum: None,
+                category: None,
+            };
+            Download::new(request, &Default::default())
+        };
+        let event = |r#type: DownloadEventType, download: &Download| DownloadEvent {
+            r#type: r#type as i32,
+            id: download.id.to_string(),
+            download: Some(crate::conversion::convert_to_download_proto(download)),
+        };
+        let (a, mut b) = (
+            new_download("https://a.com/1"),
+            new_download("https://b.com/1"),
+        );
+        let mut downloads = vec![a.clone()];
+
+        apply_event(&mut downloads, &event(DownloadEventType::Added, &b));
+        b.priority = DownloadPriority::High;
+        apply_event(&mut downloads, &event(DownloadEventType::StateChanged, &b));
+        assert_eq!(downloads.len(), 2);
+        assert_eq!(downloads[1].priority, DownloadPriority::High);
+
+        apply_event(&mut downloads, &event(DownloadEventType::Removed, &a));
+        assert_eq!(downloads.len(), 1);
+        assert_eq!(downloads[0].id, b.id);
+    }
+}
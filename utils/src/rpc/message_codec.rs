@@ -1,6 +1,9 @@
 use bytes::{Buf, BufMut, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
 
+/// Largest message either side accepts, without the length prefix
+pub const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
 /// Frame format: [length: u32][data: bytes]
 #[derive(Debug)]
 pub struct MessageCodec;
@@ -16,8 +19,7 @@ impl Decoder for MessageCodec {
 
         let length = u32::from_le_bytes([src[0], src[1], src[2], src[3]]) as usize;
 
-        if length > 1024 * 1024 {
-            // 1MB limit
+        if length > MAX_MESSAGE_SIZE {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 "Message too large",
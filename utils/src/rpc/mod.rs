@@ -1,6 +1,8 @@
 pub mod client;
 pub mod message_codec;
 pub mod server;
+use crate::rpc_types::{Error, ErrorCode};
+use ring::digest::{SHA256, digest};
 use std::fmt;
 
 #[derive(Debug, Clone)]
@@ -18,7 +20,76 @@ pub struct NativeRpcSettings {
     /// - **Windows**: Named pipe identifier (e.g., `myapp-pipe`)
     pub address: String,
     pub secret: String,
+    /// On Unix the socket is left open to every local user, otherwise it is
+    /// owner only and connections from users not in `allowed_users` are refused
     pub allow_all_users: bool,
+    /// uids besides the server's own that may connect if not `allow_all_users`,
+    /// the socket has to be in a directory they can reach
+    pub allowed_users: Vec<u32>,
+}
+
+/// Where the native server listens unless told otherwise: `net-manthan.sock`
+/// in `$XDG_RUNTIME_DIR`, or in a per-user directory under the temp directory
+/// if that isn't set. A pipe name on Windows
+pub fn default_native_address() -> String {
+    #[cfg(unix)]
+    {
+        let dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+            Some(dir) if !dir.is_empty() => std::path::PathBuf::from(dir),
+            _ => std::env::temp_dir().join(format!("net-manthan-{}", current_uid())),
+        };
+        dir.join("net-manthan.sock").to_string_lossy().into_owned()
+    }
+
+    #[cfg(not(unix))]
+    {
+        "net-manthan-ipc".to_string()
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn current_uid() -> u32 {
+    // SAFETY: geteuid has no preconditions and can't fail
+    unsafe { libc::geteuid() }
+}
+
+/// Version of `rpc.proto`, raised on changes that older clients or daemons
+/// can't work with
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest protocol version this build still talks to
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional parts of the protocol this build supports, see `HelloReply`
+pub const FEATURES: &[&str] = &[
+    "authenticate",
+    "subscribe",
+    "control",
+    "purge",
+    "stats",
+    "schedules",
+    "categories",
+    "duplicate_policy",
+    "pipelining",
+    "unsubscribe",
+    "download_options",
+];
+
+/// Environment variable clients read the secret from if their settings have none
+pub const SECRET_ENV: &str = "NET_MANTHAN_RPC_SECRET";
+
+/// Whether `given` is the RPC secret, in time independent of where they differ
+///
+/// both are hashed first, so the length of the secret doesn't leak either
+pub fn secret_matches(secret: &str, given: &str) -> bool {
+    let secret = digest(&SHA256, secret.as_bytes());
+    let given = digest(&SHA256, given.as_bytes());
+    secret
+        .as_ref()
+        .iter()
+        .zip(given.as_ref())
+        .fold(0u8, |difference, (a, b)| difference | (a ^ b))
+        == 0
 }
 
 #[derive(Debug, Clone)]
@@ -40,3 +111,49 @@ impl fmt::Display for RpcConfig {
         }
     }
 }
+
+impl Error {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Error {
+            code: code as i32,
+            message: message.into(),
+            details: Default::default(),
+        }
+    }
+
+    /// NOT_FOUND error for the download with this id
+    pub fn not_found(id: &str) -> Self {
+        Error::new(ErrorCode::NotFound, format!("download {} not found", id)).with_detail("id", id)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Error::new(ErrorCode::Internal, message)
+    }
+
+    pub fn with_detail(mut self, key: &str, value: impl Into<String>) -> Self {
+        self.details.insert(key.to_string(), value.into());
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.code().as_str_name(), self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_only_the_secret() {
+        assert!(secret_matches("s3cret", "s3cret"));
+        assert!(!secret_matches("s3cret", "s3creT"));
+        assert!(!secret_matches("s3cret", "s3cret "));
+        assert!(!secret_matches("s3cret", ""));
+        assert!(secret_matches("", ""));
+    }
+}
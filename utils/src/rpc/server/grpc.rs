@@ -0,0 +1,549 @@
+use crate::rpc::server::{RpcServerHandle, hello_reply};
+use crate::rpc::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, RpcSettings, secret_matches};
+use crate::rpc_types::net_manthan_server::{NetManthan, NetManthanServer};
+use crate::rpc_types::rpc_request::Request as Call;
+use crate::rpc_types::rpc_response::Response as Answer;
+use crate::rpc_types::{
+    CancelDownloads, ChangeDownloadOptions, Download, DownloadEvent, DownloadIds, DownloadList,
+    DownloadRequest, Error, ErrorCode, GetDownload, GetDownloads, GetGlobalOptions, GetSchedules,
+    GetStats, GlobalOptions, HeartBeat, Hello, HelloReply, MoveDownload, PauseDownloads,
+    PurgeDownloads, RemoveDownloads, ResumeDownloads, RetryDownloads, RpcRequest, Schedules,
+    SetDownloadPriority, StartDownloadNow, Stats, Subscribe,
+};
+use anyhow::{Context, Result};
+use axum::http;
+use rand::random;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::{BroadcastStream, TcpListenerStream};
+use tokio_stream::{Stream, StreamExt};
+use tonic::metadata::{Ascii, MetadataKey, MetadataValue};
+use tonic::service::Interceptor;
+use tonic::transport::Server;
+use tonic::{Code, Request, Response, Status};
+use tower::util::MapRequestLayer;
+use tracing::{error, info, warn};
+
+/// answered without the secret, it tells the client whether one is needed
+const HELLO_PATH: &str = "/rpc.NetManthan/Hello";
+
+#[derive(Debug)]
+pub struct GrpcServerHandle {
+    shutdown_tx: oneshot::Sender<()>,
+}
+
+impl GrpcServerHandle {
+    pub async fn shutdown(self) -> Result<()> {
+        let _ = self.shutdown_tx.send(());
+        Ok(())
+    }
+}
+
+pub async fn start_grpc_server(
+    handler: RpcServerHandle,
+    settings: RpcSettings,
+) -> Result<GrpcServerHandle> {
+    let ip = match settings.listen_all {
+        true => Ipv4Addr::UNSPECIFIED,
+        false => Ipv4Addr::LOCALHOST,
+    };
+    let address = SocketAddr::from((ip, settings.listen_port));
+    // bound here so a taken port is reported to the caller
+    let listener = TcpListener::bind(address)
+        .await
+        .with_context(|| format!("Failed to bind gRPC server to {}", address))?;
+
+    info!("gRPC server listening on {}", address);
+    Ok(serve(listener, handler, settings.secret))
+}
+
+/// Serve the NetManthan service on `listener` until the handle shuts it down
+fn serve(listener: TcpListener, handler: RpcServerHandle, secret: String) -> GrpcServerHandle {
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    let service = NetManthanServer::with_interceptor(
+        GrpcService {
+            handler,
+            authentication_required: !secret.is_empty(),
+        },
+        SecretCheck { secret },
+    );
+
+    tokio::spawn(async move {
+        let result = Server::builder()
+            .layer(MapRequestLayer::new(tag_method_path))
+            .add_service(service)
+            .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async {
+                let _ = shutdown_rx.await;
+                info!("Shutting down gRPC server");
+            })
+            .await;
+        if let Err(e) = result {
+            error!("gRPC server stopped: {}", e);
+        }
+    });
+
+    GrpcServerHandle { shutdown_tx }
+}
+
+/// Path of the called method, e.g. `/rpc.NetManthan/Hello`
+#[derive(Debug, Clone)]
+struct MethodPath(String);
+
+/// Put the path in the extensions, interceptors don't get to see the uri
+fn tag_method_path<B>(mut request: http::Request<B>) -> http::Request<B> {
+    let path = MethodPath(request.uri().path().to_string());
+    request.extensions_mut().insert(path);
+    request
+}
+
+/// Rejects calls without `authorization: Bearer <secret>` if a secret is set,
+/// except Hello
+#[derive(Clone)]
+struct SecretCheck {
+    secret: String,
+}
+
+impl Interceptor for SecretCheck {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        let hello = request
+            .extensions()
+            .get::<MethodPath>()
+            .is_some_and(|path| path.0 == HELLO_PATH);
+        if self.secret.is_empty() || hello {
+            return Ok(request);
+        }
+        let token = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match token {
+            Some(token) if secret_matches(&self.secret, token) => Ok(request),
+            Some(_) => Err(Status::unauthenticated("wrong secret")),
+            None => Err(Status::unauthenticated("missing secret")),
+        }
+    }
+}
+
+struct GrpcService {
+    handler: RpcServerHandle,
+    authentication_required: bool,
+}
+
+impl GrpcService {
+    /// Pass `call` to the manager and unpack its answer with `unpack`
+    async fn call<T>(
+        &self,
+        call: Call,
+        unpack: impl FnOnce(Answer) -> Option<T>,
+    ) -> Result<Response<T>, Status> {
+        let response = self
+            .handler
+            .clone()
+            .handle_call(RpcRequest {
+                request_id: random(),
+                request: Some(call),
+            })
+            .await;
+        match response.response {
+            Some(Answer::Error(error)) => Err(status_from_error(error)),
+            Some(answer) => unpack(answer)
+                .map(Response::new)
+                .ok_or_else(|| Status::internal("unexpected response")),
+            None => Err(Status::internal("empty response")),
+        }
+    }
+
+    async fn ids_call(&self, call: Call) -> Result<Response<DownloadIds>, Status> {
+        self.call(call, |answer| match answer {
+            Answer::DownloadIds(ids) => Some(ids),
+            _ => None,
+        })
+        .await
+    }
+
+    async fn download_call(&self, call: Call) -> Result<Response<Download>, Status> {
+        self.call(call, |answer| match answer {
+            Answer::Download(download) => Some(download),
+            _ => None,
+        })
+        .await
+    }
+
+    async fn options_call(&self, call: Call) -> Result<Response<GlobalOptions>, Status> {
+        self.call(call, |answer| match answer {
+            Answer::GlobalOptions(options) => Some(options),
+            _ => None,
+        })
+        .await
+    }
+
+    async fn schedules_call(&self, call: Call) -> Result<Response<Schedules>, Status> {
+        self.call(call, |answer| match answer {
+            Answer::Schedules(schedules) => Some(schedules),
+            _ => None,
+        })
+        .await
+    }
+}
+
+/// gRPC status for the errors the manager answers with, the codes share names
+fn status_from_error(error: Error) -> Status {
+    let code = match error.code() {
+        ErrorCode::Unspecified => Code::Unknown,
+        ErrorCode::NotFound => Code::NotFound,
+        ErrorCode::InvalidArgument => Code::InvalidArgument,
+        ErrorCode::AlreadyExists => Code::AlreadyExists,
+        ErrorCode::Unauthenticated => Code::Unauthenticated,
+        ErrorCode::PermissionDenied => Code::PermissionDenied,
+        ErrorCode::FailedPrecondition => Code::FailedPrecondition,
+        ErrorCode::Aborted => Code::Aborted,
+        ErrorCode::Unavailable => Code::Unavailable,
+        ErrorCode::Unimplemented => Code::Unimplemented,
+        ErrorCode::Internal => Code::Internal,
+        ErrorCode::ResourceExhausted => Code::ResourceExhausted,
+    };
+    let mut status = Status::new(code, error.message);
+    // the details go along as metadata, e.g. `detail-existing-id`
+    for (key, value) in error.details {
+        if let (Ok(key), Ok(value)) = (
+            format!("detail-{}", key.replace('_', "-")).parse::<MetadataKey<Ascii>>(),
+            value.parse::<MetadataValue<Ascii>>(),
+        ) {
+            status.metadata_mut().insert(key, value);
+        }
+    }
+    status
+}
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<DownloadEvent, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl NetManthan for GrpcService {
+    async fn add_download(
+        &self,
+        request: Request<DownloadRequest>,
+    ) -> Result<Response<GetDownload>, Status> {
+        self.call(
+            Call::AddDownload(request.into_inner()),
+            |answer| match answer {
+                Answer::DownloadCreated(created) => Some(created),
+                _ => None,
+            },
+        )
+        .await
+    }
+
+    async fn get_download(
+        &self,
+        request: Request<GetDownload>,
+    ) -> Result<Response<Download>, Status> {
+        self.download_call(Call::GetDownload(request.into_inner()))
+            .await
+    }
+
+    async fn get_downloads(
+        &self,
+        request: Request<GetDownloads>,
+    ) -> Result<Response<DownloadList>, Status> {
+        self.call(
+            Call::GetDownloads(request.into_inner()),
+            |answer| match answer {
+                Answer::Downloads(downloads) => Some(downloads),
+                _ => None,
+            },
+        )
+        .await
+    }
+
+    async fn heart_beat(&self, request: Request<HeartBeat>) -> Result<Response<HeartBeat>, Status> {
+        self.call(
+            Call::HeartBeat(request.into_inner()),
+            |answer| match answer {
+                Answer::HearBeat(heart_beat) => Some(heart_beat),
+                _ => None,
+            },
+        )
+        .await
+    }
+
+    async fn change_global_options(
+        &self,
+        request: Request<GlobalOptions>,
+    ) -> Result<Response<GlobalOptions>, Status> {
+        self.options_call(Call::ChangeGlobalOptions(request.into_inner()))
+            .await
+    }
+
+    async fn get_global_options(
+        &self,
+        request: Request<GetGlobalOptions>,
+    ) -> Result<Response<GlobalOptions>, Status> {
+        self.options_call(Call::GetGlobalOptions(request.into_inner()))
+            .await
+    }
+
+    async fn move_download(
+        &self,
+        request: Request<MoveDownload>,
+    ) -> Result<Response<Download>, Status> {
+        self.download_call(Call::MoveDownload(request.into_inner()))
+            .await
+    }
+
+    async fn start_download_now(
+        &self,
+        request: Request<StartDownloadNow>,
+    ) -> Result<Response<Download>, Status> {
+        self.download_call(Call::StartDownloadNow(request.into_inner()))
+            .await
+    }
+
+    async fn set_download_priority(
+        &self,
+        request: Request<SetDownloadPriority>,
+    ) -> Result<Response<Download>, Status> {
+        self.download_call(Call::SetDownloadPriority(request.into_inner()))
+            .await
+    }
+
+    async fn change_download_options(
+        &self,
+        request: Request<ChangeDownloadOptions>,
+    ) -> Result<Response<Download>, Status> {
+        self.download_call(Call::ChangeDownloadOptions(request.into_inner()))
+            .await
+    }
+
+    async fn get_schedules(
+        &self,
+        request: Request<GetSchedules>,
+    ) -> Result<Response<Schedules>, Status> {
+        self.schedules_call(Call::GetSchedules(request.into_inner()))
+            .await
+    }
+
+    async fn set_schedules(
+        &self,
+        request: Request<Schedules>,
+    ) -> Result<Response<Schedules>, Status> {
+        self.schedules_call(Call::SetSchedules(request.into_inner()))
+            .await
+    }
+
+    async fn purge_downloads(
+        &self,
+        request: Request<PurgeDownloads>,
+    ) -> Result<Response<DownloadIds>, Status> {
+        self.ids_call(Call::PurgeDownloads(request.into_inner()))
+            .await
+    }
+
+    async fn get_stats(&self, request: Request<GetStats>) -> Result<Response<Stats>, Status> {
+        self.call(
+            Call::GetStats(request.into_inner()),
+            |answer| match answer {
+                Answer::Stats(stats) => Some(stats),
+                _ => None,
+            },
+        )
+        .await
+    }
+
+    async fn pause_downloads(
+        &self,
+        request: Request<PauseDownloads>,
+    ) -> Result<Response<DownloadIds>, Status> {
+        self.ids_call(Call::PauseDownloads(request.into_inner()))
+            .await
+    }
+
+    async fn resume_downloads(
+        &self,
+        request: Request<ResumeDownloads>,
+    ) -> Result<Response<DownloadIds>, Status> {
+        self.ids_call(Call::ResumeDownloads(request.into_inner()))
+            .await
+    }
+
+    async fn cancel_downloads(
+        &self,
+        request: Request<CancelDownloads>,
+    ) -> Result<Response<DownloadIds>, Status> {
+        self.ids_call(Call::CancelDownloads(request.into_inner()))
+            .await
+    }
+
+    async fn remove_downloads(
+        &self,
+        request: Request<RemoveDownloads>,
+    ) -> Result<Response<DownloadIds>, Status> {
+        self.ids_call(Call::RemoveDownloads(request.into_inner()))
+            .await
+    }
+
+    async fn retry_downloads(
+        &self,
+        request: Request<RetryDownloads>,
+    ) -> Result<Response<DownloadIds>, Status> {
+        self.ids_call(Call::RetryDownloads(request.into_inner()))
+            .await
+    }
+
+    async fn hello(&self, request: Request<Hello>) -> Result<Response<HelloReply>, Status> {
+        let hello = request.into_inner();
+        if hello.protocol_version < MIN_PROTOCOL_VERSION {
+            return Err(Status::failed_precondition(format!(
+                "protocol version {} is too old, the daemon speaks {} to {}",
+                hello.protocol_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            )));
+        }
+        Ok(Response::new(hello_reply(self.authentication_required)))
+    }
+
+    type SubscribeStream = EventStream;
+
+    async fn subscribe(
+        &self,
+        request: Request<Subscribe>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        let filter = request.into_inner();
+        let events =
+            BroadcastStream::new(self.handler.subscribe_events()).filter_map(move |event| {
+                match event {
+                    Ok(event) if filter.matches(&event) => Some(Ok(event)),
+                    Ok(_) => None,
+                    Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                        warn!("Subscriber is too slow, skipped {} events", skipped);
+                        None
+                    }
+                }
+            });
+        Ok(Response::new(Box::pin(events)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::server::test_handle;
+    use crate::rpc_types::net_manthan_client::NetManthanClient;
+    use tonic::transport::Channel;
+
+    /// A server on a free port passing calls to `handler`, and a client
+    /// connected to it
+    async fn connect(
+        secret: &str,
+        handler: RpcServerHandle,
+    ) -> (GrpcServerHandle, NetManthanClient<Channel>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = serve(listener, handler, secret.into());
+        let client = NetManthanClient::connect(format!("http://{}", address))
+            .await
+            .unwrap();
+        (server, client)
+    }
+
+    fn answer(call: Call) -> Answer {
+        match call {
+            Call::GetDownloads(_) => Answer::Downloads(DownloadList::default()),
+            Call::Subscribe(_) => Answer::Downloads(DownloadList::default()),
+            Call::PauseDownloads(pause) => Answer::DownloadIds(DownloadIds { ids: pause.ids }),
+            Call::GetDownload(get) => {
+                Answer::Error(Error::not_found(&get.id).with_detail("existing_id", "other"))
+            }
+            call => Answer::Error(Error::internal(format!("unexpected {:?}", call))),
+        }
+    }
+
+    fn event(id: &str) -> DownloadEvent {
+        DownloadEvent {
+            id: id.into(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn says_hello_without_the_secret() {
+        let (server, mut client) = connect("s3cret", test_handle(answer)).await;
+
+        let reply = client
+            .hello(Hello {
+                protocol_version: PROTOCOL_VERSION,
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(reply.authentication_required);
+
+        let status = client
+            .get_downloads(GetDownloads::default())
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+
+        let mut request = Request::new(GetDownloads::default());
+        request
+            .metadata_mut()
+            .insert("authorization", "Bearer s3cret".parse().unwrap());
+        client.get_downloads(request).await.unwrap();
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn passes_calls_and_errors_on() {
+        let (server, mut client) = connect("", test_handle(answer)).await;
+
+        let paused = client
+            .pause_downloads(PauseDownloads {
+                ids: vec!["a".into()],
+                all: false,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(paused.ids, ["a"]);
+
+        let status = client
+            .get_download(GetDownload { id: "x".into() })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+        assert_eq!(status.metadata().get("detail-id").unwrap(), "x");
+        assert_eq!(
+            status.metadata().get("detail-existing-id").unwrap(),
+            "other"
+        );
+
+        let status = client.get_stats(GetStats::default()).await.unwrap_err();
+        assert_eq!(status.code(), Code::Internal);
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn streams_the_subscribed_events() {
+        let handler = test_handle(answer);
+        let (server, mut client) = connect("", handler.clone()).await;
+
+        let mut events = client
+            .subscribe(Subscribe {
+                ids: vec!["a".into()],
+                types: Vec::new(),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        handler.event_sender.send(event("b")).unwrap();
+        handler.event_sender.send(event("a")).unwrap();
+        assert_eq!(events.message().await.unwrap().unwrap().id, "a");
+
+        server.shutdown().await.unwrap();
+    }
+}
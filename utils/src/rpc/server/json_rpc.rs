@@ -0,0 +1,1228 @@
+use crate::rpc::server::RpcServerHandle;
+use crate::rpc::{RpcSettings, secret_matches};
+use crate::rpc_types::rpc_request::Request;
+use crate::rpc_types::rpc_response::Response as Answer;
+use crate::rpc_types::{
+    CancelDownloads, ChangeDownloadOptions, Download, DownloadEvent, DownloadEventType,
+    DownloadRequest, DownloadStatus, GetDownload, GetDownloads, GetGlobalOptions, GlobalOptions,
+    Headers, PauseDownloads, PurgeDownloads, ResumeDownloads, RpcRequest, download,
+};
+use anyhow::{Context, Result};
+use axum::Router;
+use axum::body::Bytes;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Request as HttpRequest, State};
+use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use rand::random;
+use serde_json::{Map, Value, json};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::oneshot;
+use tracing::{debug, error, info, warn};
+
+/// aria2 methods served besides the `system.*` ones
+const METHODS: &[&str] = &[
+    "aria2.addUri",
+    "aria2.tellStatus",
+    "aria2.tellActive",
+    "aria2.tellWaiting",
+    "aria2.tellStopped",
+    "aria2.pause",
+    "aria2.forcePause",
+    "aria2.pauseAll",
+    "aria2.forcePauseAll",
+    "aria2.unpause",
+    "aria2.unpauseAll",
+    "aria2.remove",
+    "aria2.forceRemove",
+    "aria2.removeDownloadResult",
+    "aria2.purgeDownloadResult",
+    "aria2.getGlobalStat",
+    "aria2.getVersion",
+    "aria2.getOption",
+    "aria2.changeOption",
+    "aria2.getGlobalOption",
+    "aria2.changeGlobalOption",
+    "system.multicall",
+    "system.listMethods",
+    "system.listNotifications",
+];
+
+const NOTIFICATIONS: &[&str] = &[
+    "aria2.onDownloadStart",
+    "aria2.onDownloadPause",
+    "aria2.onDownloadStop",
+    "aria2.onDownloadComplete",
+    "aria2.onDownloadError",
+];
+
+#[derive(Debug)]
+pub struct JsonRpcServerHandle {
+    shutdown_tx: oneshot::Sender<()>,
+}
+
+impl JsonRpcServerHandle {
+    pub async fn shutdown(self) -> Result<()> {
+        let _ = self.shutdown_tx.send(());
+        Ok(())
+    }
+}
+
+/// Serve aria2's JSON-RPC interface on `/jsonrpc`, over HTTP POST and WebSocket.
+/// Notifications are only sent over WebSocket, like aria2 does
+pub async fn start_json_rpc_server(
+    handler: RpcServerHandle,
+    settings: RpcSettings,
+) -> Result<JsonRpcServerHandle> {
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+    let ip = match settings.listen_all {
+        true => Ipv4Addr::UNSPECIFIED,
+        false => Ipv4Addr::LOCALHOST,
+    };
+    let address = SocketAddr::from((ip, settings.listen_port));
+    let listener = TcpListener::bind(address)
+        .await
+        .with_context(|| format!("Failed to bind JSON-RPC server to {}", address))?;
+
+    let rpc = Arc::new(JsonRpc {
+        handler,
+        secret: settings.secret,
+    });
+    let mut app = Router::new()
+        .route(
+            "/jsonrpc",
+            axum::routing::post(http_call)
+                .get(websocket)
+                .options(preflight),
+        )
+        .with_state(rpc);
+    // lets web frontends served from another origin, like AriaNg, call us,
+    // otherwise pages of other sites open in a browser could
+    if settings.allow_origin_all {
+        app = app.layer(axum::middleware::map_response(allow_any_origin));
+    }
+    let policy = OriginPolicy {
+        local_port: (!settings.listen_all).then_some(settings.listen_port),
+        allow_origin_all: settings.allow_origin_all,
+    };
+    app = app.layer(axum::middleware::from_fn_with_state(policy, check_origin));
+
+    info!("JSON-RPC server listening on {}", address);
+
+    tokio::spawn(async move {
+        let result = axum::serve(listener, app)
+            .with_graceful_shutdown(async {
+                let _ = shutdown_rx.await;
+                info!("Shutting down JSON-RPC server");
+            })
+            .await;
+        if let Err(e) = result {
+            error!("JSON-RPC server stopped: {}", e);
+        }
+    });
+
+    Ok(JsonRpcServerHandle { shutdown_tx })
+}
+
+async fn http_call(State(rpc): State<Arc<JsonRpc>>, headers: HeaderMap, body: Bytes) -> Response {
+    // browsers only send another content type cross origin after a preflight
+    if !is_json(&headers) {
+        return (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Content-Type must be application/json",
+        )
+            .into_response();
+    }
+    let response = rpc.handle_message(&String::from_utf8_lossy(&body)).await;
+    (
+        [(header::CONTENT_TYPE, "application/json-rpc")],
+        response.to_string(),
+    )
+        .into_response()
+}
+
+async fn websocket(State(rpc): State<Arc<JsonRpc>>, upgrade: WebSocketUpgrade) -> Response {
+    upgrade.on_upgrade(move |socket| rpc.session(socket))
+}
+
+async fn preflight() -> Response {
+    (
+        StatusCode::NO_CONTENT,
+        [
+            (header::ACCESS_CONTROL_ALLOW_METHODS, "POST, GET, OPTIONS"),
+            (header::ACCESS_CONTROL_ALLOW_HEADERS, "Content-Type"),
+            (header::ACCESS_CONTROL_MAX_AGE, "1728000"),
+        ],
+    )
+        .into_response()
+}
+
+/// Names the server answers to when it only listens on localhost
+const LOCAL_HOSTS: &[&str] = &["localhost", "127.0.0.1", "[::1]"];
+
+/// Which Host and Origin headers requests may carry
+#[derive(Clone, Copy)]
+struct OriginPolicy {
+    /// the port of a server listening on localhost only, None when it listens
+    /// on all interfaces and can be reached under any name
+    local_port: Option<u16>,
+    allow_origin_all: bool,
+}
+
+impl OriginPolicy {
+    /// Whether to serve a request with these headers
+    ///
+    /// a page rebinding its own name to 127.0.0.1 sends a matching Origin and
+    /// Host, so on localhost both have to name localhost. Clients that aren't
+    /// browsers send no Origin
+    fn allows(&self, headers: &HeaderMap) -> bool {
+        let host = headers
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok());
+        if let Some(port) = self.local_port
+            && !host.is_some_and(|host| is_local_host(host, port))
+        {
+            return false;
+        }
+        match headers.get(header::ORIGIN) {
+            None => true,
+            Some(_) if self.allow_origin_all => true,
+            Some(origin) => {
+                let Some(authority) = origin
+                    .to_str()
+                    .ok()
+                    .and_then(|origin| origin.split_once("://"))
+                    .map(|(_, authority)| authority)
+                else {
+                    return false;
+                };
+                match self.local_port {
+                    Some(port) => is_local_host(authority, port),
+                    None => host.is_some_and(|host| authority.eq_ignore_ascii_case(host)),
+                }
+            }
+        }
+    }
+}
+
+/// Refuse requests for other hosts and ones a browser sends from a page of
+/// another origin, see `OriginPolicy::allows`
+async fn check_origin(
+    State(policy): State<OriginPolicy>,
+    request: HttpRequest,
+    next: Next,
+) -> Response {
+    let headers = request.headers();
+    if !policy.allows(headers) {
+        warn!(
+            "Refused a JSON-RPC request for host {:?} from origin {:?}",
+            headers.get(header::HOST),
+            headers.get(header::ORIGIN)
+        );
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    next.run(request).await
+}
+
+/// Whether `host` (`name[:port]`) is one of LOCAL_HOSTS on `port`, without a
+/// port it is 80
+fn is_local_host(host: &str, port: u16) -> bool {
+    let (name, host_port) = match host.rsplit_once(':') {
+        // the colons of "[::1]"
+        Some((name, host_port)) if !host_port.ends_with(']') => (name, host_port.parse().ok()),
+        _ => (host, Some(80)),
+    };
+    host_port == Some(port)
+        && LOCAL_HOSTS
+            .iter()
+            .any(|local| name.eq_ignore_ascii_case(local))
+}
+
+/// Whether the body is JSON, parameters like charset don't matter
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.split(';').next())
+        .is_some_and(|mime| {
+            let mime = mime.trim();
+            mime.eq_ignore_ascii_case("application/json")
+                || mime.eq_ignore_ascii_case("application/json-rpc")
+        })
+}
+
+async fn allow_any_origin(mut response: Response) -> Response {
+    response.headers_mut().insert(
+        header::ACCESS_CONTROL_ALLOW_ORIGIN,
+        HeaderValue::from_static("*"),
+    );
+    response
+}
+
+/// A JSON-RPC error, aria2 answers every failed call with code 1
+#[derive(Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(message: impl Into<String>) -> Self {
+        Self {
+            code: 1,
+            message: message.into(),
+        }
+    }
+
+    fn parse_error() -> Self {
+        Self {
+            code: -32700,
+            message: "Parse error.".into(),
+        }
+    }
+
+    fn invalid_request() -> Self {
+        Self {
+            code: -32600,
+            message: "Invalid Request.".into(),
+        }
+    }
+
+    fn method_not_found(method: &str) -> Self {
+        Self {
+            code: -32601,
+            message: format!("Method not found: {}", method),
+        }
+    }
+
+    fn invalid_params(message: impl Into<String>) -> Self {
+        Self {
+            code: -32602,
+            message: message.into(),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({ "code": self.code, "message": self.message })
+    }
+}
+
+struct JsonRpc {
+    handler: RpcServerHandle,
+    secret: String,
+}
+
+impl JsonRpc {
+    /// Answer a single call or a batch of them
+    async fn handle_message(&self, message: &str) -> Value {
+        match serde_json::from_str(message) {
+            Ok(Value::Array(calls)) if !calls.is_empty() => {
+                let mut responses = Vec::with_capacity(calls.len());
+                for call in calls {
+                    responses.push(self.handle_call(call).await);
+                }
+                Value::Array(responses)
+            }
+            Ok(Value::Array(_)) => error_response(Value::Null, RpcError::invalid_request()),
+            Ok(call) => self.handle_call(call).await,
+            Err(_) => error_response(Value::Null, RpcError::parse_error()),
+        }
+    }
+
+    async fn handle_call(&self, call: Value) -> Value {
+        let id = call.get("id").cloned().unwrap_or(Value::Null);
+        let Some(method) = call.get("method").and_then(Value::as_str) else {
+            return error_response(id, RpcError::invalid_request());
+        };
+        let params = match call.get("params") {
+            None => Vec::new(),
+            Some(Value::Array(params)) => params.clone(),
+            Some(_) => {
+                return error_response(id, RpcError::invalid_params("params must be an array"));
+            }
+        };
+        debug!("JSON-RPC call {}", method);
+
+        let result = match method {
+            "system.multicall" => self.multicall(params).await,
+            method => self.call(method, params).await,
+        };
+        match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(e) => error_response(id, e),
+        }
+    }
+
+    /// `system.multicall`, results are wrapped in a list and errors are returned
+    /// in place of them, like aria2 does
+    async fn multicall(&self, params: Vec<Value>) -> Result<Value, RpcError> {
+        let Some(Value::Array(calls)) = params.into_iter().next() else {
+            return Err(RpcError::invalid_params("expected a list of calls"));
+        };
+        let mut results = Vec::with_capacity(calls.len());
+        for call in calls {
+            let method = call.get("methodName").and_then(Value::as_str);
+            let params = match call.get("params") {
+                None => Some(Vec::new()),
+                Some(Value::Array(params)) => Some(params.clone()),
+                Some(_) => None,
+            };
+            let result = match (method, params) {
+                (Some("system.multicall"), _) => {
+                    Err(RpcError::new("system.multicall can't be nested"))
+                }
+                (Some(method), Some(params)) => self.call(method, params).await,
+                _ => Err(RpcError::invalid_request()),
+            };
+            results.push(match result {
+                Ok(result) => json!([result]),
+                Err(e) => e.to_json(),
+            });
+        }
+        Ok(Value::Array(results))
+    }
+
+    async fn call(&self, method: &str, mut params: Vec<Value>) -> Result<Value, RpcError> {
+        match method {
+            "system.listMethods" => return Ok(json!(METHODS)),
+            "system.listNotifications" => return Ok(json!(NOTIFICATIONS)),
+            _ => {}
+        }
+        self.check_token(&mut params)?;
+
+        match method {
+            "aria2.addUri" => self.add_uri(&params).await,
+            "aria2.tellStatus" => {
+                let gid = string_param(&params, 0)?;
+                let download = self.download(gid).await?;
+                Ok(status_json(&download, &keys_param(&params, 1)))
+            }
+            "aria2.tellActive" => {
+                let downloads = self.downloads(&ACTIVE).await?;
+                let keys = keys_param(&params, 0);
+                Ok(downloads.iter().map(|d| status_json(d, &keys)).collect())
+            }
+            "aria2.tellWaiting" => self.tell_page(&params, &WAITING).await,
+            "aria2.tellStopped" => self.tell_page(&params, &STOPPED).await,
+            "aria2.pause" | "aria2.forcePause" => {
+                let gid = string_param(&params, 0)?;
+                let request = Request::PauseDownloads(PauseDownloads {
+                    ids: vec![gid.to_string()],
+                    all: false,
+                });
+                self.control(request, gid, "paused").await
+            }
+            "aria2.pauseAll" | "aria2.forcePauseAll" => {
+                let request = Request::PauseDownloads(PauseDownloads {
+                    ids: Vec::new(),
+                    all: true,
+                });
+                self.request(request).await.map(|_| json!("OK"))
+            }
+            "aria2.unpause" => {
+                let gid = string_param(&params, 0)?;
+                let request = Request::ResumeDownloads(ResumeDownloads {
+                    ids: vec![gid.to_string()],
+                    all: false,
+                });
+                self.control(request, gid, "unpaused").await
+            }
+            "aria2.unpauseAll" => {
+                let request = Request::ResumeDownloads(ResumeDownloads {
+                    ids: Vec::new(),
+                    all: true,
+                });
+                self.request(request).await.map(|_| json!("OK"))
+            }
+            // removed downloads stay in the stopped list, as cancelled ones do
+            "aria2.remove" | "aria2.forceRemove" => {
+                let gid = string_param(&params, 0)?;
+                let request = Request::CancelDownloads(CancelDownloads {
+                    ids: vec![gid.to_string()],
+                    all: false,
+                });
+                self.control(request, gid, "removed").await
+            }
+            "aria2.removeDownloadResult" => {
+                let gid = string_param(&params, 0)?;
+                let request = Request::PurgeDownloads(PurgeDownloads {
+                    ids: vec![gid.to_string()],
+                });
+                self.control(request, gid, "removed from the results")
+                    .await
+                    .map(|_| json!("OK"))
+            }
+            "aria2.purgeDownloadResult" => {
+                let request = Request::PurgeDownloads(PurgeDownloads { ids: Vec::new() });
+                self.request(request).await.map(|_| json!("OK"))
+            }
+            "aria2.getGlobalStat" => self.global_stat().await,
+            "aria2.getVersion" => Ok(json!({
+                "version": env!("CARGO_PKG_VERSION"),
+                "enabledFeatures": ["HTTPS"],
+            })),
+            "aria2.getOption" => {
+                let download = self.download(string_param(&params, 0)?).await?;
+                Ok(option_json(&download))
+            }
+            "aria2.changeOption" => {
+                let gid = string_param(&params, 0)?;
+                let options = download_options_from_json(gid, object_param(&params, 1)?)?;
+                self.request(Request::ChangeDownloadOptions(options))
+                    .await
+                    .map(|_| json!("OK"))
+            }
+            "aria2.getGlobalOption" => {
+                let request = Request::GetGlobalOptions(GetGlobalOptions {});
+                match self.request(request).await? {
+                    Answer::GlobalOptions(options) => Ok(global_options_json(&options)),
+                    _ => Err(RpcError::new("unexpected response")),
+                }
+            }
+            "aria2.changeGlobalOption" => {
+                let options = global_options_from_json(object_param(&params, 0)?)?;
+                self.request(Request::ChangeGlobalOptions(options))
+                    .await
+                    .map(|_| json!("OK"))
+            }
+            method => Err(RpcError::method_not_found(method)),
+        }
+    }
+
+    /// Take the `token:<secret>` aria2 clients send as the first param
+    fn check_token(&self, params: &mut Vec<Value>) -> Result<(), RpcError> {
+        let token = match params.first().and_then(Value::as_str) {
+            Some(param) => param.strip_prefix("token:").map(str::to_string),
+            None => None,
+        };
+        if token.is_some() {
+            params.remove(0);
+        }
+        let allowed = match token {
+            Some(token) => secret_matches(&self.secret, &token),
+            None => self.secret.is_empty(),
+        };
+        match allowed {
+            true => Ok(()),
+            false => Err(RpcError::new("Unauthorized")),
+        }
+    }
+
+    async fn add_uri(&self, params: &[Value]) -> Result<Value, RpcError> {
+        let uris: Vec<&str> = params
+            .first()
+            .and_then(Value::as_array)
+            .map(|uris| uris.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+        // mirrors of the same file aren't supported, the first uri is used
+        let Some(uri) = uris.first() else {
+            return Err(RpcError::invalid_params("expected a list of uris"));
+        };
+        let options = match params.get(1) {
+            Some(_) => object_param(params, 1)?.clone(),
+            None => Map::new(),
+        };
+        let option = |name: &str| options.get(name).and_then(Value::as_str).map(String::from);
+        let headers = match options.get("header") {
+            Some(Value::String(header)) => vec![header.clone()],
+            Some(Value::Array(headers)) => headers
+                .iter()
+                .filter_map(Value::as_str)
+                .map(String::from)
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        let request = Request::AddDownload(DownloadRequest {
+            url: uri.to_string(),
+            file_dir: option("dir").unwrap_or_default(),
+            filename: option("out"),
+            referrer: option("referer"),
+            headers,
+            checksum: option("checksum"),
+            ..Default::default()
+        });
+        match self.request(request).await? {
+            Answer::DownloadCreated(created) => Ok(json!(created.id)),
+            _ => Err(RpcError::new("unexpected response")),
+        }
+    }
+
+    /// `tellWaiting` and `tellStopped`, a negative offset counts from the end
+    /// and lists the downloads in reverse
+    async fn tell_page(
+        &self,
+        params: &[Value],
+        statuses: &[DownloadStatus],
+    ) -> Result<Value, RpcError> {
+        let offset = params
+            .first()
+            .and_then(Value::as_i64)
+            .ok_or_else(|| RpcError::invalid_params("expected an offset"))?;
+        let num = params
+            .get(1)
+            .and_then(Value::as_u64)
+            .ok_or_else(|| RpcError::invalid_params("expected a number of downloads"))?
+            as usize;
+        let keys = keys_param(params, 2);
+
+        let downloads = self.downloads(statuses).await?;
+        let page: Vec<&Download> = match usize::try_from(offset) {
+            Ok(offset) => downloads.iter().skip(offset).take(num).collect(),
+            Err(_) => {
+                let end = downloads.len() as i64 + offset + 1;
+                downloads
+                    .iter()
+                    .take(end.max(0) as usize)
+                    .rev()
+                    .take(num)
+                    .collect()
+            }
+        };
+        Ok(page.iter().map(|d| status_json(d, &keys)).collect())
+    }
+
+    async fn global_stat(&self) -> Result<Value, RpcError> {
+        let downloads = self.downloads(&[]).await?;
+        let count = |wanted: fn(DownloadStatus) -> bool| {
+            downloads.iter().filter(|d| wanted(d.status())).count()
+        };
+        let speed: u64 = downloads
+            .iter()
+            .filter(|d| is_active(d.status()))
+            .map(|d| d.smoothed_speed)
+            .sum();
+        Ok(json!({
+            "downloadSpeed": speed.to_string(),
+            "uploadSpeed": "0",
+            "numActive": count(is_active).to_string(),
+            "numWaiting": count(is_waiting).to_string(),
+            "numStopped": count(is_stopped).to_string(),
+            "numStoppedTotal": count(is_stopped).to_string(),
+        }))
+    }
+
+    /// Run a control request on `gid`, answering with the gid like aria2
+    async fn control(&self, request: Request, gid: &str, action: &str) -> Result<Value, RpcError> {
+        match self.request(request).await? {
+            Answer::DownloadIds(ids) if ids.ids.iter().any(|id| id == gid) => Ok(json!(gid)),
+            _ => Err(RpcError::new(format!(
+                "GID {} cannot be {} now",
+                gid, action
+            ))),
+        }
+    }
+
+    async fn download(&self, gid: &str) -> Result<Download, RpcError> {
+        let request = Request::GetDownload(GetDownload { id: gid.into() });
+        match self.request(request).await? {
+            Answer::Download(download) => Ok(download),
+            _ => Err(RpcError::new("unexpected response")),
+        }
+    }
+
+    /// Downloads with one of `statuses`, all of them if empty
+    async fn downloads(&self, statuses: &[DownloadStatus]) -> Result<Vec<Download>, RpcError> {
+        let query = GetDownloads {
+            statuses: statuses.iter().map(|status| *status as i32).collect(),
+            ..Default::default()
+        };
+        match self.request(Request::GetDownloads(query)).await? {
+            Answer::Downloads(downloads) => Ok(downloads.list),
+            _ => Err(RpcError::new("unexpected response")),
+        }
+    }
+
+    /// Pass a request to the manager, its errors become aria2 errors
+    async fn request(&self, request: Request) -> Result<Answer, RpcError> {
+        let response = self
+            .handler
+            .clone()
+            .handle_call(RpcRequest {
+                request_id: random(),
+                request: Some(request),
+            })
+            .await;
+        match response.response {
+            Some(Answer::Error(error)) => Err(RpcError::new(error.message)),
+            Some(answer) => Ok(answer),
+            None => Err(RpcError::new("empty response")),
+        }
+    }
+
+    /// Answer calls sent over the socket and notify it of download events
+    async fn session(self: Arc<Self>, mut socket: WebSocket) {
+        let mut events = self.handler.subscribe_events();
+        // the downloads as they are now, so only changes from here on notify
+        let mut statuses: HashMap<String, DownloadStatus> = match self.downloads(&[]).await {
+            Ok(downloads) => downloads
+                .iter()
+                .map(|d| (d.id.clone(), d.status()))
+                .collect(),
+            Err(e) => {
+                warn!(
+                    "Failed to get downloads for a JSON-RPC session: {}",
+                    e.message
+                );
+                HashMap::new()
+            }
+        };
+
+        loop {
+            tokio::select! {
+                message = socket.recv() => {
+                    let text = match message {
+                        Some(Ok(Message::Text(text))) => text,
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                        // pings are answered by axum
+                        Some(Ok(_)) => continue,
+                    };
+                    let response = self.handle_message(&text).await;
+                    if socket.send(Message::Text(response.to_string().into())).await.is_err() {
+                        break;
+                    }
+                }
+                event = events.recv() => {
+                    let event = match event {
+                        Ok(event) => event,
+                        Err(RecvError::Lagged(skipped)) => {
+                            warn!("JSON-RPC session is too slow, skipped {} events", skipped);
+                            continue;
+                        }
+                        Err(RecvError::Closed) => break,
+                    };
+                    let Some(method) = notification(&event, &mut statuses) else {
+                        continue;
+                    };
+                    let notification = json!({
+                        "jsonrpc": "2.0",
+                        "method": method,
+                        "params": [{ "gid": event.id }],
+                    });
+                    if socket.send(Message::Text(notification.to_string().into())).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The aria2 notification a download event fires, `statuses` holds the last
+/// status seen of each download
+fn notification(
+    event: &DownloadEvent,
+    statuses: &mut HashMap<String, DownloadStatus>,
+) -> Option<&'static str> {
+    if event.r#type() == DownloadEventType::Removed {
+        statuses.remove(&event.id);
+        return None;
+    }
+    let status = event.download.as_ref()?.status();
+    let previous = statuses.insert(event.id.clone(), status);
+    if previous == Some(status) {
+        return None;
+    }
+    match status {
+        // retrying and reconnecting belong to the same run
+        _ if is_active(status) => match previous.is_some_and(is_active) {
+            true => None,
+            false => Some("aria2.onDownloadStart"),
+        },
+        DownloadStatus::Paused => Some("aria2.onDownloadPause"),
+        DownloadStatus::Cancelled => Some("aria2.onDownloadStop"),
+        DownloadStatus::Complete => Some("aria2.onDownloadComplete"),
+        DownloadStatus::Failed => Some("aria2.onDownloadError"),
+        _ => None,
+    }
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": error.to_json() })
+}
+
+fn string_param(params: &[Value], index: usize) -> Result<&str, RpcError> {
+    params
+        .get(index)
+        .and_then(Value::as_str)
+        .ok_or_else(|| RpcError::invalid_params(format!("param {} must be a string", index)))
+}
+
+fn object_param(params: &[Value], index: usize) -> Result<&Map<String, Value>, RpcError> {
+    params
+        .get(index)
+        .and_then(Value::as_object)
+        .ok_or_else(|| RpcError::invalid_params(format!("param {} must be an object", index)))
+}
+
+/// Keys to keep in a status, all of them if the param is missing
+fn keys_param(params: &[Value], index: usize) -> Vec<String> {
+    params
+        .get(index)
+        .and_then(Value::as_array)
+        .map(|keys| {
+            keys.iter()
+                .filter_map(Value::as_str)
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+const ACTIVE: [DownloadStatus; 3] = [
+    DownloadStatus::Connecting,
+    DownloadStatus::Retrying,
+    DownloadStatus::Downloading,
+];
+
+/// aria2 lists paused downloads with the waiting ones
+const WAITING: [DownloadStatus; 4] = [
+    DownloadStatus::StatusUnspecified,
+    DownloadStatus::Created,
+    DownloadStatus::Queued,
+    DownloadStatus::Paused,
+];
+
+const STOPPED: [DownloadStatus; 3] = [
+    DownloadStatus::Complete,
+    DownloadStatus::Failed,
+    DownloadStatus::Cancelled,
+];
+
+fn is_active(status: DownloadStatus) -> bool {
+    ACTIVE.contains(&status)
+}
+
+fn is_waiting(status: DownloadStatus) -> bool {
+    WAITING.contains(&status)
+}
+
+fn is_stopped(status: DownloadStatus) -> bool {
+    STOPPED.contains(&status)
+}
+
+fn aria2_status(status: DownloadStatus) -> &'static str {
+    match status {
+        DownloadStatus::Connecting | DownloadStatus::Retrying | DownloadStatus::Downloading => {
+            "active"
+        }
+        DownloadStatus::Paused => "paused",
+        DownloadStatus::Complete => "complete",
+        DownloadStatus::Failed => "error",
+        DownloadStatus::Cancelled => "removed",
+        DownloadStatus::StatusUnspecified | DownloadStatus::Created | DownloadStatus::Queued => {
+            "waiting"
+        }
+    }
+}
+
+/// Total and downloaded bytes and the number of open connections
+fn progress(download: &Download) -> (u64, u64, usize) {
+    let downloading = |status: DownloadStatus| status == DownloadStatus::Downloading;
+    match &download.parts {
+        Some(download::Parts::Resumable(parts)) => (
+            parts
+                .parts
+                .iter()
+                .map(|part| part.end_byte + 1 - part.start_byte)
+                .sum(),
+            parts.parts.iter().map(|part| part.bytes_downloaded).sum(),
+            parts
+                .parts
+                .iter()
+                .filter(|part| downloading(part.status()))
+                .count(),
+        ),
+        Some(download::Parts::NonResumable(part)) => (
+            part.total_bytes,
+            part.bytes_downloaded,
+            downloading(part.status()) as usize,
+        ),
+        Some(download::Parts::None(_)) | None => (0, 0, 0),
+    }
+}
+
+/// A download in the shape of aria2's `tellStatus`, numbers are strings there
+fn status_json(download: &Download, keys: &[String]) -> Value {
+    let (total, completed, connections) = progress(download);
+    let dir = Path::new(&download.file)
+        .parent()
+        .map(|dir| dir.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut status = json!({
+        "gid": download.id,
+        "status": aria2_status(download.status()),
+        "totalLength": total.to_string(),
+        "completedLength": completed.to_string(),
+        "uploadLength": "0",
+        "downloadSpeed": download.smoothed_speed.to_string(),
+        "uploadSpeed": "0",
+        "connections": connections.to_string(),
+        "numPieces": "1",
+        "pieceLength": total.to_string(),
+        "dir": dir,
+        "files": [{
+            "index": "1",
+            "path": download.file,
+            "length": total.to_string(),
+            "completedLength": completed.to_string(),
+            "selected": "true",
+            "uris": [{ "uri": download.url, "status": "used" }],
+        }],
+    });
+    if download.status() == DownloadStatus::Failed {
+        status["errorCode"] = json!("1");
+        status["errorMessage"] = json!("download failed");
+    }
+    if let Some(status) = status.as_object_mut()
+        && !keys.is_empty()
+    {
+        status.retain(|key, _| keys.contains(key));
+    }
+    status
+}
+
+fn option_json(download: &Download) -> Value {
+    let dir = Path::new(&download.file)
+        .parent()
+        .map(|dir| dir.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut options = json!({ "dir": dir, "out": download.file_name });
+    if let Some(referrer) = &download.referrer {
+        options["referer"] = json!(referrer);
+    }
+    if !download.headers.is_empty() {
+        options["header"] = json!(download.headers);
+    }
+    options["split"] = json!(download.connections.to_string());
+    options["max-connection-per-server"] = json!(download.connections.to_string());
+    options["max-download-limit"] = json!(download.speed_limit.to_string());
+    options
+}
+
+/// The options of aria2's `changeOption` this daemon has, others are ignored
+fn download_options_from_json(
+    gid: &str,
+    options: &Map<String, Value>,
+) -> Result<ChangeDownloadOptions, RpcError> {
+    let mut download_options = ChangeDownloadOptions {
+        id: gid.to_string(),
+        ..Default::default()
+    };
+    for (name, value) in options {
+        // header may be given more than once, as a list
+        if name == "header" {
+            let headers = match value {
+                Value::Array(headers) => headers
+                    .iter()
+                    .filter_map(|header| header.as_str().map(String::from))
+                    .collect(),
+                Value::String(header) => vec![header.clone()],
+                _ => return Err(RpcError::new(format!("invalid value {} for header", value))),
+            };
+            download_options.headers = Some(Headers { headers });
+            continue;
+        }
+        let value = match value {
+            Value::String(value) => value.clone(),
+            value => value.to_string(),
+        };
+        let invalid = || RpcError::new(format!("invalid value {} for {}", value, name));
+        match name.as_str() {
+            "split" | "max-connection-per-server" => {
+                download_options.connections = Some(value.parse().map_err(|_| invalid())?)
+            }
+            "max-download-limit" => {
+                download_options.speed_limit = Some(parse_size(&value).ok_or_else(invalid)?)
+            }
+            "dir" => download_options.dir = Some(value),
+            "out" => download_options.file_name = Some(value),
+            _ => debug!("Ignoring option {}", name),
+        }
+    }
+    Ok(download_options)
+}
+
+fn global_options_json(options: &GlobalOptions) -> Value {
+    let mut json = Map::new();
+    if let Some(max) = options.max_concurrent_downloads {
+        json.insert("max-concurrent-downloads".into(), json!(max.to_string()));
+    }
+    if let Some(limit) = options.max_overall_download_limit {
+        json.insert(
+            "max-overall-download-limit".into(),
+            json!(limit.to_string()),
+        );
+    }
+    Value::Object(json)
+}
+
+/// The aria2 options net-manthan has a global equivalent of, the others are
+/// ignored so frontends can send all of their settings
+fn global_options_from_json(options: &Map<String, Value>) -> Result<GlobalOptions, RpcError> {
+    let mut global_options = GlobalOptions::default();
+    for (name, value) in options {
+        // aria2 takes every option as a string, be lenient with numbers
+        let value = match value {
+            Value::String(value) => value.clone(),
+            value => value.to_string(),
+        };
+        let invalid = || RpcError::new(format!("invalid value {} for {}", value, name));
+        match name.as_str() {
+            "max-concurrent-downloads" => {
+                global_options.max_concurrent_downloads =
+                    Some(value.parse().map_err(|_| invalid())?)
+            }
+            "max-overall-download-limit" => {
+                global_options.max_overall_download_limit =
+                    Some(parse_size(&value).ok_or_else(invalid)?)
+            }
+            _ => debug!("Ignoring global option {}", name),
+        }
+    }
+    Ok(global_options)
+}
+
+/// A byte count with aria2's optional K or M suffix
+fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let (number, multiplier) = match value.char_indices().last()? {
+        (index, 'K' | 'k') => (&value[..index], 1024),
+        (index, 'M' | 'm') => (&value[..index], 1024 * 1024),
+        _ => (value, 1),
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::server::test_handle;
+    use crate::rpc_types::{DownloadList, Error};
+
+    /// A server whose manager answers GetDownloads with `downloads`
+    fn json_rpc(secret: &str, downloads: Vec<Download>) -> JsonRpc {
+        let handler = test_handle(move |request| match request {
+            Request::GetDownloads(_) => Answer::Downloads(DownloadList {
+                list: downloads.clone(),
+                ..Default::default()
+            }),
+            request => Answer::Error(Error::internal(format!("unexpected {:?}", request))),
+        });
+        JsonRpc {
+            handler,
+            secret: secret.into(),
+        }
+    }
+
+    fn download(id: &str, status: DownloadStatus) -> Download {
+        Download {
+            id: id.into(),
+            status: status as i32,
+            ..Default::default()
+        }
+    }
+
+    async fn call(rpc: &JsonRpc, message: Value) -> Value {
+        rpc.handle_message(&message.to_string()).await
+    }
+
+    #[tokio::test]
+    async fn checks_the_token() {
+        let rpc = json_rpc("s3cret", Vec::new());
+        let version =
+            |params: Value| json!({ "id": 1, "method": "aria2.getVersion", "params": params });
+
+        let response = call(&rpc, version(json!([]))).await;
+        assert_eq!(response["error"]["message"], "Unauthorized");
+        let response = call(&rpc, version(json!(["token:wrong"]))).await;
+        assert_eq!(response["error"]["message"], "Unauthorized");
+        let response = call(&rpc, version(json!(["token:s3cret"]))).await;
+        assert_eq!(response["id"], 1);
+        assert!(response["result"]["version"].is_string());
+
+        // listing the methods needs no token, like in aria2
+        let response = call(&rpc, json!({ "id": 2, "method": "system.listMethods" })).await;
+        assert!(response["result"].as_array().is_some_and(|m| !m.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn answers_batches_and_multicalls() {
+        let rpc = json_rpc("", Vec::new());
+
+        let response = call(
+            &rpc,
+            json!([
+                { "id": 1, "method": "aria2.getVersion" },
+                { "id": 2, "method": "aria2.noSuchMethod" },
+            ]),
+        )
+        .await;
+        assert_eq!(response[0]["id"], 1);
+        assert_eq!(response[1]["error"]["code"], -32601);
+        assert_eq!(call(&rpc, json!([])).await["error"]["code"], -32600);
+        assert_eq!(rpc.handle_message("{").await["error"]["code"], -32700);
+
+        let response = call(
+            &rpc,
+            json!({ "id": 3, "method": "system.multicall", "params": [[
+                { "methodName": "aria2.getVersion" },
+                { "methodName": "system.multicall", "params": [[]] },
+                { "params": [] },
+            ]]}),
+        )
+        .await;
+        let results = response["result"].as_array().unwrap();
+        assert!(results[0][0]["version"].is_string());
+        assert_eq!(results[1]["code"], 1);
+        assert_eq!(results[2]["code"], -32600);
+    }
+
+    #[tokio::test]
+    async fn pages_waiting_downloads() {
+        let downloads = ["a", "b", "c", "d", "e"]
+            .into_iter()
+            .map(|id| download(id, DownloadStatus::Queued))
+            .collect();
+        let rpc = json_rpc("", downloads);
+        let page = |offset: i64, num: u64| {
+            let rpc = &rpc;
+            async move {
+                let response = call(
+                    rpc,
+                    json!({ "id": 1, "method": "aria2.tellWaiting", "params": [offset, num, ["gid"]] }),
+                )
+                .await;
+                response["result"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|status| status["gid"].as_str().unwrap().to_string())
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(page(1, 2).await, ["b", "c"]);
+        // negative offsets count from the end and go backwards
+        assert_eq!(page(-1, 2).await, ["e", "d"]);
+        assert_eq!(page(-4, 3).await, ["b", "a"]);
+        assert_eq!(page(-10, 3).await, Vec::<String>::new());
+        assert_eq!(page(7, 3).await, Vec::<String>::new());
+    }
+
+    #[test]
+    fn notifies_status_changes() {
+        let mut statuses = HashMap::new();
+        let mut event = |id: &str, r#type: DownloadEventType, status: DownloadStatus| {
+            notification(
+                &DownloadEvent {
+                    r#type: r#type as i32,
+                    id: id.into(),
+                    download: Some(download(id, status)),
+                },
+                &mut statuses,
+            )
+        };
+        use DownloadEventType::*;
+
+        assert_eq!(event("a", Added, DownloadStatus::Queued), None);
+        assert_eq!(
+            event("a", StateChanged, DownloadStatus::Connecting),
+            Some("aria2.onDownloadStart")
+        );
+        assert_eq!(event("a", Progress, DownloadStatus::Downloading), None);
+        assert_eq!(
+            event("a", StateChanged, DownloadStatus::Paused),
+            Some("aria2.onDownloadPause")
+        );
+        assert_eq!(
+            event("a", StateChanged, DownloadStatus::Downloading),
+            Some("aria2.onDownloadStart")
+        );
+        assert_eq!(
+            event("a", StateChanged, DownloadStatus::Complete),
+            Some("aria2.onDownloadComplete")
+        );
+        assert_eq!(
+            event("b", StateChanged, DownloadStatus::Failed),
+            Some("aria2.onDownloadError")
+        );
+        assert_eq!(
+            event("b", StateChanged, DownloadStatus::Cancelled),
+            Some("aria2.onDownloadStop")
+        );
+        assert_eq!(event("b", Removed, DownloadStatus::Cancelled), None);
+        assert!(!statuses.contains_key("b"));
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("100"), Some(100));
+        assert_eq!(parse_size("2K"), Some(2048));
+        assert_eq!(parse_size(" 1m"), Some(1024 * 1024));
+        assert_eq!(parse_size("1G"), None);
+        assert_eq!(parse_size("18446744073709551615M"), None);
+    }
+
+    #[test]
+    fn checks_the_origin_and_content_type() {
+        let value = HeaderValue::from_static;
+        let request = |host: &'static str, origin: Option<&'static str>| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::HOST, value(host));
+            if let Some(origin) = origin {
+                headers.insert(header::ORIGIN, value(origin));
+            }
+            headers
+        };
+        let local = OriginPolicy {
+            local_port: Some(6800),
+            allow_origin_all: false,
+        };
+        assert!(local.allows(&request("localhost:6800", None)));
+        assert!(local.allows(&request("[::1]:6800", Some("http://127.0.0.1:6800"))));
+        assert!(!local.allows(&request("localhost:6800", Some("https://evil.example"))));
+        assert!(!local.allows(&request("localhost:6800", Some("null"))));
+        assert!(!local.allows(&request("localhost:6801", None)));
+        assert!(!local.allows(&HeaderMap::new()));
+        // DNS rebinding, evil.example resolves to 127.0.0.1
+        let rebound = request("evil.example:6800", Some("http://evil.example:6800"));
+        assert!(!local.allows(&rebound));
+        assert!(
+            !OriginPolicy {
+                allow_origin_all: true,
+                ..local
+            }
+            .allows(&rebound)
+        );
+
+        // on all interfaces any name can reach the server, only the origin counts
+        let all = OriginPolicy {
+            local_port: None,
+            allow_origin_all: false,
+        };
+        assert!(all.allows(&request("nas.lan:6800", Some("http://nas.lan:6800"))));
+        assert!(!all.allows(&request("nas.lan:6800", Some("https://evil.example"))));
+
+        assert!(is_local_host("LOCALHOST", 80));
+        assert!(is_local_host("[::1]", 80));
+        assert!(!is_local_host("localhost:junk", 6800));
+
+        let mut headers = HeaderMap::new();
+        assert!(!is_json(&headers));
+        headers.insert(header::CONTENT_TYPE, value("text/plain"));
+        assert!(!is_json(&headers));
+        headers.insert(
+            header::CONTENT_TYPE,
+            value("application/json; charset=UTF-8"),
+        );
+        assert!(is_json(&headers));
+    }
+}
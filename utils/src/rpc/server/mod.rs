@@ -1,17 +1,26 @@
 use crate::{
     rpc::{
-        RpcConfig,
-        server::native::{NativeServerHandle, start_native_server},
+        FEATURES, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, RpcConfig,
+        message_codec::MAX_MESSAGE_SIZE,
+        server::{
+            grpc::{GrpcServerHandle, start_grpc_server},
+            json_rpc::{JsonRpcServerHandle, start_json_rpc_server},
+            native::{NativeServerHandle, start_native_server},
+        },
     },
     rpc_types::{
-        Download, DownloadRequest, Error, GetDownload, GetDownloads, RpcRequest, RpcResponse,
-        rpc_request::Request, rpc_response::Response,
+        CancelDownloads, Download, DownloadEvent, DownloadRequest, Error, ErrorCode, GetDownload,
+        GetDownloads, HelloReply, Limits, PauseDownloads, RemoveDownloads, ResumeDownloads,
+        RetryDownloads, RpcRequest, RpcResponse, Subscribe, rpc_request::Request,
+        rpc_response::Response,
     },
 };
 use rand::Rng;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{broadcast, mpsc, oneshot};
 use tracing::{error, info};
 
+mod grpc;
+mod json_rpc;
 mod native;
 
 pub struct ManagerCommand {
@@ -22,8 +31,14 @@ pub struct ManagerCommand {
 pub struct RpcServer {
     config: RpcConfig,
     handle: RpcServerHandle,
-    // later it can become an enum for all other servers,or just take a onshot channel
-    shutdown_handle: Option<NativeServerHandle>,
+    shutdown_handle: Option<ServerHandle>,
+}
+
+/// Stops whichever server was started
+enum ServerHandle {
+    Native(NativeServerHandle),
+    Grpc(GrpcServerHandle),
+    JsonRpc(JsonRpcServerHandle),
 }
 
 impl RpcServer {
@@ -44,22 +59,47 @@ impl RpcServer {
                 match start_native_server(self.handle.clone(), settings.clone()).await {
                     Ok(h) => {
                         info!("Native Rpc Server Started");
-                        self.shutdown_handle = Some(h);
+                        self.shutdown_handle = Some(ServerHandle::Native(h));
                     }
-                    Err(_) => {
-                        error!("Failed to start Native Rpc Server");
+                    Err(e) => {
+                        error!("Failed to start Native Rpc Server: {:#}", e);
                     }
                 }
             }
-            _ => {
-                todo!();
+            RpcConfig::Grpc(settings) => {
+                match start_grpc_server(self.handle.clone(), settings.clone()).await {
+                    Ok(h) => {
+                        info!("gRPC Server Started");
+                        self.shutdown_handle = Some(ServerHandle::Grpc(h));
+                    }
+                    Err(e) => {
+                        error!("Failed to start gRPC Server: {:#}", e);
+                    }
+                }
+            }
+            RpcConfig::JsonRpc(settings) => {
+                match start_json_rpc_server(self.handle.clone(), settings.clone()).await {
+                    Ok(h) => {
+                        info!("JSON-RPC Server Started");
+                        self.shutdown_handle = Some(ServerHandle::JsonRpc(h));
+                    }
+                    Err(e) => {
+                        error!("Failed to start JSON-RPC Server: {:#}", e);
+                    }
+                }
             }
         }
     }
 
     pub async fn shutdown(self) {
         match self.shutdown_handle {
-            Some(h) => {
+            Some(ServerHandle::Native(h)) => {
+                let _ = h.shutdown().await;
+            }
+            Some(ServerHandle::Grpc(h)) => {
+                let _ = h.shutdown().await;
+            }
+            Some(ServerHandle::JsonRpc(h)) => {
                 let _ = h.shutdown().await;
             }
             None => {}
@@ -67,14 +107,61 @@ impl RpcServer {
     }
 }
 
+/// events a subscriber may fall behind by before it starts missing them
+pub const EVENT_CAPACITY: usize = 1024;
+
+/// requests of one native connection processed at the same time
+pub const MAX_IN_FLIGHT_REQUESTS: usize = 64;
+
+/// What this daemon tells a client saying `Hello`
+pub fn hello_reply(authentication_required: bool) -> HelloReply {
+    HelloReply {
+        protocol_version: PROTOCOL_VERSION,
+        min_protocol_version: MIN_PROTOCOL_VERSION,
+        daemon_version: env!("CARGO_PKG_VERSION").to_string(),
+        features: FEATURES.iter().map(|feature| feature.to_string()).collect(),
+        limits: Some(Limits {
+            max_message_size: MAX_MESSAGE_SIZE as u32,
+            event_buffer: EVENT_CAPACITY as u32,
+            max_in_flight_requests: MAX_IN_FLIGHT_REQUESTS as u32,
+        }),
+        authentication_required,
+    }
+}
+
+impl Subscribe {
+    /// Whether a subscriber with these filters wants `event`
+    pub fn matches(&self, event: &DownloadEvent) -> bool {
+        (self.ids.is_empty() || self.ids.contains(&event.id))
+            && (self.types.is_empty() || self.types.contains(&event.r#type))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct RpcServerHandle {
     pub command_sender: mpsc::Sender<ManagerCommand>,
+    /// the manager publishes every DownloadEvent here, subscribers filter them
+    pub event_sender: broadcast::Sender<DownloadEvent>,
 }
 
 impl RpcServerHandle {
+    /// Receive the events published from now on
+    pub fn subscribe_events(&self) -> broadcast::Receiver<DownloadEvent> {
+        self.event_sender.subscribe()
+    }
+
     pub async fn handle_call(&mut self, request: RpcRequest) -> RpcResponse {
         let request_id = request.request_id;
+        if request.request.is_none() {
+            // a request added to the protocol after this daemon was built
+            return RpcResponse {
+                request_id,
+                response: Some(Response::Error(Error::new(
+                    ErrorCode::Unimplemented,
+                    "request not supported by this daemon",
+                ))),
+            };
+        }
         let (send, recv) = oneshot::channel();
         if let Err(e) = self
             .command_sender
@@ -85,28 +172,30 @@ impl RpcServerHandle {
             .await
         {
             return RpcResponse {
-                request_id: request_id,
-                response: Some(Response::Error(Error {
-                    kind: format!("Download manager thread has terminated. Error: {}", e),
-                })),
+                request_id,
+                response: Some(Response::Error(Error::new(
+                    ErrorCode::Unavailable,
+                    format!("Download manager thread has terminated. Error: {}", e),
+                ))),
             };
         }
 
         match recv.await {
             Ok(res) => res,
             Err(e) => RpcResponse {
-                request_id: request_id,
-                response: Some(Response::Error(Error {
-                    kind: format!(
+                request_id,
+                response: Some(Response::Error(Error::new(
+                    ErrorCode::Unavailable,
+                    format!(
                         "Download manager dropped the response channel. Error: {}",
                         e
                     ),
-                })),
+                ))),
             },
         }
     }
 
-    pub async fn add_download(&mut self, request: DownloadRequest) -> Result<GetDownload, String> {
+    pub async fn add_download(&mut self, request: DownloadRequest) -> Result<GetDownload, Error> {
         let mut rng = rand::rngs::ThreadRng::default();
         let request_id: u64 = rng.random();
 
@@ -118,31 +207,129 @@ impl RpcServerHandle {
             .await;
 
         match response.response {
-            Some(res) => match res {
-                Response::DownloadCreated(download) => Ok(download),
-                _ => Err("Something went wrong".to_string()),
-            },
-            None => Err("Something went wrong".to_string()),
+            Some(Response::DownloadCreated(download)) => Ok(download),
+            other => Err(unexpected_response(other)),
         }
     }
 
-    pub async fn get_downloads(&mut self) -> Result<Vec<Download>, String> {
+    pub async fn get_downloads(&mut self) -> Result<Vec<Download>, Error> {
         let mut rng = rand::rngs::ThreadRng::default();
         let request_id: u64 = rng.random();
 
         let response = self
             .handle_call(RpcRequest {
                 request_id,
-                request: Some(Request::GetDownloads(GetDownloads {})),
+                request: Some(Request::GetDownloads(GetDownloads::default())),
             })
             .await;
 
         match response.response {
-            Some(res) => match res {
-                Response::Downloads(d) => Ok(d.list),
-                _ => Err("Something went wrong".to_string()),
-            },
-            None => Err("Something went wrong".to_string()),
+            Some(Response::Downloads(d)) => Ok(d.list),
+            other => Err(unexpected_response(other)),
         }
     }
+
+    /// Pause downloads, or all of them if `all` is set, returns the paused ids
+    pub async fn pause_downloads(
+        &mut self,
+        ids: Vec<String>,
+        all: bool,
+    ) -> Result<Vec<String>, Error> {
+        self.ids_call(Request::PauseDownloads(PauseDownloads { ids, all }))
+            .await
+    }
+
+    /// Queue paused downloads again, returns the resumed ids
+    pub async fn resume_downloads(
+        &mut self,
+        ids: Vec<String>,
+        all: bool,
+    ) -> Result<Vec<String>, Error> {
+        self.ids_call(Request::ResumeDownloads(ResumeDownloads { ids, all }))
+            .await
+    }
+
+    /// Cancel unfinished downloads, returns the cancelled ids
+    pub async fn cancel_downloads(
+        &mut self,
+        ids: Vec<String>,
+        all: bool,
+    ) -> Result<Vec<String>, Error> {
+        self.ids_call(Request::CancelDownloads(CancelDownloads { ids, all }))
+            .await
+    }
+
+    /// Drop downloads from the list, returns the removed ids
+    pub async fn remove_downloads(
+        &mut self,
+        ids: Vec<String>,
+        all: bool,
+        delete_file: bool,
+    ) -> Result<Vec<String>, Error> {
+        self.ids_call(Request::RemoveDownloads(RemoveDownloads {
+            ids,
+            all,
+            delete_file,
+        }))
+        .await
+    }
+
+    /// Queue failed and cancelled downloads again, returns the retried ids
+    pub async fn retry_downloads(
+        &mut self,
+        ids: Vec<String>,
+        all: bool,
+    ) -> Result<Vec<String>, Error> {
+        self.ids_call(Request::RetryDownloads(RetryDownloads { ids, all }))
+            .await
+    }
+
+    async fn ids_call(&mut self, request: Request) -> Result<Vec<String>, Error> {
+        let mut rng = rand::rngs::ThreadRng::default();
+        let request_id: u64 = rng.random();
+
+        let response = self
+            .handle_call(RpcRequest {
+                request_id,
+                request: Some(request),
+            })
+            .await;
+
+        match response.response {
+            Some(Response::DownloadIds(ids)) => Ok(ids.ids),
+            other => Err(unexpected_response(other)),
+        }
+    }
+}
+
+/// A handle whose manager answers every request with `answer`, the servers
+/// are tested against it
+#[cfg(test)]
+pub(crate) fn test_handle(
+    answer: impl Fn(Request) -> Response + Send + 'static,
+) -> RpcServerHandle {
+    let (command_sender, mut commands) = mpsc::channel::<ManagerCommand>(8);
+    let (event_sender, _) = broadcast::channel(EVENT_CAPACITY);
+    tokio::spawn(async move {
+        while let Some(command) = commands.recv().await {
+            let _ = command.respond_to.send(RpcResponse {
+                request_id: command.request.request_id,
+                response: command.request.request.map(&answer),
+            });
+        }
+    });
+    RpcServerHandle {
+        command_sender,
+        event_sender,
+    }
+}
+
+/// The error a response carries, or an INTERNAL one if it answers something
+/// other than what was asked
+fn unexpected_response(response: Option<Response>) -> Error {
+    match response {
+        Some(Response::Error(error)) => error,
+        Some(_) => Error::internal("unexpected response"),
+        None => Error::internal("empty response"),
+    }
 }
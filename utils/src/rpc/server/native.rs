@@ -1,24 +1,35 @@
-use crate::rpc::NativeRpcSettings;
-use crate::rpc::message_codec::MessageCodec;
-use crate::rpc::server::RpcServerHandle;
-use crate::rpc_types::RpcRequest;
-use anyhow::Result;
+use crate::rpc::message_codec::{MAX_MESSAGE_SIZE, MessageCodec};
+use crate::rpc::server::{MAX_IN_FLIGHT_REQUESTS, RpcServerHandle, hello_reply};
+use crate::rpc::{MIN_PROTOCOL_VERSION, NativeRpcSettings, PROTOCOL_VERSION, secret_matches};
+use crate::rpc_types::rpc_request::Request;
+use crate::rpc_types::rpc_response::Response;
+use crate::rpc_types::{
+    Authenticated, DownloadEvent, Error, ErrorCode, RpcRequest, RpcResponse, Subscribe,
+    Unsubscribed,
+};
+use anyhow::{Context, Result};
 use bytes::{Buf, BytesMut};
 use prost::Message;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::oneshot;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{Semaphore, mpsc, oneshot};
+use tokio::task::JoinHandle;
 use tokio_util::codec::{Decoder, Encoder};
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 #[cfg(windows)]
-use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
+use tokio::net::windows::named_pipe::ServerOptions;
 
 #[cfg(unix)]
-use anyhow::Context;
+use crate::rpc::current_uid;
 #[cfg(unix)]
-use tokio::net::{UnixListener, UnixStream};
+use anyhow::bail;
+#[cfg(unix)]
+use std::path::Path;
 #[cfg(unix)]
-use tracing::warn;
+use tokio::net::{UnixListener, UnixStream};
 
 #[derive(Debug)]
 pub struct NativeServerHandle {
@@ -62,22 +73,24 @@ async fn start_unix_server(
     settings: NativeRpcSettings,
     mut shutdown_rx: oneshot::Receiver<()>,
 ) -> Result<()> {
-    // Clean up existing socket file
-    if std::path::Path::new(&settings.address).exists() {
-        std::fs::remove_file(&settings.address)
-            .with_context(|| format!("Failed to remove existing socket: {}", settings.address))?;
-    }
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = Path::new(&settings.address);
+    prepare_socket_dir(path).await?;
+    remove_stale_socket(path).await?;
 
-    let listener = UnixListener::bind(&settings.address)
+    let listener = UnixListener::bind(path)
         .with_context(|| format!("Failed to bind Unix socket: {}", settings.address))?;
 
-    // Set permissions
-    if !settings.allow_all_users {
-        use std::os::unix::fs::PermissionsExt;
-        let perms = std::fs::Permissions::from_mode(0o600); // Owner only
-        std::fs::set_permissions(&settings.address, perms)
-            .with_context(|| format!("Failed to set socket permissions: {}", settings.address))?;
-    }
+    // other users can't reach the socket anyway unless its directory lets them,
+    // the allowed ones are told apart by their credentials below
+    let mode = match settings.allow_all_users || !settings.allowed_users.is_empty() {
+        true => 0o666,
+        false => 0o600,
+    };
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .with_context(|| format!("Failed to set socket permissions: {}", settings.address))?;
+    let uid = current_uid();
 
     info!(
         "Native RPC server listening on Unix socket: {}",
@@ -90,9 +103,15 @@ async fn start_unix_server(
                 result = listener.accept() => {
                     match result {
                         Ok((stream, _)) => {
+                            if !settings.allow_all_users
+                                && !peer_allowed(&stream, uid, &settings.allowed_users)
+                            {
+                                continue;
+                            }
                             let handler = handler.clone();
+                            let secret = settings.secret.clone();
                             tokio::spawn(async move {
-                                if let Err(e) = handle_unix_connection(stream, handler).await {
+                                if let Err(e) = handle_connection(stream, handler, secret).await {
                                     error!("Error handling Unix connection: {}", e);
                                 }
                             });
@@ -118,58 +137,79 @@ async fn start_unix_server(
     Ok(())
 }
 
+/// Create the directory of the socket owner only if it doesn't exist, an
+/// existing one has to belong to this user or root so nobody else can swap the
+/// socket out
 #[cfg(unix)]
-async fn handle_unix_connection(
-    mut stream: UnixStream,
-    mut handler: RpcServerHandle,
-) -> Result<()> {
-    let mut buffer = BytesMut::new();
-    let mut codec = MessageCodec;
-
-    loop {
-        // Read data from stream
-        let mut temp = [0u8; 4096];
-        match stream.read(&mut temp).await {
-            Ok(0) => break, // Connection closed
-            Ok(n) => {
-                buffer.extend_from_slice(&temp[..n]);
+async fn prepare_socket_dir(path: &Path) -> Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+    let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) else {
+        return Ok(());
+    };
+    match tokio::fs::metadata(dir).await {
+        Ok(metadata) => {
+            if !metadata.is_dir() {
+                bail!("{} is not a directory", dir.display());
             }
-            Err(e) => {
-                error!("Failed to read from Unix stream: {}", e);
-                break;
+            if metadata.uid() != current_uid() && metadata.uid() != 0 {
+                bail!(
+                    "Socket directory {} belongs to another user ({})",
+                    dir.display(),
+                    metadata.uid()
+                );
             }
+            Ok(())
         }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .with_context(|| format!("Failed to create socket directory: {}", dir.display())),
+        Err(e) => {
+            Err(e).with_context(|| format!("Failed to check socket directory: {}", dir.display()))
+        }
+    }
+}
 
-        // Process complete messages
-        while let Some(message_data) = codec.decode(&mut buffer)? {
-            let request: RpcRequest = match RpcRequest::decode(message_data.chunk()) {
-                Ok(req) => req,
-                Err(e) => {
-                    error!("Failed to deserialize request: {}", e);
-                    continue;
-                }
-            };
-
-            debug!("Received request: {}", request.request_id);
-
-            let response = handler.handle_call(request).await;
-            let mut response_data = Vec::with_capacity(response.encoded_len());
-            if let Err(e) = response.encode(&mut response_data) {
-                error!("Failed to encode response into bytes: {}", e);
-                break;
-            }
-
-            let mut encoded = BytesMut::new();
-            codec.encode(response_data, &mut encoded)?;
-
-            if let Err(e) = stream.write_all(&encoded).await {
-                error!("Failed to write response to Unix stream: {}", e);
-                break;
-            }
+/// Remove the socket a previous server left behind, refusing to touch anything
+/// that isn't a socket or one another server still listens on
+#[cfg(unix)]
+async fn remove_stale_socket(path: &Path) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let metadata = match tokio::fs::symlink_metadata(path).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to check socket: {}", path.display()));
         }
+    };
+    if !metadata.file_type().is_socket() {
+        bail!("{} exists and is not a socket", path.display());
     }
+    if UnixStream::connect(path).await.is_ok() {
+        bail!("Another server is listening on {}", path.display());
+    }
+    tokio::fs::remove_file(path)
+        .await
+        .with_context(|| format!("Failed to remove stale socket: {}", path.display()))
+}
 
-    Ok(())
+/// Whether the user on the other end of `stream` is `uid` or in `allowed_users`
+#[cfg(unix)]
+fn peer_allowed(stream: &UnixStream, uid: u32, allowed_users: &[u32]) -> bool {
+    match stream.peer_cred() {
+        Ok(peer) if peer.uid() == uid || allowed_users.contains(&peer.uid()) => true,
+        Ok(peer) => {
+            warn!("Refused native RPC connection from uid {}", peer.uid());
+            false
+        }
+        Err(e) => {
+            warn!("Refused native RPC connection, no peer credentials: {}", e);
+            false
+        }
+    }
 }
 
 #[cfg(windows)]
@@ -201,8 +241,9 @@ async fn start_windows_server(
                     match result {
                         Ok(()) => {
                             let handler = handler.clone();
+                            let secret = settings.secret.clone();
                             tokio::spawn(async move {
-                                if let Err(e) = handle_windows_connection(server, handler).await {
+                                if let Err(e) = handle_connection(server, handler, secret).await {
                                     error!("Error handling named pipe connection: {}", e);
                                 }
                             });
@@ -223,24 +264,34 @@ async fn start_windows_server(
     Ok(())
 }
 
-#[cfg(windows)]
-async fn handle_windows_connection(
-    mut pipe: NamedPipeServer,
-    mut handler: RpcServerHandle,
-) -> Result<()> {
+/// Serve requests from one client and push the events it subscribed to, the
+/// same for Unix sockets and named pipes. Requests are answered as they finish,
+/// up to MAX_IN_FLIGHT_REQUESTS at a time. With a secret set the client has to
+/// `Authenticate` before anything else is served
+async fn handle_connection<S>(stream: S, handler: RpcServerHandle, secret: String) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, writer) = tokio::io::split(stream);
+    let (responses, outgoing) = mpsc::channel(MAX_IN_FLIGHT_REQUESTS);
+    let writer = tokio::spawn(write_responses(writer, outgoing));
+
+    let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT_REQUESTS));
+    // by the request_id of their Subscribe
+    let mut subscriptions: HashMap<u64, JoinHandle<()>> = HashMap::new();
     let mut buffer = BytesMut::new();
     let mut codec = MessageCodec;
+    let mut authenticated = secret.is_empty();
+    let mut temp = [0u8; 4096];
 
-    loop {
-        // Read data from named pipe
-        let mut temp = [0u8; 4096];
-        match pipe.read(&mut temp).await {
+    'connection: loop {
+        match reader.read(&mut temp).await {
             Ok(0) => break, // Connection closed
             Ok(n) => {
                 buffer.extend_from_slice(&temp[..n]);
             }
             Err(e) => {
-                error!("Failed to read from named pipe: {}", e);
+                error!("Failed to read from stream: {}", e);
                 break;
             }
         }
@@ -255,24 +306,528 @@ async fn handle_windows_connection(
                 }
             };
 
-            debug!("Received request: {}", request.request_id);
+            let request_id = request.request_id;
+            debug!("Received request: {}", request_id);
+
+            // the connection state changes in the order the requests came in,
+            // everything else runs on its own
+            let response = match &request.request {
+                Some(Request::Hello(hello)) => {
+                    info!(
+                        "Native RPC client {:?} speaks protocol {}",
+                        hello.client_name, hello.protocol_version
+                    );
+                    if hello.protocol_version < MIN_PROTOCOL_VERSION {
+                        let _ = responses
+                            .send(too_old(request_id, hello.protocol_version))
+                            .await;
+                        break 'connection;
+                    }
+                    Response::Hello(hello_reply(!authenticated))
+                }
+                Some(Request::Authenticate(authenticate)) if !authenticated => {
+                    if !secret_matches(&secret, &authenticate.secret) {
+                        warn!("Closing native RPC connection with a wrong secret");
+                        let _ = responses.send(unauthenticated(request_id)).await;
+                        break 'connection;
+                    }
+                    authenticated = true;
+                    Response::Authenticated(Authenticated {})
+                }
+                _ if !authenticated => {
+                    if responses.send(unauthenticated(request_id)).await.is_err() {
+                        break 'connection;
+                    }
+                    continue;
+                }
+                Some(Request::Subscribe(filter)) => {
+                    // subscribe before the manager answers so no event after its
+                    // snapshot of the downloads is missed
+                    let receiver = handler.subscribe_events();
+                    let filter = filter.clone();
+                    let task = tokio::spawn(forward_events(
+                        handler.clone(),
+                        request,
+                        filter,
+                        receiver,
+                        responses.clone(),
+                    ));
+                    subscriptions.retain(|_, task| !task.is_finished());
+                    if let Some(replaced) = subscriptions.insert(request_id, task) {
+                        replaced.abort();
+                    }
+                    continue;
+                }
+                Some(Request::Unsubscribe(unsubscribe)) => {
+                    if let Some(task) = subscriptions.remove(&unsubscribe.subscription) {
+                        task.abort();
+                    }
+                    Response::Unsubscribed(Unsubscribed {})
+                }
+                _ => {
+                    let permit = in_flight
+                        .clone()
+                        .acquire_owned()
+                        .await
+                        .expect("semaphore is never closed");
+                    let mut handler = handler.clone();
+                    let responses = responses.clone();
+                    tokio::spawn(async move {
+                        let response = handler.handle_call(request).await;
+                        let _ = responses.send(response).await;
+                        drop(permit);
+                    });
+                    continue;
+                }
+            };
 
-            let response = handler.handle_call(request).await;
-            let mut response_data = Vec::with_capacity(response.encoded_len());
-            if let Err(e) = response.encode(&mut response_data) {
-                error!("Failed to encode response into bytes: {}", e);
-                break;
+            let response = RpcResponse {
+                request_id,
+                response: Some(response),
+            };
+            if responses.send(response).await.is_err() {
+                break 'connection;
             }
+        }
+    }
 
-            let mut encoded = BytesMut::new();
-            codec.encode(response_data, &mut encoded)?;
+    for task in subscriptions.values() {
+        task.abort();
+    }
+    // the writer stops once the requests still running are answered
+    drop(responses);
+    writer.await?
+}
 
-            if let Err(e) = pipe.write_all(&encoded).await {
-                error!("Failed to write response to named pipe: {}", e);
-                break;
+/// Answer a Subscribe and push the events it asks for after the response, until
+/// the connection closes or it is unsubscribed
+async fn forward_events(
+    mut handler: RpcServerHandle,
+    request: RpcRequest,
+    filter: Subscribe,
+    mut receiver: broadcast::Receiver<DownloadEvent>,
+    responses: mpsc::Sender<RpcResponse>,
+) {
+    let request_id = request.request_id;
+    let response = fit_message(handler.handle_call(request).await);
+    let failed = matches!(response.response, Some(Response::Error(_)));
+    if responses.send(response).await.is_err() || failed {
+        return;
+    }
+    loop {
+        match receiver.recv().await {
+            Ok(event) if filter.matches(&event) => {
+                let event = RpcResponse {
+                    request_id,
+                    response: Some(Response::Event(event)),
+                };
+                if responses.send(event).await.is_err() {
+                    return;
+                }
+            }
+            Ok(_) => {}
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("Subscriber is too slow, skipped {} events", skipped)
             }
+            // the manager is gone, nothing will be published anymore
+            Err(RecvError::Closed) => return,
         }
     }
+}
+
+fn too_old(request_id: u64, protocol_version: u32) -> RpcResponse {
+    let error = Error::new(
+        ErrorCode::FailedPrecondition,
+        format!(
+            "protocol version {} is too old, the daemon speaks {} to {}",
+            protocol_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        ),
+    )
+    .with_detail("protocol_version", PROTOCOL_VERSION.to_string())
+    .with_detail("min_protocol_version", MIN_PROTOCOL_VERSION.to_string());
+    RpcResponse {
+        request_id,
+        response: Some(Response::Error(error)),
+    }
+}
 
+fn unauthenticated(request_id: u64) -> RpcResponse {
+    RpcResponse {
+        request_id,
+        response: Some(Response::Error(Error::new(
+            ErrorCode::Unauthenticated,
+            "missing or wrong secret",
+        ))),
+    }
+}
+
+/// `response`, or a RESOURCE_EXHAUSTED error in its place if it doesn't fit in
+/// a message. The client would have to drop the connection for it, failing the
+/// other calls on it as well
+fn fit_message(response: RpcResponse) -> RpcResponse {
+    let size = response.encoded_len();
+    if size <= MAX_MESSAGE_SIZE {
+        return response;
+    }
+    warn!(
+        "Response to request {} is {} bytes, answering with an error",
+        response.request_id, size
+    );
+    let error = Error::new(
+        ErrorCode::ResourceExhausted,
+        format!(
+            "the response is {} bytes, more than the {} a message may have, ask for \
+             fewer downloads or fields",
+            size, MAX_MESSAGE_SIZE
+        ),
+    )
+    .with_detail("max_message_size", MAX_MESSAGE_SIZE.to_string());
+    RpcResponse {
+        request_id: response.request_id,
+        response: Some(Response::Error(error)),
+    }
+}
+
+/// Write the responses of one connection as they come in
+async fn write_responses<W>(mut writer: W, mut responses: mpsc::Receiver<RpcResponse>) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut codec = MessageCodec;
+    while let Some(response) = responses.recv().await {
+        write_response(&mut writer, &mut codec, &fit_message(response)).await?;
+    }
+    // the client sees the connection close after the last response
+    let _ = writer.shutdown().await;
     Ok(())
 }
+
+async fn write_response<S>(
+    stream: &mut S,
+    codec: &mut MessageCodec,
+    response: &RpcResponse,
+) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut response_data = Vec::with_capacity(response.encoded_len());
+    response
+        .encode(&mut response_data)
+        .context("Failed to encode response into bytes")?;
+
+    let mut encoded = BytesMut::new();
+    codec.encode(response_data, &mut encoded)?;
+
+    stream
+        .write_all(&encoded)
+        .await
+        .context("Failed to write response to stream")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::server::{ManagerCommand, test_handle};
+    use crate::rpc_types::{
+        Authenticate, Download, DownloadList, GetDownload, GetDownloads, Hello, Unsubscribe,
+    };
+    use tokio::io::{DuplexStream, ReadHalf, WriteHalf};
+
+    /// The client end of a connection served by `handle_connection`
+    struct TestClient {
+        reader: ReadHalf<DuplexStream>,
+        writer: WriteHalf<DuplexStream>,
+        buffer: BytesMut,
+    }
+
+    impl TestClient {
+        fn connect(handler: RpcServerHandle, secret: &str) -> Self {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            tokio::spawn(handle_connection(server, handler, secret.to_string()));
+            let (reader, writer) = tokio::io::split(client);
+            TestClient {
+                reader,
+                writer,
+                buffer: BytesMut::new(),
+            }
+        }
+
+        /// Write all `requests` in one go, without waiting for any answer
+        async fn send(&mut self, requests: Vec<(u64, Request)>) {
+            let mut encoded = BytesMut::new();
+            for (request_id, request) in requests {
+                let request = RpcRequest {
+                    request_id,
+                    request: Some(request),
+                };
+                MessageCodec
+                    .encode(request.encode_to_vec(), &mut encoded)
+                    .unwrap();
+            }
+            self.writer.write_all(&encoded).await.unwrap();
+        }
+
+        /// The next response, None once the server closed the connection
+        async fn receive(&mut self) -> Option<RpcResponse> {
+            loop {
+                if let Some(message) = MessageCodec.decode(&mut self.buffer).unwrap() {
+                    return Some(RpcResponse::decode(message.chunk()).unwrap());
+                }
+                let mut temp = [0u8; 4096];
+                match self.reader.read(&mut temp).await.unwrap() {
+                    0 => return None,
+                    n => self.buffer.extend_from_slice(&temp[..n]),
+                }
+            }
+        }
+    }
+
+    fn get_download(id: &str) -> Request {
+        Request::GetDownload(GetDownload { id: id.into() })
+    }
+
+    /// Answers GetDownload with the download it asks for
+    fn answer(request: Request) -> Response {
+        match request {
+            Request::GetDownload(get) => Response::Download(Download {
+                id: get.id,
+                ..Default::default()
+            }),
+            Request::GetDownloads(_) | Request::Subscribe(_) => {
+                Response::Downloads(DownloadList::default())
+            }
+            request => Response::Error(Error::internal(format!("unexpected {:?}", request))),
+        }
+    }
+
+    fn event(id: &str) -> DownloadEvent {
+        DownloadEvent {
+            id: id.into(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_pipelined_requests_as_they_finish() {
+        let (command_sender, mut commands) = mpsc::channel::<ManagerCommand>(8);
+        let (event_sender, _) = broadcast::channel(8);
+        // holds the first request until the second one is in, then answers the
+        // second one first
+        tokio::spawn(async move {
+            let first = commands.recv().await.unwrap();
+            let second = commands.recv().await.unwrap();
+            for command in [second, first] {
+                let _ = command.respond_to.send(RpcResponse {
+                    request_id: command.request.request_id,
+                    response: command.request.request.map(answer),
+                });
+            }
+        });
+        let handler = RpcServerHandle {
+            command_sender,
+            event_sender,
+        };
+        let mut client = TestClient::connect(handler, "");
+
+        client
+            .send(vec![(1, get_download("one")), (2, get_download("two"))])
+            .await;
+        for (request_id, id) in [(2, "two"), (1, "one")] {
+            let response = client.receive().await.unwrap();
+            assert_eq!(response.request_id, request_id);
+            let Some(Response::Download(download)) = response.response else {
+                panic!("unexpected response {:?}", response);
+            };
+            assert_eq!(download.id, id);
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_only_authenticated_clients() {
+        let mut client = TestClient::connect(test_handle(answer), "s3cret");
+
+        client
+            .send(vec![(
+                1,
+                Request::Hello(Hello {
+                    protocol_version: PROTOCOL_VERSION,
+                    client_name: "test".into(),
+                }),
+            )])
+            .await;
+        let response = client.receive().await.unwrap();
+        let Some(Response::Hello(hello)) = response.response else {
+            panic!("unexpected response {:?}", response);
+        };
+        assert!(hello.authentication_required);
+
+        // refused, the connection stays open
+        client.send(vec![(2, get_download("one"))]).await;
+        let response = client.receive().await.unwrap();
+        assert_eq!(response.request_id, 2);
+        assert!(matches!(
+            response.response,
+            Some(Response::Error(error)) if error.code() == ErrorCode::Unauthenticated
+        ));
+
+        // a wrong secret closes it
+        client
+            .send(vec![(
+                3,
+                Request::Authenticate(Authenticate {
+                    secret: "guess".into(),
+                }),
+            )])
+            .await;
+        let response = client.receive().await.unwrap();
+        assert!(matches!(
+            response.response,
+            Some(Response::Error(error)) if error.code() == ErrorCode::Unauthenticated
+        ));
+        assert!(client.receive().await.is_none());
+
+        let mut client = TestClient::connect(test_handle(answer), "s3cret");
+        client
+            .send(vec![
+                (
+                    1,
+                    Request::Authenticate(Authenticate {
+                        secret: "s3cret".into(),
+                    }),
+                ),
+                (2, Request::GetDownloads(GetDownloads::default())),
+            ])
+            .await;
+        let response = client.receive().await.unwrap();
+        assert!(matches!(
+            response.response,
+            Some(Response::Authenticated(_))
+        ));
+        let response = client.receive().await.unwrap();
+        assert!(matches!(response.response, Some(Response::Downloads(_))));
+    }
+
+    #[tokio::test]
+    async fn refuses_responses_too_large_for_a_message() {
+        let everything = || {
+            Response::Downloads(DownloadList {
+                list: vec![Download {
+                    url: "x".repeat(MAX_MESSAGE_SIZE),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            })
+        };
+        let handler = test_handle(move |request| match request {
+            Request::GetDownloads(get) if get.limit == 0 => everything(),
+            Request::Subscribe(_) => everything(),
+            request => answer(request),
+        });
+        let mut client = TestClient::connect(handler.clone(), "");
+
+        client
+            .send(vec![
+                (1, Request::GetDownloads(GetDownloads::default())),
+                (
+                    2,
+                    Request::GetDownloads(GetDownloads {
+                        limit: 10,
+                        ..Default::default()
+                    }),
+                ),
+            ])
+            .await;
+        let mut responses = [
+            client.receive().await.unwrap(),
+            client.receive().await.unwrap(),
+        ];
+        responses.sort_by_key(|response| response.request_id);
+        assert!(matches!(
+            &responses[0].response,
+            Some(Response::Error(error)) if error.code() == ErrorCode::ResourceExhausted
+        ));
+        // the connection is still fine
+        assert!(matches!(
+            responses[1].response,
+            Some(Response::Downloads(_))
+        ));
+
+        // a subscription without its snapshot gets no events either
+        client
+            .send(vec![(3, Request::Subscribe(Subscribe::default()))])
+            .await;
+        let response = client.receive().await.unwrap();
+        assert!(matches!(
+            response.response,
+            Some(Response::Error(error)) if error.code() == ErrorCode::ResourceExhausted
+        ));
+        let _ = handler.event_sender.send(event("a"));
+        client.send(vec![(4, get_download("one"))]).await;
+        assert_eq!(client.receive().await.unwrap().request_id, 4);
+    }
+
+    #[tokio::test]
+    async fn routes_events_to_their_subscriptions() {
+        let handler = test_handle(answer);
+        let mut client = TestClient::connect(handler.clone(), "");
+
+        client
+            .send(vec![
+                (
+                    10,
+                    Request::Subscribe(Subscribe {
+                        ids: vec!["a".into()],
+                        types: Vec::new(),
+                    }),
+                ),
+                (11, Request::Subscribe(Subscribe::default())),
+            ])
+            .await;
+        let mut subscribed: Vec<u64> = Vec::new();
+        for _ in 0..2 {
+            let response = client.receive().await.unwrap();
+            assert!(matches!(response.response, Some(Response::Downloads(_))));
+            subscribed.push(response.request_id);
+        }
+        subscribed.sort();
+        assert_eq!(subscribed, [10, 11]);
+
+        // events carry the request_id of the subscription they match
+        let mut events = Vec::new();
+        handler.event_sender.send(event("a")).unwrap();
+        handler.event_sender.send(event("b")).unwrap();
+        for _ in 0..3 {
+            let response = client.receive().await.unwrap();
+            let Some(Response::Event(event)) = response.response else {
+                panic!("unexpected response {:?}", response);
+            };
+            events.push((response.request_id, event.id));
+        }
+        events.sort();
+        assert_eq!(
+            events,
+            [(10, "a".into()), (11, "a".into()), (11, "b".into())]
+        );
+
+        client
+            .send(vec![(
+                12,
+                Request::Unsubscribe(Unsubscribe { subscription: 11 }),
+            )])
+            .await;
+        let response = client.receive().await.unwrap();
+        assert_eq!(response.request_id, 12);
+        assert!(matches!(response.response, Some(Response::Unsubscribed(_))));
+
+        // only the first subscription is left, the response to 13 comes after
+        // its event or before it, but nothing for 11
+        handler.event_sender.send(event("a")).unwrap();
+        client.send(vec![(13, get_download("one"))]).await;
+        let mut request_ids = vec![
+            client.receive().await.unwrap().request_id,
+            client.receive().await.unwrap().request_id,
+        ];
+        request_ids.sort();
+        assert_eq!(request_ids, [10, 13]);
+    }
+}